@@ -0,0 +1,98 @@
+/* Runs a ROM for a few seconds through the C API and prints the screen.
+ * Exits with an error if restoring a snapshot doesn't replay the same frames */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include "chip8.h"
+
+#define FRAMES 120
+#define INSTRUCTIONS_PER_FRAME 10
+
+static unsigned char *read_file(const char *path, size_t *length) {
+    FILE *file = fopen(path, "rb");
+    if (!file) {
+        return NULL;
+    }
+
+    unsigned char *contents = malloc(4096);
+    *length = fread(contents, 1, 4096, file);
+    fclose(file);
+    return contents;
+}
+
+static void run(Chip8 *chip8, int frames, int *sounding) {
+    for (int frame = 0; frame < frames; frame++) {
+        /* Hold key 1 for the first half, which moves the left paddle in Pong */
+        chip8_set_key(chip8, 1, frame < frames / 2);
+        if (chip8_run_frame(chip8, INSTRUCTIONS_PER_FRAME)) {
+            (*sounding)++;
+        }
+    }
+}
+
+static size_t copy_screen(Chip8 *chip8, unsigned char *copy) {
+    size_t size = chip8_screen_width(chip8) * chip8_screen_height(chip8);
+    memcpy(copy, chip8_framebuffer(chip8), size);
+    return size;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 2) {
+        fprintf(stderr, "usage: %s ROM\n", argv[0]);
+        return 2;
+    }
+
+    size_t rom_length;
+    unsigned char *rom = read_file(argv[1], &rom_length);
+    if (!rom) {
+        fprintf(stderr, "can't read %s\n", argv[1]);
+        return 1;
+    }
+
+    Chip8 *chip8 = chip8_create();
+    chip8_seed(chip8, 1);
+    if (!chip8_set_quirks(chip8, "chip8") || !chip8_load_rom(chip8, rom, rom_length)) {
+        fprintf(stderr, "can't load %s\n", argv[1]);
+        return 1;
+    }
+    free(rom);
+
+    int sounding = 0;
+    run(chip8, FRAMES, &sounding);
+
+    size_t state_length = chip8_save_state(chip8, NULL, 0);
+    unsigned char *state = malloc(state_length);
+    chip8_save_state(chip8, state, state_length);
+
+    /* Snapshots leave out the random number generator, so reseed it the same way before both runs */
+    static unsigned char first[128 * 64], second[128 * 64];
+    chip8_seed(chip8, 2);
+    run(chip8, FRAMES, &sounding);
+    size_t size = copy_screen(chip8, first);
+
+    if (!chip8_load_state(chip8, state, state_length)) {
+        fprintf(stderr, "can't restore the snapshot\n");
+        return 1;
+    }
+    chip8_seed(chip8, 2);
+    run(chip8, FRAMES, &sounding);
+    copy_screen(chip8, second);
+    free(state);
+
+    if (memcmp(first, second, size) != 0) {
+        fprintf(stderr, "the snapshot replayed differently\n");
+        return 1;
+    }
+
+    size_t width = chip8_screen_width(chip8);
+    for (size_t y = 0; y < chip8_screen_height(chip8); y++) {
+        for (size_t x = 0; x < width; x++) {
+            putchar(second[y * width + x] ? '#' : '.');
+        }
+        putchar('\n');
+    }
+    printf("Frames with sound: %d\n", sounding);
+
+    chip8_destroy(chip8);
+    return 0;
+}
@@ -0,0 +1,32 @@
+use learn_rust::cpu::{Cpu, MEMORY_SIZE, STACK_SIZE};
+
+/// Upper bound on the cycles a single input runs for so slow inputs don't time out
+pub const MAX_CYCLES: usize = 10_000;
+
+/// Runs a single cycle and checks the CPU is still in a sane state afterwards
+pub fn cycle_and_check(cpu: &mut Cpu) {
+    let delay_timer = cpu.delay_timer;
+    let sound_timer = cpu.sound_timer;
+    let program_counter = cpu.program_counter;
+    let waiting_for_key = cpu.waiting_for_key();
+
+    cpu.cycle();
+
+    let opcode = cpu.current_opcode;
+    assert!(cpu.stack_pointer <= STACK_SIZE, "stack pointer {} overflowed after {:04X}", cpu.stack_pointer, opcode);
+    assert!((cpu.program_counter as usize) < MEMORY_SIZE, "program counter {:04X} out of range after {:04X}", cpu.program_counter, opcode);
+
+    // Timers only ever count down unless the instruction just loaded them
+    if opcode & 0xF0FF != 0xF015 {
+        assert!(cpu.delay_timer <= delay_timer, "delay timer went from {} to {} after {:04X}", delay_timer, cpu.delay_timer, opcode);
+    }
+
+    if opcode & 0xF0FF != 0xF018 {
+        assert!(cpu.sound_timer <= sound_timer, "sound timer went from {} to {} after {:04X}", sound_timer, cpu.sound_timer, opcode);
+    }
+
+    // Nothing runs while the CPU waits for a key
+    if waiting_for_key && cpu.waiting_for_key() {
+        assert_eq!(cpu.program_counter, program_counter, "program counter moved while waiting for a key");
+    }
+}
@@ -0,0 +1,34 @@
+#![no_main]
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use learn_rust::cpu::Cpu;
+
+mod common;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    /// Seeds the random number generator so crashes through Cxkk reproduce from the saved input
+    seed: u64,
+    /// Keypad state for each cycle as a bitmask, repeated once it runs out
+    keys: Vec<u16>,
+    rom: Vec<u8>
+}
+
+fuzz_target!(|input: Input| {
+    let mut cpu = Cpu::new();
+    cpu.seed_rng(input.seed);
+    if cpu.load_rom(input.rom).is_err() {
+        return;
+    }
+
+    for cycle in 0..common::MAX_CYCLES {
+        if !input.keys.is_empty() {
+            let mask = input.keys[cycle % input.keys.len()];
+            for (key, pressed) in cpu.keypad.iter_mut().enumerate() {
+                *pressed = mask & (1 << key) != 0;
+            }
+        }
+
+        common::cycle_and_check(&mut cpu);
+    }
+});
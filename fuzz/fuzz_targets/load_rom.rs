@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use learn_rust::cpu::{Cpu, MAX_ROM_SIZE};
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let mut cpu = Cpu::new();
+    cpu.seed_rng(0);
+    let loaded = cpu.load_rom(data.to_vec());
+    assert_eq!(loaded.is_ok(), data.len() <= MAX_ROM_SIZE, "a {} byte ROM was loaded or turned away wrongly", data.len());
+    if loaded.is_err() {
+        return;
+    }
+
+    for _ in 0..common::MAX_CYCLES {
+        common::cycle_and_check(&mut cpu);
+    }
+});
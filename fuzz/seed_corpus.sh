@@ -0,0 +1,8 @@
+#!/bin/sh
+# Seeds the corpus of every fuzz target with the ROMs in src/test_roms
+cd "$(dirname "$0")" || exit 1
+
+for target in load_rom execute; do
+    mkdir -p "corpus/$target"
+    cp ../src/test_roms/* "corpus/$target/"
+done
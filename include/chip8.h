@@ -0,0 +1,103 @@
+#ifndef CHIP8_H
+#define CHIP8_H
+
+/* Generated by cbindgen from src/ffi.rs with `CHIP8_UPDATE_HEADER=1 cargo test --test header`, don't edit by hand */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// A CPU for C programs embedding the emulator, along with the pixels last handed out by `chip8_framebuffer`.
+// Opaque to C, which only ever holds a pointer from `chip8_create`
+typedef struct Chip8 Chip8;
+
+// A CPU with the built-in font loaded and default quirks, or null if it couldn't be made. Free it with
+// `chip8_destroy`
+Chip8 *chip8_create(void);
+
+// # Safety
+// `chip8` must come from `chip8_create` and not be used again. Null is ignored
+void chip8_destroy(Chip8 *chip8);
+
+// Seeds the random number generator so runs can be repeated
+//
+// # Safety
+// `chip8` must come from `chip8_create`
+void chip8_seed(Chip8 *chip8, uint64_t seed);
+
+// Switches to a quirk profile like "chip8" or "schip", returning false if there's no such profile
+//
+// # Safety
+// `chip8` must come from `chip8_create` and `profile` must be null or a null-terminated string
+bool chip8_set_quirks(Chip8 *chip8, const char *profile);
+
+// Copies a ROM into memory at 0x200 and starts running from there, returning false if it doesn't fit or `rom` is
+// null with a nonzero length
+//
+// # Safety
+// `chip8` must come from `chip8_create` and `rom` must point to `length` bytes. It may be null when `length` is 0
+bool chip8_load_rom(Chip8 *chip8,
+                    const uint8_t *rom,
+                    size_t length);
+
+// Runs a number of instructions without touching the timers
+//
+// # Safety
+// `chip8` must come from `chip8_create`
+void chip8_step(Chip8 *chip8, size_t instructions);
+
+// Runs a 60Hz frame, returning whether the buzzer sounded. Returns false without running for a null CPU
+//
+// # Safety
+// `chip8` must come from `chip8_create`
+bool chip8_run_frame(Chip8 *chip8,
+                     size_t instructions_per_frame);
+
+// Whether the sound timer is running
+//
+// # Safety
+// `chip8` must come from `chip8_create`
+bool chip8_sound_active(const Chip8 *chip8);
+
+// Presses or releases a key from 0 to 15, returning false for any other key
+//
+// # Safety
+// `chip8` must come from `chip8_create`
+bool chip8_set_key(Chip8 *chip8, uint8_t key, bool pressed);
+
+// Width of the display in pixels, which changes when a program switches resolution
+//
+// # Safety
+// `chip8` must come from `chip8_create`
+size_t chip8_screen_width(const Chip8 *chip8);
+
+// # Safety
+// `chip8` must come from `chip8_create`
+size_t chip8_screen_height(const Chip8 *chip8);
+
+// The display row by row as one color index per pixel, `chip8_screen_width` by `chip8_screen_height` bytes.
+// It stays valid until the next call with this CPU. Null for a null CPU
+//
+// # Safety
+// `chip8` must come from `chip8_create`
+const uint8_t *chip8_framebuffer(Chip8 *chip8);
+
+// Saves the machine, apart from the random number generator, into `buffer` if it has room and returns the size
+// of the snapshot either way, so it can be called with a null buffer first to find out how much room is needed.
+// Returns 0 for a null CPU
+//
+// # Safety
+// `chip8` must come from `chip8_create` and `buffer` must be null or point to `capacity` writable bytes
+size_t chip8_save_state(const Chip8 *chip8,
+                        uint8_t *buffer,
+                        size_t capacity);
+
+// Restores a snapshot from `chip8_save_state`, returning false and leaving the machine alone if it's damaged
+//
+// # Safety
+// `chip8` must come from `chip8_create` and `state` must point to `length` bytes. It may be null when `length` is 0
+bool chip8_load_state(Chip8 *chip8,
+                      const uint8_t *state,
+                      size_t length);
+
+#endif  /* CHIP8_H */
@@ -0,0 +1,95 @@
+from pathlib import Path
+
+import numpy as np
+import pytest
+
+import chip8
+
+PONG = (Path(__file__).parents[2] / "src" / "test_roms" / "Pong.ch8").read_bytes()
+
+
+@pytest.fixture
+def cpu():
+    cpu = chip8.Cpu(seed=1)
+    cpu.load_rom(PONG)
+    return cpu
+
+
+def test_load_rom_starts_at_the_load_address(cpu):
+    assert cpu.pc == 0x200
+    assert cpu.read_memory(0x200, len(PONG)) == PONG
+
+
+def test_screen_is_a_numpy_array_of_color_indices(cpu):
+    assert not cpu.screen().any()
+
+    cpu.run_frames(10)
+    screen = cpu.screen()
+    assert screen.shape == (32, 64)
+    assert screen.dtype == np.uint8
+    assert screen.any()
+    assert set(np.unique(screen)) <= {0, 1}
+
+
+def test_step_runs_instructions_without_the_timers(cpu):
+    cpu.delay_timer = 5
+    cpu.step(3)
+    assert cpu.pc != 0x200
+    assert cpu.delay_timer == 5
+
+    cpu.cycle()
+    assert cpu.delay_timer == 4
+
+
+def test_registers_and_memory_can_be_written(cpu):
+    cpu.set_register(3, 0x42)
+    assert cpu.register(3) == 0x42
+    assert cpu.registers[3] == 0x42
+
+    cpu.write_memory(0xF00, b"\x01\x02\x03")
+    assert cpu.read_memory(0xF00, 3) == b"\x01\x02\x03"
+
+    cpu.index = 0x300
+    assert cpu.index == 0x300
+
+
+def test_out_of_range_arguments_raise_value_error(cpu):
+    with pytest.raises(ValueError):
+        cpu.register(16)
+    with pytest.raises(ValueError):
+        cpu.read_memory(0xFFF, 2)
+    with pytest.raises(ValueError):
+        cpu.press(16)
+    with pytest.raises(ValueError):
+        cpu.load_rom(bytes(5000))
+    with pytest.raises(ValueError):
+        chip8.Cpu(quirks="nonsense")
+
+
+def test_keys_finish_a_key_wait():
+    cpu = chip8.Cpu()
+    # LD V0, K then JP to itself
+    cpu.load_rom(bytes([0xF0, 0x0A, 0x12, 0x02]))
+    cpu.step(1)
+    assert cpu.waiting_for_key
+
+    cpu.press(7)
+    cpu.release(7)
+    cpu.step(1)
+    assert not cpu.waiting_for_key
+    assert cpu.register(0) == 7
+
+
+def test_state_round_trips(cpu):
+    cpu.run_frames(60)
+    state = cpu.save_state()
+    cpu.run_frames(30)
+    expected = (cpu.pc, cpu.registers, cpu.screen().copy())
+
+    cpu.load_state(state)
+    cpu.run_frames(30)
+    assert (cpu.pc, cpu.registers) == expected[:2]
+    assert np.array_equal(cpu.screen(), expected[2])
+
+    with pytest.raises(ValueError):
+        cpu.load_state(b"not a state")
@@ -0,0 +1,412 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use crate::instruction::Instruction;
+use crate::quirks::Quirks;
+
+const ADDRESS_MASK: u16 = 0x0FFF;
+
+/// A run of instructions that's only ever entered at the top and left at the bottom
+#[derive(Clone, Debug, PartialEq)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub instructions: Vec<(u16, Instruction)>,
+    /// Blocks control can go to next, including the return address after a call
+    pub successors: Vec<u16>,
+    /// The subroutine the last instruction calls
+    pub call: Option<u16>
+}
+
+impl BasicBlock {
+    /// Address of the last instruction
+    pub fn last_address(&self) -> u16 {
+        self.instructions.last().map(|&(address, _)| address).unwrap_or(self.start)
+    }
+}
+
+/// Something in a ROM worth a closer look
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Finding {
+    /// Bytes no path from the entry point reaches, inclusive. Usually sprite data, sometimes dead code
+    Unreachable { start: u16, end: u16 },
+    /// A jump or call to an address the ROM doesn't cover
+    OutsideRom { address: u16, target: u16 },
+    /// `JP V0, addr`, which goes somewhere that depends on V0 so the analysis can't follow it
+    ComputedJump { address: u16, base: u16 },
+    /// `LD [I], Vx` or `LD B, Vx` writing over reachable instructions
+    SelfModifyingStore { address: u16, target: u16 },
+    /// `LD [I], Vx` or `LD B, Vx` where I can't be worked out, so it might write over code
+    UnknownStore { address: u16 },
+    /// `RET` reachable from the entry point without a call, which underflows the stack
+    ReturnOutsideSubroutine { address: u16 },
+    /// A subroutine with no reachable `RET`
+    NoReturn { subroutine: u16 }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Finding::Unreachable { start, end } => {
+                write!(f, "{:#05X}-{:#05X}: unreachable, {} bytes of data or dead code", start, end, end - start + 1)
+            },
+            Finding::OutsideRom { address, target } => write!(f, "{:#05X}: goes to {:#05X}, outside the ROM", address, target),
+            Finding::ComputedJump { address, base } => write!(f, "{:#05X}: computed jump to {:#05X} + V0", address, base),
+            Finding::SelfModifyingStore { address, target } => {
+                write!(f, "{:#05X}: self-modifying store over the code at {:#05X}", address, target)
+            },
+            Finding::UnknownStore { address } => write!(f, "{:#05X}: store through an unknown I, may modify code", address),
+            Finding::ReturnOutsideSubroutine { address } => write!(f, "{:#05X}: RET outside a subroutine", address),
+            Finding::NoReturn { subroutine } => write!(f, "{:#05X}: subroutine never returns", subroutine)
+        }
+    }
+}
+
+/// What `I` is known to hold on the way into an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IndexValue {
+    Known(u16),
+    Unknown
+}
+
+impl IndexValue {
+    fn merge(self, other: IndexValue) -> IndexValue {
+        if self == other { self } else { IndexValue::Unknown }
+    }
+}
+
+/// Where control can go after an instruction
+struct Flow {
+    next: Vec<u16>,
+    call: Option<u16>,
+    ends_block: bool
+}
+
+fn flow(address: u16, instruction: Instruction) -> Flow {
+    let next = address.wrapping_add(2) & ADDRESS_MASK;
+    let skip = address.wrapping_add(4) & ADDRESS_MASK;
+    let (next, call, ends_block) = match instruction {
+        Instruction::Jp(target) => (vec![target], None, true),
+        Instruction::Call(target) => (vec![next], Some(target), true),
+        Instruction::Ret | Instruction::JpOffset(_) => (vec![], None, true),
+        Instruction::SeByte { .. } | Instruction::SneByte { .. } | Instruction::SeRegister { .. }
+        | Instruction::SneRegister { .. } | Instruction::Skp { .. } | Instruction::Sknp { .. } => (vec![next, skip], None, true),
+        _ => (vec![next], None, false)
+    };
+
+    Flow { next, call, ends_block }
+}
+
+/// The result of walking a ROM from its entry point: basic blocks, which subroutines call which, and findings
+pub struct Analysis {
+    pub entry: u16,
+    /// Blocks by start address
+    pub blocks: BTreeMap<u16, BasicBlock>,
+    /// Subroutines by entry address, with the subroutines each one calls. The entry point counts as one
+    pub call_graph: BTreeMap<u16, BTreeSet<u16>>,
+    pub findings: Vec<Finding>
+}
+
+impl Analysis {
+    /// Follows every jump, call, skip and return reachable from `entry` in a ROM loaded at `start_address`.
+    /// The quirks decide how stores and loads move I, which the self-modifying store findings depend on
+    pub fn analyze(rom: &[u8], start_address: u16, entry: u16, quirks: Quirks) -> Self {
+        let rom_end = start_address as usize + rom.len();
+        let decode = |address: u16| {
+            let offset = (address - start_address) as usize;
+            Instruction::decode((rom[offset] as u16) << 8 | rom[offset + 1] as u16)
+        };
+        let in_rom = |address: u16| address >= start_address && address as usize + 1 < rom_end;
+
+        let mut findings = BTreeSet::new();
+        if !in_rom(entry) {
+            findings.insert(Finding::OutsideRom { address: entry, target: entry });
+            return Analysis { entry, blocks: BTreeMap::new(), call_graph: BTreeMap::new(), findings: findings.into_iter().collect() };
+        }
+
+        let mut instructions = BTreeMap::new();
+        let mut leaders = BTreeSet::new();
+        let mut subroutines = BTreeSet::new();
+        let mut pending = vec![entry];
+        leaders.insert(entry);
+
+        while let Some(address) = pending.pop() {
+            if instructions.contains_key(&address) {
+                continue;
+            }
+            let instruction = decode(address);
+            instructions.insert(address, instruction);
+
+            let flow = flow(address, instruction);
+            for &target in flow.next.iter().chain(flow.call.iter()) {
+                if !in_rom(target) {
+                    findings.insert(Finding::OutsideRom { address, target });
+                    continue;
+                }
+                if flow.ends_block {
+                    leaders.insert(target);
+                }
+                pending.push(target);
+            }
+
+            if let Some(target) = flow.call.filter(|&target| in_rom(target)) {
+                subroutines.insert(target);
+            }
+            if let Instruction::JpOffset(base) = instruction {
+                findings.insert(Finding::ComputedJump { address, base });
+            }
+        }
+
+        let blocks = build_blocks(&instructions, &leaders);
+        let functions = std::iter::once(entry).chain(subroutines.iter().cloned()).collect::<BTreeSet<u16>>();
+        let mut call_graph = BTreeMap::new();
+
+        for &function in &functions {
+            let members = function_blocks(&blocks, function);
+            let calls = members.iter().filter_map(|start| blocks[start].call).filter(|&target| in_rom(target)).collect();
+            call_graph.insert(function, calls);
+
+            let returns = members.iter().map(|start| &blocks[start]).filter(|block| matches!(block.instructions.last(), Some((_, Instruction::Ret))));
+            if function == entry {
+                for block in returns {
+                    findings.insert(Finding::ReturnOutsideSubroutine { address: block.last_address() });
+                }
+            } else if returns.count() == 0 {
+                findings.insert(Finding::NoReturn { subroutine: function });
+            }
+        }
+
+        findings.extend(store_findings(&blocks, &instructions, entry, &subroutines, quirks));
+        findings.extend(unreachable_ranges(&instructions, start_address, rom.len()));
+
+        Analysis { entry, blocks, call_graph, findings: findings.into_iter().collect() }
+    }
+
+    /// The control-flow graph in Graphviz DOT. Subroutine entries are boxed in bold and calls are dashed edges
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+
+        for block in self.blocks.values() {
+            let label: String = block
+                .instructions
+                .iter()
+                .map(|(address, instruction)| format!("{:03X}  {}\\l", address, instruction))
+                .collect();
+            let style = if self.call_graph.contains_key(&block.start) { ", style=bold" } else { "" };
+            dot.push_str(&format!("    b{:03X} [label=\"{}\"{}];\n", block.start, label, style));
+        }
+
+        for block in self.blocks.values() {
+            for successor in block.successors.iter().filter(|successor| self.blocks.contains_key(successor)) {
+                dot.push_str(&format!("    b{:03X} -> b{:03X};\n", block.start, successor));
+            }
+            if let Some(target) = block.call.filter(|target| self.blocks.contains_key(target)) {
+                dot.push_str(&format!("    b{:03X} -> b{:03X} [style=dashed];\n", block.start, target));
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Which subroutine calls which, in Graphviz DOT
+    pub fn call_graph_dot(&self) -> String {
+        let mut dot = String::from("digraph calls {\n    node [shape=box, fontname=monospace];\n");
+        for (function, calls) in &self.call_graph {
+            let label = if *function == self.entry { format!("entry {:03X}", function) } else { format!("sub {:03X}", function) };
+            dot.push_str(&format!("    f{:03X} [label=\"{}\"];\n", function, label));
+            for target in calls {
+                dot.push_str(&format!("    f{:03X} -> f{:03X};\n", function, target));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Splits the reachable instructions into blocks, each starting at a leader or after a block-ending instruction
+fn build_blocks(instructions: &BTreeMap<u16, Instruction>, leaders: &BTreeSet<u16>) -> BTreeMap<u16, BasicBlock> {
+    let mut blocks = BTreeMap::new();
+
+    for &start in leaders.iter().filter(|start| instructions.contains_key(start)) {
+        let mut block = BasicBlock { start, instructions: Vec::new(), successors: Vec::new(), call: None };
+        let mut address = start;
+
+        loop {
+            let instruction = instructions[&address];
+            block.instructions.push((address, instruction));
+
+            let flow = flow(address, instruction);
+            let next = flow.next.first().cloned();
+            let continues = !flow.ends_block
+                && next.is_some_and(|next| instructions.contains_key(&next) && !leaders.contains(&next));
+
+            if !continues {
+                block.successors = flow.next.into_iter().filter(|next| instructions.contains_key(next)).collect();
+                block.call = flow.call;
+                break;
+            }
+            address = next.expect("checked above");
+        }
+
+        blocks.insert(start, block);
+    }
+
+    blocks
+}
+
+/// Blocks reachable from a subroutine's entry without following calls into other subroutines
+fn function_blocks(blocks: &BTreeMap<u16, BasicBlock>, entry: u16) -> BTreeSet<u16> {
+    let mut members = BTreeSet::new();
+    let mut pending = vec![entry];
+    while let Some(start) = pending.pop() {
+        if !blocks.contains_key(&start) || !members.insert(start) {
+            continue;
+        }
+        pending.extend(blocks[&start].successors.iter().cloned());
+    }
+    members
+}
+
+/// Works out what I holds going into each block, where a single value reaches it, and checks every store against
+/// the reachable code
+fn store_findings(
+    blocks: &BTreeMap<u16, BasicBlock>,
+    instructions: &BTreeMap<u16, Instruction>,
+    entry: u16,
+    subroutines: &BTreeSet<u16>,
+    quirks: Quirks
+) -> Vec<Finding> {
+    let mut index_in: BTreeMap<u16, IndexValue> = BTreeMap::new();
+    let mut pending = vec![entry];
+    index_in.insert(entry, IndexValue::Unknown);
+    for &subroutine in subroutines {
+        index_in.insert(subroutine, IndexValue::Unknown);
+        pending.push(subroutine);
+    }
+
+    while let Some(start) = pending.pop() {
+        let block = match blocks.get(&start) {
+            Some(block) => block,
+            None => continue
+        };
+
+        let mut index = index_in[&start];
+        for &(_, instruction) in &block.instructions {
+            index = next_index(index, instruction, quirks);
+        }
+        // A subroutine may change I before returning
+        if block.call.is_some() {
+            index = IndexValue::Unknown;
+        }
+
+        for &successor in &block.successors {
+            let merged = match index_in.get(&successor) {
+                Some(&current) => current.merge(index),
+                None => index
+            };
+            if index_in.get(&successor) != Some(&merged) {
+                index_in.insert(successor, merged);
+                pending.push(successor);
+            }
+        }
+    }
+
+    let code = instructions.keys().flat_map(|&address| vec![address, address + 1]).collect::<BTreeSet<u16>>();
+    let mut findings = Vec::new();
+    for block in blocks.values() {
+        let mut index = index_in.get(&block.start).cloned().unwrap_or(IndexValue::Unknown);
+        for &(address, instruction) in &block.instructions {
+            let length = match instruction {
+                Instruction::LdStore { x } => Some(x as u16 + 1),
+                Instruction::LdBcd { .. } => Some(3),
+                _ => None
+            };
+
+            match (length, index) {
+                (Some(length), IndexValue::Known(target)) => {
+                    if let Some(&overwritten) = code.range(target..target + length).next() {
+                        findings.push(Finding::SelfModifyingStore { address, target: overwritten });
+                    }
+                },
+                (Some(_), IndexValue::Unknown) => findings.push(Finding::UnknownStore { address }),
+                (None, _) => ()
+            }
+
+            index = next_index(index, instruction, quirks);
+        }
+    }
+
+    findings
+}
+
+/// What I holds after an instruction, matching how `Cpu` moves it under the quirks
+fn next_index(index: IndexValue, instruction: Instruction, quirks: Quirks) -> IndexValue {
+    match (instruction, index) {
+        (Instruction::LdIndex(address), _) => IndexValue::Known(address),
+        // These depend on a register
+        (Instruction::AddIndex { .. }, _) | (Instruction::LdFont { .. }, _) => IndexValue::Unknown,
+        (Instruction::LdStore { x }, IndexValue::Known(address)) | (Instruction::LdLoad { x }, IndexValue::Known(address))
+            if quirks.load_store_increments_i =>
+        {
+            let step = if quirks.load_store_increments_by_x { x as u16 } else { x as u16 + 1 };
+            IndexValue::Known(address.wrapping_add(step) & ADDRESS_MASK)
+        },
+        _ => index
+    }
+}
+
+/// Ranges of ROM bytes that no reachable instruction covers
+fn unreachable_ranges(instructions: &BTreeMap<u16, Instruction>, start_address: u16, length: usize) -> Vec<Finding> {
+    let covered = instructions.keys().flat_map(|&address| vec![address, address + 1]).collect::<BTreeSet<u16>>();
+    let mut findings = Vec::new();
+    let mut range: Option<(u16, u16)> = None;
+
+    for address in start_address..start_address + length as u16 {
+        if covered.contains(&address) {
+            if let Some((start, end)) = range.take() {
+                findings.push(Finding::Unreachable { start, end });
+            }
+        } else {
+            range = Some(match range {
+                Some((start, _)) => (start, address),
+                None => (address, address)
+            });
+        }
+    }
+
+    if let Some((start, end)) = range {
+        findings.push(Finding::Unreachable { start, end });
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_move_i_the_way_the_quirks_say() {
+        // The second store only reaches the code at 0x200 when the first moves I along by x + 1
+        let program = [
+            0xA1, 0xFD, // LD I, 0x1FD
+            0xF1, 0x55, // LD [I], V1
+            0xF1, 0x55, // LD [I], V1
+            0x12, 0x06  // JP 0x206
+        ];
+        let expected = [
+            (Quirks::default(), vec![]),
+            (Quirks::chip8(), vec![Finding::SelfModifyingStore { address: 0x204, target: 0x200 }]),
+            (Quirks::chip48(), vec![])
+        ];
+
+        for (quirks, findings) in expected.iter() {
+            assert_eq!(&Analysis::analyze(&program, 0x200, 0x200, *quirks).findings, findings, "{:?}", quirks);
+        }
+    }
+
+    #[test]
+    fn registers_make_i_unknown() {
+        let program = [0xA3, 0x00, 0xF0, 0x1E, 0xF0, 0x55, 0x12, 0x06];
+        let findings = Analysis::analyze(&program, 0x200, 0x200, Quirks::default()).findings;
+        assert_eq!(findings, vec![Finding::UnknownStore { address: 0x204 }]);
+    }
+}
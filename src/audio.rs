@@ -0,0 +1,201 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use crate::cpu::FRAMES_PER_SECOND;
+
+pub const SAMPLE_RATE: u32 = 44100;
+const AMPLITUDE: i16 = i16::MAX / 4;
+/// Bits per second an XO-CHIP pattern plays at with the default pitch of 64
+const PATTERN_RATE: f64 = 4000.0;
+const DEFAULT_PITCH: u8 = 64;
+
+/// A 128 bit waveform played one bit at a time, the way XO-CHIP describes sound. Each set bit is a high sample
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pattern {
+    pub bits: [u8; 16],
+    pub pitch: u8
+}
+
+impl Pattern {
+    /// The plain CHIP-8 buzzer, a square wave of 8 high and 8 low bits, which is 250Hz at the default pitch
+    pub fn beeper() -> Self {
+        Pattern {
+            bits: [0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00],
+            pitch: DEFAULT_PITCH
+        }
+    }
+
+    /// Bits played per second, doubling every 48 steps of pitch
+    pub fn playback_rate(&self) -> f64 {
+        PATTERN_RATE * 2f64.powf((self.pitch as f64 - DEFAULT_PITCH as f64) / 48.0)
+    }
+
+    fn bit(&self, position: usize) -> bool {
+        let position = position % 128;
+        self.bits[position / 8] & (0x80 >> (position % 8)) != 0
+    }
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Pattern::beeper()
+    }
+}
+
+/// Renders the sound output one emulated frame at a time, so a recording only depends on what the program
+/// did and not on how fast it ran. Each frame gets exactly its share of samples, 735 at 44.1kHz
+pub struct AudioRenderer {
+    sample_rate: u32,
+    frames: u64,
+    samples: u64,
+    /// Position in the pattern, in bits, carried across frames so the waveform doesn't jump
+    position: f64
+}
+
+impl AudioRenderer {
+    pub fn new(sample_rate: u32) -> Self {
+        AudioRenderer {
+            sample_rate,
+            frames: 0,
+            samples: 0,
+            position: 0.0
+        }
+    }
+
+    /// Samples for the next frame, playing `pattern` if the sound timer was running and silence otherwise
+    pub fn render_frame(&mut self, pattern: Option<&Pattern>) -> Vec<i16> {
+        self.frames += 1;
+        let end = self.frames * self.sample_rate as u64 / FRAMES_PER_SECOND as u64;
+        let count = (end - self.samples) as usize;
+        self.samples = end;
+
+        let pattern = match pattern {
+            Some(pattern) => pattern,
+            None => {
+                self.position = 0.0;
+                return vec![0; count];
+            }
+        };
+
+        let step = pattern.playback_rate() / self.sample_rate as f64;
+        (0..count)
+            .map(|_| {
+                let sample = if pattern.bit(self.position as usize) { AMPLITUDE } else { -AMPLITUDE };
+                self.position = (self.position + step) % 128.0;
+                sample
+            })
+            .collect()
+    }
+}
+
+/// Writes the sound output to a 16-bit mono WAV file, a frame at a time
+pub struct WavRecorder {
+    writer: hound::WavWriter<BufWriter<File>>,
+    renderer: AudioRenderer,
+    frames: usize
+}
+
+impl WavRecorder {
+    pub fn create(path: &Path) -> Result<Self, String> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int
+        };
+        let writer = hound::WavWriter::create(path, spec).map_err(|error| format!("can't create {}: {}", path.display(), error))?;
+
+        Ok(WavRecorder {
+            writer,
+            renderer: AudioRenderer::new(SAMPLE_RATE),
+            frames: 0
+        })
+    }
+
+    /// Adds a frame of sound, playing `pattern` if the sound timer was running during it. The CPU doesn't run
+    /// XO-CHIP's pattern and pitch instructions, so the frontend always passes `Pattern::beeper()`, but anything
+    /// driving the CPU itself can play its own patterns
+    pub fn record_frame(&mut self, sounding: bool, pattern: &Pattern) -> Result<(), String> {
+        self.frames += 1;
+        for sample in self.renderer.render_frame(if sounding { Some(pattern) } else { None }) {
+            self.writer.write_sample(sample).map_err(|error| format!("can't write audio: {}", error))?;
+        }
+        Ok(())
+    }
+
+    /// Fills in the WAV header. Returns how many frames were recorded
+    pub fn finish(self) -> Result<usize, String> {
+        let frames = self.frames;
+        self.writer.finalize().map_err(|error| format!("can't write audio: {}", error))?;
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lengths of the runs of equal samples
+    fn runs(samples: &[i16]) -> Vec<usize> {
+        let mut runs = vec![1];
+        for pair in samples.windows(2) {
+            if pair[0] == pair[1] {
+                *runs.last_mut().unwrap() += 1;
+            } else {
+                runs.push(1);
+            }
+        }
+        runs
+    }
+
+    #[test]
+    fn every_frame_gets_its_share_of_samples() {
+        let mut renderer = AudioRenderer::new(SAMPLE_RATE);
+        for _ in 0..120 {
+            assert_eq!(renderer.render_frame(Some(&Pattern::beeper())).len(), 735);
+        }
+
+        // 22050 doesn't divide by 60, so frames alternate between 367 and 368 samples and a second still adds up
+        let mut renderer = AudioRenderer::new(22050);
+        let counts: Vec<usize> = (0..60).map(|_| renderer.render_frame(None).len()).collect();
+        assert!(counts.iter().all(|&count| count == 367 || count == 368));
+        assert_eq!(counts.iter().sum::<usize>(), 22050);
+    }
+
+    #[test]
+    fn the_beeper_keeps_its_phase_across_frames() {
+        let mut renderer = AudioRenderer::new(SAMPLE_RATE);
+        let samples: Vec<i16> = (0..10).flat_map(|_| renderer.render_frame(Some(&Pattern::beeper()))).collect();
+        assert_eq!(samples[0], AMPLITUDE);
+
+        // Half a period of 250Hz is 88.2 samples, and restarting at a frame boundary would cut one short
+        let runs = runs(&samples);
+        assert_eq!(runs.len(), 10 * 735 * 500 / SAMPLE_RATE as usize + 1);
+        assert!(runs[..runs.len() - 1].iter().all(|&run| run == 88 || run == 89), "{:?}", runs);
+    }
+
+    #[test]
+    fn silence_restarts_the_pattern() {
+        let mut renderer = AudioRenderer::new(SAMPLE_RATE);
+        renderer.render_frame(Some(&Pattern::beeper()));
+        assert!(renderer.render_frame(None).iter().all(|&sample| sample == 0));
+        assert_eq!(&runs(&renderer.render_frame(Some(&Pattern::beeper())))[..1], &[89]);
+    }
+
+    #[test]
+    fn patterns_play_their_bits_at_their_pitch() {
+        let mut pattern = Pattern { bits: [0; 16], pitch: DEFAULT_PITCH };
+        pattern.bits[..8].copy_from_slice(&[0xFF; 8]);
+
+        // 64 high bits at 4000 bits a second last 705.6 samples, so the switches land on samples 706 and 1412
+        let mut renderer = AudioRenderer::new(SAMPLE_RATE);
+        let samples: Vec<i16> = (0..2).flat_map(|_| renderer.render_frame(Some(&pattern))).collect();
+        assert_eq!(&runs(&samples)[..2], &[706, 706]);
+
+        // 48 steps of pitch up plays twice as fast
+        pattern.pitch = DEFAULT_PITCH + 48;
+        assert_eq!(pattern.playback_rate(), 2.0 * PATTERN_RATE);
+        let mut renderer = AudioRenderer::new(SAMPLE_RATE);
+        assert_eq!(&runs(&renderer.render_frame(Some(&pattern)))[..2], &[353, 353]);
+    }
+}
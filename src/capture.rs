@@ -0,0 +1,192 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use gif::SetParameter;
+use crate::cpu::FRAMES_PER_SECOND;
+use crate::framebuffer::Framebuffer;
+use crate::palette::Palette;
+
+/// Palette index of every pixel, scaled up so each CHIP-8 pixel is a `scale` x `scale` square
+fn scaled_indexes(framebuffer: &Framebuffer, palette: &Palette, scale: usize) -> Vec<u8> {
+    let width = framebuffer.width() * scale;
+    let mut indexes = Vec::with_capacity(width * framebuffer.height() * scale);
+
+    for y in 0..framebuffer.height() {
+        let row: Vec<u8> = (0..framebuffer.width())
+            .flat_map(|x| {
+                // Colors past the end of a small palette are drawn with the foreground
+                let index = framebuffer.color_index(x, y);
+                let index = if index < palette.colors().len() { index } else { 1 };
+                std::iter::repeat_n(index as u8, scale)
+            })
+            .collect();
+
+        for _ in 0..scale {
+            indexes.extend_from_slice(&row);
+        }
+    }
+
+    indexes
+}
+
+/// The display as packed 24-bit RGB in the palette's colors, scaled up by `scale`
+pub fn render_rgb(framebuffer: &Framebuffer, palette: &Palette, scale: usize) -> Vec<u8> {
+    let indexes = scaled_indexes(framebuffer, palette, scale);
+    let mut rgb = Vec::with_capacity(indexes.len() * 3);
+    for index in indexes {
+        let color = palette.colors()[index as usize];
+        rgb.extend_from_slice(&[color.r, color.g, color.b]);
+    }
+    rgb
+}
+
+/// Saves the display as a PNG in the palette's colors, scaled up by `scale`
+pub fn save_screenshot(path: &Path, framebuffer: &Framebuffer, palette: &Palette, scale: usize) -> Result<(), String> {
+    save_png(path, framebuffer.width() * scale, framebuffer.height() * scale, &render_rgb(framebuffer, palette, scale))
+}
+
+/// Saves packed 24-bit RGB pixels as a PNG
+pub fn save_png(path: &Path, width: usize, height: usize, rgb: &[u8]) -> Result<(), String> {
+    let file = File::create(path).map_err(|error| format!("can't create {}: {}", path.display(), error))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(rgb))
+        .map_err(|error| format!("can't write {}: {}", path.display(), error))
+}
+
+enum Output {
+    /// Frames with the same picture are merged into one with a longer delay
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        pending: Option<Vec<u8>>,
+        centiseconds_written: usize
+    },
+    /// Every frame as packed 24-bit RGB, back to back
+    Raw(BufWriter<File>)
+}
+
+/// Records the display once per emulated frame, so the result plays back at exactly 60 frames per second
+/// however fast the emulator actually ran. Paths ending in `.gif` get an animated GIF, anything else raw
+/// RGB frames for ffmpeg, see `ffmpeg_command`
+pub struct Recorder {
+    output: Output,
+    palette: Palette,
+    scale: usize,
+    width: usize,
+    height: usize,
+    frames: usize
+}
+
+impl Recorder {
+    pub fn create(path: &Path, width: usize, height: usize, palette: &Palette, scale: usize) -> Result<Self, String> {
+        let create_error = |error: std::io::Error| format!("can't create {}: {}", path.display(), error);
+        let file = BufWriter::new(File::create(path).map_err(create_error)?);
+
+        let is_gif = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("gif"));
+        let output = if is_gif {
+            let global_palette: Vec<u8> = palette.colors().iter().flat_map(|color| vec![color.r, color.g, color.b]).collect();
+            let mut encoder = gif::Encoder::new(file, (width * scale) as u16, (height * scale) as u16, &global_palette)
+                .map_err(create_error)?;
+            encoder.set(gif::Repeat::Infinite).map_err(create_error)?;
+
+            Output::Gif { encoder, pending: None, centiseconds_written: 0 }
+        } else {
+            Output::Raw(file)
+        };
+
+        Ok(Recorder {
+            output,
+            palette: palette.clone(),
+            scale,
+            width,
+            height,
+            frames: 0
+        })
+    }
+
+    /// Adds the display at the end of an emulated frame
+    pub fn record_frame(&mut self, framebuffer: &Framebuffer) -> Result<(), String> {
+        if framebuffer.width() != self.width || framebuffer.height() != self.height {
+            return Err(String::from("the display changed size while recording"));
+        }
+
+        let frame = self.frames;
+        self.frames += 1;
+
+        match &mut self.output {
+            Output::Gif { pending, .. } => {
+                let indexes = scaled_indexes(framebuffer, &self.palette, self.scale);
+                if pending.as_ref() == Some(&indexes) {
+                    return Ok(());
+                }
+
+                match pending.replace(indexes) {
+                    Some(previous) => self.write_gif_frame(&previous, frame),
+                    None => Ok(())
+                }
+            },
+            Output::Raw(file) => file
+                .write_all(&render_rgb(framebuffer, &self.palette, self.scale))
+                .map_err(|error| format!("can't write a frame: {}", error))
+        }
+    }
+
+    /// Writes the GIF frame that stayed on screen until frame `until`. GIF delays are in hundredths of a second,
+    /// so each is rounded to keep the running total exact: three single frames get 2, 1 and 2
+    fn write_gif_frame(&mut self, indexes: &[u8], until: usize) -> Result<(), String> {
+        let width = (self.width * self.scale) as u16;
+        let height = (self.height * self.scale) as u16;
+
+        if let Output::Gif { encoder, centiseconds_written, .. } = &mut self.output {
+            let end = (until * 100 + FRAMES_PER_SECOND as usize / 2) / FRAMES_PER_SECOND as usize;
+            let mut frame = gif::Frame::from_indexed_pixels(width, height, indexes, None);
+            frame.delay = (end - *centiseconds_written).min(u16::MAX as usize) as u16;
+            *centiseconds_written = end;
+
+            encoder.write_frame(&frame).map_err(|error| format!("can't write a frame: {}", error))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Writes out anything still buffered. Returns how many frames were recorded
+    pub fn finish(mut self) -> Result<usize, String> {
+        let frames = self.frames;
+        let pending = match &mut self.output {
+            Output::Gif { pending, .. } => pending.take(),
+            Output::Raw(file) => {
+                file.flush().map_err(|error| format!("can't write a frame: {}", error))?;
+                None
+            }
+        };
+
+        if let Some(indexes) = pending {
+            self.write_gif_frame(&indexes, frames)?;
+        }
+
+        Ok(frames)
+    }
+
+    /// Command that turns raw frames recorded at `path` into a video
+    pub fn ffmpeg_command(&self, path: &Path) -> String {
+        format!(
+            "ffmpeg -f rawvideo -pixel_format rgb24 -video_size {}x{} -framerate {} -i {} output.mp4",
+            self.width * self.scale,
+            self.height * self.scale,
+            FRAMES_PER_SECOND,
+            path.display()
+        )
+    }
+
+    pub fn is_gif(&self) -> bool {
+        matches!(self.output, Output::Gif { .. })
+    }
+}
@@ -0,0 +1,281 @@
+use std::fmt;
+use crate::cpu::{Cpu, MEMORY_SIZE};
+use crate::symbols::Symbols;
+
+/// How a byte compares with what it was when the search last looked, or with a given value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Unchanged,
+    Changed,
+    Increased,
+    Decreased,
+    Equals(u8)
+}
+
+impl Comparison {
+    /// Reads `same`, `changed`, `inc`, `dec` or `= VALUE`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words.as_slice() {
+            ["same"] | ["unchanged"] => Ok(Comparison::Unchanged),
+            ["changed"] => Ok(Comparison::Changed),
+            ["inc"] | ["increased"] => Ok(Comparison::Increased),
+            ["dec"] | ["decreased"] => Ok(Comparison::Decreased),
+            ["=", value] => parse_byte(value).map(Comparison::Equals),
+            _ => Err(format!("expected same, changed, inc, dec or = VALUE, got '{}'", text))
+        }
+    }
+
+    fn matches(self, previous: u8, current: u8) -> bool {
+        match self {
+            Comparison::Unchanged => current == previous,
+            Comparison::Changed => current != previous,
+            Comparison::Increased => current > previous,
+            Comparison::Decreased => current < previous,
+            Comparison::Equals(value) => current == value
+        }
+    }
+}
+
+/// Finds where a program keeps a value, like the number of lives, by snapshotting memory and keeping only the
+/// addresses that changed the way the value did since the last snapshot
+#[derive(Clone, Debug)]
+pub struct MemorySearch {
+    snapshot: Vec<u8>,
+    candidates: Vec<u16>
+}
+
+impl MemorySearch {
+    /// A search with every address as a candidate
+    pub fn new(cpu: &Cpu) -> Self {
+        MemorySearch {
+            snapshot: cpu.memory.to_vec(),
+            candidates: (0..MEMORY_SIZE as u16).collect()
+        }
+    }
+
+    /// Drops the candidates that don't match, then takes a new snapshot to compare against next time
+    pub fn narrow(&mut self, cpu: &Cpu, comparison: Comparison) {
+        let snapshot = &self.snapshot;
+        self.candidates
+            .retain(|&address| comparison.matches(snapshot[address as usize], cpu.memory[address as usize]));
+        self.snapshot = cpu.memory.to_vec();
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+}
+
+/// What a cheat holds at a value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheatTarget {
+    Memory(u16),
+    Register(usize)
+}
+
+/// A memory address or register frozen at a value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cheat {
+    pub target: CheatTarget,
+    pub value: u8
+}
+
+impl Cheat {
+    /// Reads `TARGET=VALUE`, where the target is a register like `V3`, a label or an address
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Self, String> {
+        let (target, value) = text.split_once('=').ok_or_else(|| format!("expected TARGET=VALUE, got '{}'", text))?;
+        Ok(Cheat {
+            target: parse_target(target.trim(), symbols)?,
+            value: parse_byte(value.trim())?
+        })
+    }
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.target {
+            CheatTarget::Memory(address) => write!(f, "{:#05X}={:#04X}", address, self.value),
+            CheatTarget::Register(register) => write!(f, "V{:X}={:#04X}", register, self.value)
+        }
+    }
+}
+
+/// Reads a register like `V3`, a label or an address
+pub fn parse_target(text: &str, symbols: &Symbols) -> Result<CheatTarget, String> {
+    let register = text.strip_prefix('V').or_else(|| text.strip_prefix('v')).filter(|digit| digit.len() == 1);
+    if let Some(register) = register.and_then(|digit| usize::from_str_radix(digit, 16).ok()) {
+        return Ok(CheatTarget::Register(register));
+    }
+
+    match symbols.resolve(text)? {
+        address if (address as usize) < MEMORY_SIZE => Ok(CheatTarget::Memory(address)),
+        address => Err(format!("{:#X} is outside memory", address))
+    }
+}
+
+/// Reads a byte in hex like `0x05`, or in decimal
+fn parse_byte(text: &str) -> Result<u8, String> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => text.parse()
+    }
+    .map_err(|_| format!("'{}' isn't a byte", text))
+}
+
+/// The cheats in use, applied at the start of every frame
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cheats {
+    list: Vec<Cheat>
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats::default()
+    }
+
+    /// Reads cheats as saved by `to_strings`
+    pub fn parse<S: AsRef<str>>(cheats: &[S], symbols: &Symbols) -> Result<Self, String> {
+        let list = cheats.iter().map(|cheat| Cheat::parse(cheat.as_ref(), symbols)).collect::<Result<_, _>>()?;
+        Ok(Cheats { list })
+    }
+
+    pub fn to_strings(&self) -> Vec<String> {
+        self.list.iter().map(Cheat::to_string).collect()
+    }
+
+    /// Adds a cheat, replacing any other for the same target
+    pub fn add(&mut self, cheat: Cheat) {
+        self.remove(cheat.target);
+        self.list.push(cheat);
+    }
+
+    /// Removes the cheat for a target, returning whether there was one
+    pub fn remove(&mut self, target: CheatTarget) -> bool {
+        let count = self.list.len();
+        self.list.retain(|cheat| cheat.target != target);
+        self.list.len() != count
+    }
+
+    pub fn list(&self) -> &[Cheat] {
+        &self.list
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Writes every frozen value back
+    pub fn apply(&self, cpu: &mut Cpu) {
+        for cheat in &self.list {
+            match cheat.target {
+                CheatTarget::Memory(address) => {
+                    if cpu.memory[address as usize] != cheat.value {
+                        cpu.write_byte(address, cheat.value);
+                    }
+                },
+                CheatTarget::Register(register) => cpu.cpu_registers[register] = cheat.value
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// Sets bytes of memory, the way a running program would
+    fn write(cpu: &mut Cpu, bytes: &[(u16, u8)]) {
+        for &(address, value) in bytes {
+            cpu.write_byte(address, value);
+        }
+    }
+
+    #[test]
+    fn searches_narrow_down_to_the_byte_that_changed_like_the_value() {
+        let mut cpu = Cpu::new();
+        let mut search = MemorySearch::new(&cpu);
+        assert_eq!(search.candidates().len(), MEMORY_SIZE);
+
+        write(&mut cpu, &[(0x300, 3), (0x301, 3), (0x302, 9), (0x303, 1)]);
+        search.narrow(&cpu, Comparison::Changed);
+        assert_eq!(search.candidates(), &[0x300, 0x301, 0x302, 0x303]);
+
+        write(&mut cpu, &[(0x303, 2)]);
+        search.narrow(&cpu, Comparison::Unchanged);
+        assert_eq!(search.candidates(), &[0x300, 0x301, 0x302]);
+
+        write(&mut cpu, &[(0x300, 2), (0x301, 4), (0x302, 8)]);
+        search.narrow(&cpu, Comparison::Decreased);
+        assert_eq!(search.candidates(), &[0x300, 0x302]);
+
+        write(&mut cpu, &[(0x300, 3), (0x302, 9)]);
+        search.narrow(&cpu, Comparison::Increased);
+        assert_eq!(search.candidates(), &[0x300, 0x302]);
+
+        search.narrow(&cpu, Comparison::Equals(3));
+        assert_eq!(search.candidates(), &[0x300]);
+    }
+
+    #[test]
+    fn comparisons_parse() {
+        assert_eq!(Comparison::parse("same"), Ok(Comparison::Unchanged));
+        assert_eq!(Comparison::parse("changed"), Ok(Comparison::Changed));
+        assert_eq!(Comparison::parse("inc"), Ok(Comparison::Increased));
+        assert_eq!(Comparison::parse("decreased"), Ok(Comparison::Decreased));
+        assert_eq!(Comparison::parse("= 0x1F"), Ok(Comparison::Equals(0x1F)));
+        assert_eq!(Comparison::parse("= 31"), Ok(Comparison::Equals(31)));
+        assert!(Comparison::parse("= 256").is_err());
+        assert!(Comparison::parse("bigger").is_err());
+    }
+
+    #[test]
+    fn frozen_values_are_written_back_after_the_program_changes_them() {
+        let mut symbols = Symbols::new();
+        symbols.insert("lives", 0x300);
+        let mut cheats = Cheats::new();
+        cheats.add(Cheat::parse("lives=5", &symbols).unwrap());
+        cheats.add(Cheat::parse("V2=0x09", &symbols).unwrap());
+
+        // LD V0, 1, LD V2, 1, LD I, 0x300, LD [I], V2, which overwrites both
+        let mut cpu = Cpu::new();
+        cpu.load_rom(vec![0x60, 0x01, 0x62, 0x01, 0xA3, 0x00, 0xF2, 0x55]).unwrap();
+        cheats.apply(&mut cpu);
+        cpu.run_instructions(4);
+        assert_eq!((cpu.memory[0x300], cpu.cpu_registers[2]), (1, 1));
+
+        cheats.apply(&mut cpu);
+        assert_eq!((cpu.memory[0x300], cpu.cpu_registers[2]), (5, 9));
+    }
+
+    #[test]
+    fn freezing_a_target_again_replaces_its_value() {
+        let mut cheats = Cheats::new();
+        cheats.add(Cheat { target: CheatTarget::Memory(0x300), value: 1 });
+        cheats.add(Cheat { target: CheatTarget::Register(3), value: 2 });
+        cheats.add(Cheat { target: CheatTarget::Memory(0x300), value: 3 });
+        assert_eq!(cheats.to_strings(), vec!["V3=0x02", "0x300=0x03"]);
+
+        assert!(cheats.remove(CheatTarget::Register(3)));
+        assert!(!cheats.remove(CheatTarget::Register(3)));
+        assert_eq!(cheats.to_strings(), vec!["0x300=0x03"]);
+    }
+
+    #[test]
+    fn cheats_are_saved_for_one_rom() {
+        let mut cheats = Cheats::new();
+        cheats.add(Cheat { target: CheatTarget::Memory(0x2F0), value: 9 });
+        cheats.add(Cheat { target: CheatTarget::Register(0xA), value: 0xFF });
+
+        let mut config = Config::default();
+        config.rom_settings_mut("pong").cheats = Some(cheats.to_strings());
+        let saved = toml::to_string_pretty(&config).unwrap();
+        let config: Config = toml::from_str(&saved).unwrap();
+
+        let symbols = Symbols::new();
+        let loaded = config.settings_for("pong", &Default::default()).cheats.unwrap();
+        assert_eq!(Cheats::parse(&loaded, &symbols), Ok(cheats));
+        assert_eq!(config.settings_for("tetris", &Default::default()).cheats, None);
+    }
+}
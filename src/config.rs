@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "frontend")]
+use crate::keymap::Keymap;
+use crate::palette::Palette;
+use crate::quirks::{self, Quirks};
+
+const CONFIG_DIRECTORY: &str = "chip8";
+const CONFIG_FILE: &str = "config.toml";
+
+/// Quirks given either by profile name, e.g. `quirks = "chip8"`, or flag by flag, e.g. `quirks = { shift_uses_vy = true }`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum QuirkSetting {
+    Profile(String),
+    Custom(Quirks)
+}
+
+impl QuirkSetting {
+    /// The profile name when the quirks match one, otherwise the individual flags
+    pub fn from_quirks(quirks: Quirks) -> Self {
+        match quirks.profile_name() {
+            Some(name) => QuirkSetting::Profile(String::from(name)),
+            None => QuirkSetting::Custom(quirks)
+        }
+    }
+}
+
+/// Settings that can be given globally or per ROM. Anything left out falls back to the
+/// global default and then to the emulator's built-in value
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    /// Instructions executed per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock: Option<u32>,
+    /// Quirk profile name, see `quirks::PROFILES`, or individual quirks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quirks: Option<QuirkSetting>,
+    /// Display colors as a preset name or a list of colors, see `Palette::parse`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub palette: Option<String>,
+    /// Size of a CHIP-8 pixel on screen
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<u32>,
+    /// Host key name to CHIP-8 key, e.g. `Up = "5"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keymap: Option<BTreeMap<String, String>>,
+    /// Values held every frame, e.g. `["V3=0x09", "0x2F0=0x05"]`, see `Cheat::parse`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cheats: Option<Vec<String>>
+}
+
+impl Settings {
+    /// These settings with anything unset taken from `fallback`
+    pub fn or(&self, fallback: &Settings) -> Settings {
+        Settings {
+            clock: self.clock.or(fallback.clock),
+            quirks: self.quirks.clone().or_else(|| fallback.quirks.clone()),
+            palette: self.palette.clone().or_else(|| fallback.palette.clone()),
+            scale: self.scale.or(fallback.scale),
+            keymap: self.keymap.clone().or_else(|| fallback.keymap.clone()),
+            cheats: self.cheats.clone().or_else(|| fallback.cheats.clone())
+        }
+    }
+
+    pub fn parse_quirks(&self) -> Result<Option<Quirks>, String> {
+        match &self.quirks {
+            Some(QuirkSetting::Profile(name)) => Quirks::from_profile(name)
+                .map(Some)
+                .ok_or_else(|| format!("unknown quirk profile '{}', expected one of {}", name, quirks::PROFILES.join(", "))),
+            Some(QuirkSetting::Custom(quirks)) => Ok(Some(*quirks)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn parse_palette(&self) -> Result<Option<Palette>, String> {
+        self.palette.as_deref().map(Palette::parse).transpose()
+    }
+
+    #[cfg(feature = "frontend")]
+    pub fn parse_keymap(&self) -> Result<Option<Keymap>, String> {
+        self.keymap
+            .as_ref()
+            .map(|keys| Keymap::from_pairs(keys.iter().map(|(host_key, chip8_key)| (host_key.as_str(), chip8_key.as_str()))))
+            .transpose()
+    }
+}
+
+/// The user's config file: global defaults plus overrides for ROMs identified by the SHA-1 of their bytes
+/// ```toml
+/// [defaults]
+/// clock = 600
+/// palette = "amber"
+///
+/// [roms.0123456789abcdef0123456789abcdef01234567]
+/// quirks = "chip8"
+///
+/// [roms.0123456789abcdef0123456789abcdef01234567.keymap]
+/// Up = "5"
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub defaults: Settings,
+    #[serde(default)]
+    pub roms: BTreeMap<String, Settings>
+}
+
+impl Config {
+    /// Where the config file lives, e.g. `~/.config/chip8/config.toml` on Linux
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|directory| directory.join(CONFIG_DIRECTORY).join(CONFIG_FILE))
+    }
+
+    /// Loads the config file, or an empty config if there isn't one yet
+    pub fn load() -> Result<Self, String> {
+        match Config::path() {
+            Some(path) => Config::load_from(&path),
+            None => Ok(Config::default())
+        }
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+            Err(error) => return Err(format!("can't read {}: {}", path.display(), error))
+        };
+
+        toml::from_str(&text).map_err(|error| format!("{}: {}", path.display(), error))
+    }
+
+    pub fn save(&self) -> Result<PathBuf, String> {
+        let path = Config::path().ok_or_else(|| String::from("can't find the config directory"))?;
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory).map_err(|error| format!("can't create {}: {}", directory.display(), error))?;
+        }
+
+        let text = toml::to_string_pretty(self).map_err(|error| format!("can't write the config: {}", error))?;
+        fs::write(&path, text).map_err(|error| format!("can't write {}: {}", path.display(), error))?;
+
+        Ok(path)
+    }
+
+    /// Settings for a ROM: its overrides, then `recommended` (e.g. from the ROM database), then the global defaults
+    pub fn settings_for(&self, rom_hash: &str, recommended: &Settings) -> Settings {
+        let recommended = recommended.or(&self.defaults);
+        match self.roms.get(rom_hash) {
+            Some(overrides) => overrides.or(&recommended),
+            None => recommended
+        }
+    }
+
+    /// The overrides for a ROM, created empty if it doesn't have any yet
+    pub fn rom_settings_mut(&mut self, rom_hash: &str) -> &mut Settings {
+        self.roms.entry(rom_hash.to_string()).or_default()
+    }
+}
+
+/// Lowercase hex SHA-1 of a ROM, the key its settings are stored under
+pub fn rom_hash(bytes: &[u8]) -> String {
+    sha1_smol::Sha1::from(bytes).digest().to_string()
+}
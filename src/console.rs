@@ -0,0 +1,164 @@
+use ggez::event::KeyCode;
+use crate::cheats::{self, Cheat, CheatTarget, Comparison, MemorySearch};
+use crate::display::Emulator;
+
+/// Lines of output kept on screen
+const OUTPUT_LINES: usize = 16;
+/// Candidates listed after a search narrows down to this many or fewer
+const LISTED_CANDIDATES: usize = 8;
+
+const HELP: [&str; 7] = [
+    "search                  start a memory search",
+    "same/changed/inc/dec    keep addresses that changed that way since last time",
+    "= VALUE                 keep addresses holding VALUE",
+    "list                    show the candidates",
+    "freeze TARGET[=VALUE]   hold an address, label or register like V3 at a value",
+    "unfreeze TARGET         stop holding it",
+    "cheats, save            list the cheats, save them for this ROM"
+];
+
+/// A command line for searching memory and managing cheats. It takes every key while open, so the game keeps
+/// running without input until it's closed
+pub struct Console {
+    input: String,
+    output: Vec<String>
+}
+
+impl Console {
+    pub fn new(emulator: &Emulator) -> Self {
+        let mut console = Console {
+            input: String::new(),
+            output: Vec::new()
+        };
+        match &emulator.ram_search {
+            Some(search) => console.print(format!("Candidates left from the last search: {}", search.candidates().len())),
+            None => console.print(String::from("Type help for commands"))
+        }
+        console
+    }
+
+    /// Lines of text to show, ending with the command being typed
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![String::from("Console"), String::new()];
+        lines.extend(self.output.iter().cloned());
+        lines.push(format!("> {}_", self.input));
+        lines
+    }
+
+    /// Adds a typed character to the command
+    pub fn type_char(&mut self, character: char) {
+        if !character.is_control() {
+            self.input.push(character);
+        }
+    }
+
+    /// Handles a key press, returning whether the console stays open
+    pub fn handle_key(&mut self, emulator: &mut Emulator, keycode: KeyCode) -> bool {
+        match keycode {
+            KeyCode::Return | KeyCode::NumpadEnter => {
+                let command = std::mem::take(&mut self.input);
+                self.print(format!("> {}", command));
+                if let Err(error) = self.run(emulator, command.trim()) {
+                    self.print(error);
+                }
+            },
+            KeyCode::Back => {
+                self.input.pop();
+            },
+            KeyCode::Escape | KeyCode::F8 => return false,
+            _ => ()
+        }
+
+        true
+    }
+
+    fn print(&mut self, line: String) {
+        self.output.push(line);
+        let excess = self.output.len().saturating_sub(OUTPUT_LINES);
+        self.output.drain(..excess);
+    }
+
+    fn run(&mut self, emulator: &mut Emulator, command: &str) -> Result<(), String> {
+        let (name, argument) = command.split_once(' ').map(|(name, argument)| (name, argument.trim())).unwrap_or((command, ""));
+        match name {
+            "" => (),
+            "help" => HELP.iter().for_each(|line| self.print(line.to_string())),
+            "search" => {
+                emulator.ram_search = Some(MemorySearch::new(&emulator.cpu));
+                self.print(format!("Searching all {} addresses", emulator.cpu.memory.len()));
+            },
+            "same" | "unchanged" | "changed" | "inc" | "increased" | "dec" | "decreased" | "=" => {
+                let comparison = Comparison::parse(command)?;
+                let search = emulator.ram_search.as_mut().ok_or_else(|| String::from("Start a search first"))?;
+                search.narrow(&emulator.cpu, comparison);
+                let count = search.candidates().len();
+                self.print(format!("Candidates left: {}", count));
+                if count <= LISTED_CANDIDATES {
+                    self.list(emulator);
+                }
+            },
+            "list" => self.list(emulator),
+            "freeze" => {
+                let cheat = match argument.contains('=') {
+                    true => Cheat::parse(argument, &emulator.symbols)?,
+                    false => {
+                        let target = cheats::parse_target(argument, &emulator.symbols)?;
+                        let value = match target {
+                            CheatTarget::Memory(address) => emulator.cpu.memory[address as usize],
+                            CheatTarget::Register(register) => emulator.cpu.cpu_registers[register]
+                        };
+                        Cheat { target, value }
+                    }
+                };
+                emulator.cheats.add(cheat);
+                self.print(format!("Froze {}", cheat));
+            },
+            "unfreeze" => {
+                let target = cheats::parse_target(argument, &emulator.symbols)?;
+                match emulator.cheats.remove(target) {
+                    true => self.print(format!("Unfroze {}", argument)),
+                    false => self.print(format!("{} isn't frozen", argument))
+                }
+            },
+            "cheats" => {
+                if emulator.cheats.is_empty() {
+                    self.print(String::from("No cheats"));
+                }
+                for cheat in emulator.cheats.list().to_vec() {
+                    self.print(cheat.to_string());
+                }
+            },
+            "save" => {
+                let hash = emulator.rom_hash.clone().ok_or_else(|| String::from("No ROM to save cheats for"))?;
+                let config = emulator.config.as_mut().ok_or_else(|| String::from("The config file isn't in use"))?;
+                let cheats = emulator.cheats.to_strings();
+                config.rom_settings_mut(&hash).cheats = if cheats.is_empty() { None } else { Some(cheats) };
+                let path = config.save()?;
+                self.print(format!("Saved to {}", path.display()));
+            },
+            _ => return Err(format!("Unknown command '{}', type help for commands", name))
+        }
+
+        Ok(())
+    }
+
+    /// Prints the first few candidates with the values they hold now
+    fn list(&mut self, emulator: &Emulator) {
+        let search = match &emulator.ram_search {
+            Some(search) => search,
+            None => return self.print(String::from("Start a search first"))
+        };
+
+        let candidates = search.candidates();
+        for &address in candidates.iter().take(LISTED_CANDIDATES) {
+            let label = match emulator.symbols.is_empty() {
+                true => String::new(),
+                false => format!("  {}", emulator.symbols.describe(address))
+            };
+            self.print(format!("{:#05X} = {:#04X}{}", address, emulator.cpu.memory[address as usize], label));
+        }
+        if candidates.len() > LISTED_CANDIDATES {
+            self.print(format!("... and {} more", candidates.len() - LISTED_CANDIDATES));
+        }
+    }
+}
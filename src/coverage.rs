@@ -0,0 +1,287 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::cpu::MEMORY_SIZE;
+use crate::instruction::Instruction;
+
+/// Which instructions ran and which bytes were read or written as data, over one or more runs of a ROM
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coverage {
+    /// SHA-1 of the ROM, so runs of different ROMs don't get mixed up
+    pub rom_hash: String,
+    pub runs: u32,
+    /// Times the instruction at each address ran
+    executions: Vec<u64>,
+    /// Bytes read by DRW and LD Vx, [I]
+    reads: Vec<bool>,
+    /// Bytes written by LD B, Vx, LD [I], Vx and `Cpu::write_byte`
+    writes: Vec<bool>
+}
+
+/// Coverage as it's saved, listing only the addresses that were touched
+#[derive(Serialize, Deserialize)]
+struct SavedCoverage {
+    rom_hash: String,
+    runs: u32,
+    executions: Vec<(u16, u64)>,
+    reads: Vec<u16>,
+    writes: Vec<u16>
+}
+
+impl Coverage {
+    /// Empty coverage for a single run of the ROM with this hash
+    pub fn new(rom_hash: &str) -> Self {
+        Coverage {
+            rom_hash: rom_hash.to_string(),
+            runs: 1,
+            executions: vec![0; MEMORY_SIZE],
+            reads: vec![false; MEMORY_SIZE],
+            writes: vec![false; MEMORY_SIZE]
+        }
+    }
+
+    pub fn record_execution(&mut self, address: usize) {
+        self.executions[address] += 1;
+    }
+
+    pub fn record_read(&mut self, address: usize) {
+        self.reads[address] = true;
+    }
+
+    pub fn record_write(&mut self, address: usize) {
+        self.writes[address] = true;
+    }
+
+    pub fn executions(&self, address: usize) -> u64 {
+        self.executions[address]
+    }
+
+    pub fn was_read(&self, address: usize) -> bool {
+        self.reads[address]
+    }
+
+    pub fn was_written(&self, address: usize) -> bool {
+        self.writes[address]
+    }
+
+    /// Adds another run's counts to these
+    pub fn merge(&mut self, other: &Coverage) -> Result<(), String> {
+        if other.rom_hash != self.rom_hash {
+            return Err(String::from("the coverage is of a different ROM"));
+        }
+
+        self.runs += other.runs;
+        for address in 0..MEMORY_SIZE {
+            self.executions[address] += other.executions[address];
+            self.reads[address] |= other.reads[address];
+            self.writes[address] |= other.writes[address];
+        }
+        Ok(())
+    }
+
+    /// Loads coverage saved by `save`, or `None` if the file doesn't exist yet
+    pub fn load(path: &Path) -> Result<Option<Self>, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(format!("can't read {}: {}", path.display(), error))
+        };
+        let saved: SavedCoverage = serde_json::from_str(&text).map_err(|error| format!("{}: {}", path.display(), error))?;
+
+        let mut coverage = Coverage::new(&saved.rom_hash);
+        coverage.runs = saved.runs;
+        for (address, count) in saved.executions {
+            coverage.executions[address as usize % MEMORY_SIZE] = count;
+        }
+        for address in saved.reads {
+            coverage.reads[address as usize % MEMORY_SIZE] = true;
+        }
+        for address in saved.writes {
+            coverage.writes[address as usize % MEMORY_SIZE] = true;
+        }
+        Ok(Some(coverage))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let touched = |flags: &[bool]| (0..MEMORY_SIZE as u16).filter(|&address| flags[address as usize]).collect();
+        let saved = SavedCoverage {
+            rom_hash: self.rom_hash.clone(),
+            runs: self.runs,
+            executions: (0..MEMORY_SIZE as u16)
+                .map(|address| (address, self.executions[address as usize]))
+                .filter(|&(_, count)| count > 0)
+                .collect(),
+            reads: touched(&self.reads),
+            writes: touched(&self.writes)
+        };
+
+        let text = serde_json::to_string(&saved).map_err(|error| format!("can't write the coverage: {}", error))?;
+        fs::write(path, text).map_err(|error| format!("can't write {}: {}", path.display(), error))
+    }
+
+    /// Splits a ROM into instructions and leftover data bytes, the way it ran: every executed address starts an
+    /// instruction, and the rest is read two bytes at a time like `disasm` does
+    fn lines(&self, rom: &[u8], start_address: u16) -> Vec<Line> {
+        let end = start_address as usize + rom.len();
+        let mut lines = Vec::new();
+        let mut address = start_address as usize;
+
+        while address < end {
+            let executed = self.executions[address % MEMORY_SIZE] > 0;
+            let aligned = (address - start_address as usize).is_multiple_of(2);
+            let next_executed = self.executions[(address + 1) % MEMORY_SIZE] > 0;
+
+            if address + 1 < end && (executed || (aligned && !next_executed)) {
+                lines.push(Line { address, length: 2, executions: self.executions[address % MEMORY_SIZE] });
+                address += 2;
+            } else {
+                lines.push(Line { address, length: 1, executions: 0 });
+                address += 1;
+            }
+        }
+
+        lines
+    }
+
+    /// The ROM's disassembly with how often each instruction ran, and which bytes were read (R) or written (W)
+    pub fn annotate(&self, rom: &[u8], start_address: u16) -> String {
+        let mut text = String::new();
+        for line in self.lines(rom, start_address) {
+            let bytes = &rom[line.address - start_address as usize..][..line.length];
+            let range = line.address..line.address + line.length;
+            let read = if range.clone().any(|address| self.reads[address % MEMORY_SIZE]) { 'R' } else { ' ' };
+            let written = if range.clone().any(|address| self.writes[address % MEMORY_SIZE]) { 'W' } else { ' ' };
+            let count = if line.executions > 0 { line.executions.to_string() } else { String::from("-") };
+
+            let code = if line.length == 2 {
+                let opcode = (bytes[0] as u16) << 8 | bytes[1] as u16;
+                format!("{:04X}  {}", opcode, Instruction::decode(opcode))
+            } else {
+                format!("{:02X}    DB {:#04X}", bytes[0], bytes[0])
+            };
+            text.push_str(&format!("{:>8} {}{}  {:#05X}  {}\n", count, read, written, line.address, code));
+        }
+        text
+    }
+
+    /// Totals for the ROM: instructions that ran, and bytes used as data
+    pub fn summary(&self, rom: &[u8], start_address: u16) -> String {
+        let executed = self.lines(rom, start_address).iter().filter(|line| line.executions > 0).count();
+        let rom_range = start_address as usize..start_address as usize + rom.len();
+        let read = rom_range.clone().filter(|&address| self.reads[address % MEMORY_SIZE]).count();
+        let written = rom_range.filter(|&address| self.writes[address % MEMORY_SIZE]).count();
+        let words = rom.len() / 2;
+
+        format!(
+            "{} runs: {} of {} instruction words executed ({:.1}%), {} ROM bytes read as data, {} written",
+            self.runs,
+            executed,
+            words,
+            if words == 0 { 0.0 } else { executed as f64 * 100.0 / words as f64 },
+            read,
+            written
+        )
+    }
+
+    /// An lcov tracefile. Line numbers are those of `disasm` output for the ROM, one line per two bytes, which
+    /// `source` should name. Subroutines are listed as functions named after their address
+    pub fn lcov(&self, rom: &[u8], start_address: u16, source: &str, subroutines: &[u16]) -> String {
+        let line_number = |address: usize| (address - start_address as usize) / 2 + 1;
+        let mut text = format!("TN:\nSF:{}\n", source);
+
+        let in_rom = |&&address: &&u16| address >= start_address && (address as usize) < start_address as usize + rom.len();
+        for &subroutine in subroutines.iter().filter(in_rom) {
+            text.push_str(&format!("FN:{},sub_{:03X}\n", line_number(subroutine as usize), subroutine));
+        }
+        for &subroutine in subroutines.iter().filter(in_rom) {
+            text.push_str(&format!("FNDA:{},sub_{:03X}\n", self.executions[subroutine as usize], subroutine));
+        }
+        let functions_hit = subroutines.iter().filter(in_rom).filter(|&&subroutine| self.executions[subroutine as usize] > 0).count();
+        text.push_str(&format!("FNF:{}\nFNH:{}\n", subroutines.iter().filter(in_rom).count(), functions_hit));
+
+        // An instruction that doesn't start on an even offset counts towards the line it starts in
+        let mut counts: Vec<(usize, u64)> = Vec::new();
+        for line in self.lines(rom, start_address).into_iter().filter(|line| line.length == 2) {
+            let number = line_number(line.address);
+            match counts.last_mut() {
+                Some((last, count)) if *last == number => *count += line.executions,
+                _ => counts.push((number, line.executions))
+            }
+        }
+
+        for &(number, count) in &counts {
+            text.push_str(&format!("DA:{},{}\n", number, count));
+        }
+        let hit = counts.iter().filter(|&&(_, count)| count > 0).count();
+        text.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", counts.len(), hit));
+        text
+    }
+}
+
+/// An instruction, or a byte that's left over between instructions
+struct Line {
+    address: usize,
+    length: usize,
+    executions: u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// LD V0, 1, CALL 0x206, JP 0x204, RET, a data byte and LD V0, 2 starting on an odd address
+    const ROM: [u8; 11] = [0x60, 0x01, 0x22, 0x06, 0x12, 0x04, 0x00, 0xEE, 0xAA, 0x60, 0x02];
+
+    fn run(executed: &[(usize, u64)]) -> Coverage {
+        let mut coverage = Coverage::new("rom");
+        for &(address, count) in executed {
+            for _ in 0..count {
+                coverage.record_execution(address);
+            }
+        }
+        coverage
+    }
+
+    #[test]
+    fn merged_runs_add_up() {
+        let mut coverage = run(&[(0x200, 1), (0x202, 1), (0x206, 1)]);
+        coverage.record_read(0x208);
+        let mut other = run(&[(0x200, 1), (0x204, 3), (0x209, 1)]);
+        other.record_write(0x300);
+        coverage.merge(&other).unwrap();
+
+        assert_eq!(coverage.runs, 2);
+        let counts: Vec<u64> = [0x200, 0x202, 0x204, 0x206, 0x208, 0x209].iter().map(|&address| coverage.executions(address)).collect();
+        assert_eq!(counts, [2, 1, 3, 1, 0, 1]);
+        assert!(coverage.was_read(0x208) && coverage.was_written(0x300));
+        assert!(!coverage.was_written(0x208) && !coverage.was_read(0x300));
+
+        assert!(coverage.merge(&Coverage::new("another rom")).is_err());
+        assert_eq!(coverage.runs, 2);
+    }
+
+    #[test]
+    fn lcov_lists_subroutines_and_disassembly_lines() {
+        let mut coverage = run(&[(0x200, 1), (0x202, 1), (0x206, 1)]);
+        coverage.merge(&run(&[(0x200, 1), (0x204, 3)])).unwrap();
+
+        assert_eq!(
+            coverage.lcov(&ROM, 0x200, "game.asm", &[0x206, 0x209, 0x300]),
+            "TN:\nSF:game.asm\n\
+             FN:4,sub_206\nFN:5,sub_209\nFNDA:1,sub_206\nFNDA:0,sub_209\nFNF:2\nFNH:1\n\
+             DA:1,2\nDA:2,1\nDA:3,3\nDA:4,1\nDA:5,0\nLF:5\nLH:4\nend_of_record\n"
+        );
+    }
+
+    #[test]
+    fn an_instruction_on_an_odd_address_counts_towards_the_line_it_starts_in() {
+        let coverage = run(&[(0x200, 1), (0x209, 2)]);
+
+        assert_eq!(
+            coverage.lcov(&ROM, 0x200, "game.asm", &[]),
+            "TN:\nSF:game.asm\nFNF:0\nFNH:0\n\
+             DA:1,1\nDA:2,0\nDA:3,0\nDA:4,0\nDA:5,2\nLF:5\nLH:2\nend_of_record\n"
+        );
+    }
+}
@@ -1,492 +1,694 @@
-use rand::Rng;
-const START_ADDRESS: u16 = 0x200;
-const FONT_START_ADDRESS: usize = 0x50;
+use std::collections::BTreeSet;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use crate::coverage::Coverage;
+use crate::framebuffer::Framebuffer;
+use crate::instruction::Instruction;
+use crate::profiler::Profiler;
+use crate::quirks::Quirks;
+use crate::sprites::Sprite;
+use self::recompiler::Recompiler;
+
+mod recompiler;
+mod state;
+
+pub use self::recompiler::Divergence;
+
+pub const START_ADDRESS: u16 = 0x200;
+pub const FONT_START_ADDRESS: usize = 0x50;
+pub const MEMORY_SIZE: usize = 4096;
+pub const STACK_SIZE: usize = 16;
+pub const MAX_ROM_SIZE: usize = MEMORY_SIZE - START_ADDRESS as usize;
+const ADDRESS_MASK: u16 = 0x0FFF;
 
 pub const SCREEN_WIDTH: u8 = 64;
 pub const SCREEN_HEIGHT: u8 = 32;
 
+/// The timers count down once per frame, and `run_frame` is meant to be called this often
+pub const FRAMES_PER_SECOND: u32 = 60;
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: usize = 10;
+
+/// How instructions get executed. Both backends produce exactly the same results
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Decodes and runs one instruction at a time
+    Interpreter,
+    /// Translates straight-line runs of instructions into cached closures and runs them back to back
+    Recompiler
+}
+
+/// State of an `Fx0A` waiting for a key to be pressed and released
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct KeyWait {
+    register: usize,
+    pressed: Option<usize> // The key that went down, which completes the wait once it comes back up
+}
+
+#[derive(Clone)]
 pub struct Cpu {
     pub current_opcode: u16,
-    pub memory: [u8; 4096],
+    pub memory: [u8; MEMORY_SIZE],
     pub cpu_registers: [u8; 16],
     pub index_register: u16,
     pub program_counter: u16, // Holds the address of the next instruction to execute
-    pub execution_stack: [u16; 16],
+    pub execution_stack: [u16; STACK_SIZE],
     pub stack_pointer: usize,
     pub delay_timer: u8, // If it's zero it stays zero, otherwise it counts down to zero at 60Hz
     pub sound_timer: u8, // If it's zero it stays zero, otherwise it decrements and makes a sound every time it does
     pub keypad: [bool; 16],
-    pub graphics: [[bool; SCREEN_WIDTH as usize]; SCREEN_HEIGHT as usize],
-    pub can_draw: bool
+    pub graphics: Framebuffer,
+    pub can_draw: bool,
+    pub quirks: Quirks,
+    pub sprites_drawn: BTreeSet<Sprite>, // Every address and height a DRW has drawn from
+    pub coverage: Option<Coverage>, // Collected while set, with the interpreter standing in for the recompiler
+    pub profiler: Option<Profiler>, // Same as coverage
+    pub breakpoints: BTreeSet<u16>, // Running stops before an instruction at any of these, which also needs the interpreter
+    breakpoint_hit: Option<u16>, // The breakpoint running last stopped at, which the next run steps past
+    waiting_for_vblank: bool, // Set by a draw under the display wait quirk, until the next frame starts
+    key_wait: Option<KeyWait>, // Set by Fx0A until a key is pressed and released
+    instruction_cache: Vec<Option<Instruction>>, // Decoded instruction at each address, cleared when memory under it changes
+    backend: Backend,
+    recompiler: Recompiler,
+    rng: StdRng
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
+#[allow(non_snake_case)]
 impl Cpu {
     pub fn new() -> Self {
         let mut chip8 = Cpu {
             cpu_registers: [0; 16],
-            memory: [0; 4096],
+            memory: [0; MEMORY_SIZE],
             index_register: 0,
             program_counter: START_ADDRESS,
-            execution_stack: [0; 16],
+            execution_stack: [0; STACK_SIZE],
             stack_pointer: 0,
             delay_timer: 0,
             sound_timer: 0,
             keypad: [false; 16],
-            graphics: [[false; SCREEN_WIDTH as usize]; SCREEN_HEIGHT as usize],
+            graphics: Framebuffer::new(SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize),
             current_opcode: 0,
-            can_draw: false
+            can_draw: false,
+            quirks: Quirks::default(),
+            sprites_drawn: BTreeSet::new(),
+            coverage: None,
+            profiler: None,
+            breakpoints: BTreeSet::new(),
+            breakpoint_hit: None,
+            waiting_for_vblank: false,
+            key_wait: None,
+            instruction_cache: vec![None; MEMORY_SIZE],
+            backend: Backend::Interpreter,
+            recompiler: Recompiler::new(),
+            rng: StdRng::from_entropy()
         };
     
         chip8.initialize_fontset();
     
-        return chip8;
+        chip8
     }
 
-    pub fn load_rom(&mut self, bytes: Vec<u8>) {
-        for (index, &byte) in bytes.iter().enumerate() {
-            self.memory[START_ADDRESS as usize + index] = byte;
+    /// Copies the ROM into memory at the start address, failing if it doesn't fit
+    pub fn load_rom(&mut self, bytes: Vec<u8>) -> Result<(), String> {
+        self.load_rom_at(bytes, START_ADDRESS)
+    }
+
+    /// Copies the ROM into memory at `address` and starts executing from there. Fails without touching memory if
+    /// the ROM runs past the end of it
+    pub fn load_rom_at(&mut self, bytes: Vec<u8>, address: u16) -> Result<(), String> {
+        let address = (address & ADDRESS_MASK) as usize;
+        let capacity = MEMORY_SIZE - address;
+        if bytes.len() > capacity {
+            return Err(format!("the ROM is {} bytes but only {} bytes fit in memory from {:#05X}", bytes.len(), capacity, address));
         }
+
+        self.memory[address..address + bytes.len()].copy_from_slice(&bytes);
+
+        self.program_counter = address as u16;
+        self.invalidate_instruction_cache();
+        Ok(())
     }
 
+    /// Runs one instruction and then updates the timers
     pub fn cycle(&mut self) {
-        self.fetch_opcode();
-        self.execute_opcode();
+        self.run_instructions(1);
+        self.update_timers();
+    }
+
+    /// Runs a 60Hz frame: a number of instructions followed by a single timer update.
+    /// Returns whether the buzzer sounded during the frame
+    pub fn run_frame(&mut self, instructions_per_frame: usize) -> bool {
+        self.run_instructions(instructions_per_frame);
+        let sounding = self.sound_timer > 0;
         self.update_timers();
+        sounding
+    }
+
+    /// Runs a number of instructions with the selected backend without touching the timers.
+    /// Stops early when a draw has to wait for the next frame or while waiting for a key.
+    /// Compiled blocks don't stop between instructions, so breakpoints, coverage and profiling always interpret
+    pub fn run_instructions(&mut self, count: usize) {
+        self.poll_key_wait();
+        let mut resuming = self.breakpoint_hit.take() == Some(self.program_counter & ADDRESS_MASK);
+
+        match self.backend {
+            Backend::Recompiler if self.breakpoints.is_empty() && self.coverage.is_none() && self.profiler.is_none() => {
+                let mut remaining = count;
+                while remaining > 0 && !self.halted() {
+                    remaining -= self.run_block(remaining);
+                }
+            },
+            _ => {
+                for _ in 0..count {
+                    if self.halted() {
+                        break;
+                    }
+
+                    let address = (self.program_counter & ADDRESS_MASK) as usize;
+                    if !resuming && self.breakpoints.contains(&(address as u16)) {
+                        self.breakpoint_hit = Some(address as u16);
+                        break;
+                    }
+                    resuming = false;
+
+                    if let Some(coverage) = &mut self.coverage {
+                        coverage.record_execution(address);
+                    }
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.record_instruction(address);
+                    }
+                    let instruction = self.fetch_instruction();
+                    self.execute_instruction(instruction);
+                }
+            }
+        }
+    }
+
+    /// The breakpoint the last run stopped at, if it stopped at one
+    pub fn breakpoint_hit(&self) -> Option<u16> {
+        self.breakpoint_hit
+    }
+
+    /// Whether the CPU is idle until the next frame because a draw is waiting for the vertical blank
+    pub fn waiting_for_vblank(&self) -> bool {
+        self.waiting_for_vblank
+    }
+
+    /// Whether the CPU is halted on an `Fx0A` until a key is pressed and released. Timers keep running meanwhile
+    pub fn waiting_for_key(&self) -> bool {
+        self.key_wait.is_some()
+    }
+
+    fn halted(&self) -> bool {
+        self.waiting_for_vblank || self.key_wait.is_some()
+    }
+
+    /// Presses or releases a key on the keypad. Same as setting `keypad` directly, except that a press and
+    /// release between two runs of the CPU still completes an `Fx0A`
+    pub fn set_key(&mut self, key: usize, pressed: bool) {
+        let key = key & 0xF;
+        self.keypad[key] = pressed;
+
+        if let Some(wait) = &mut self.key_wait {
+            match wait.pressed {
+                None if pressed => wait.pressed = Some(key),
+                Some(waited) if waited == key && !pressed => self.finish_key_wait(key),
+                _ => ()
+            }
+        }
+    }
+
+    /// Catches up an `Fx0A` with changes made to `keypad` directly
+    fn poll_key_wait(&mut self) {
+        let wait = match &mut self.key_wait {
+            Some(wait) => wait,
+            None => return
+        };
+
+        match wait.pressed {
+            None => wait.pressed = self.keypad.iter().position(|&pressed| pressed),
+            Some(key) if !self.keypad[key] => self.finish_key_wait(key),
+            Some(_) => ()
+        }
+    }
+
+    fn finish_key_wait(&mut self, key: usize) {
+        if let Some(wait) = self.key_wait.take() {
+            self.cpu_registers[wait.register] = key as u8;
+        }
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    /// Makes `RND` produce the same sequence on every run
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Writes a byte to memory, discarding any cached instruction it was part of
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        self.write_memory((address & ADDRESS_MASK) as usize, value);
+    }
+
+    /// Discards every cached instruction and compiled block. Needed after writing to `memory` directly instead of through `write_byte`
+    pub fn invalidate_instruction_cache(&mut self) {
+        for cached in self.instruction_cache.iter_mut() {
+            *cached = None;
+        }
+
+        self.recompiler.clear();
+    }
+
+    /// Decodes the instruction at the program counter, reusing the cached decoding when there is one
+    fn fetch_instruction(&mut self) -> Instruction {
+        let address = (self.program_counter & ADDRESS_MASK) as usize;
+        if let Some(instruction) = self.instruction_cache[address] {
+            self.current_opcode = instruction.encode();
+            return instruction;
+        }
+
+        self.fetch_opcode();
+        let instruction = Instruction::decode(self.current_opcode);
+        self.instruction_cache[address] = Some(instruction);
+
+        instruction
     }
 
     fn fetch_opcode(&mut self) {
-        let first = self.memory[self.program_counter as usize] as u16;
-        let second = self.memory[(self.program_counter + 1) as usize] as u16;
+        let first = self.memory[(self.program_counter & ADDRESS_MASK) as usize] as u16;
+        let second = self.memory[(self.program_counter.wrapping_add(1) & ADDRESS_MASK) as usize] as u16;
         let opcode = (first << 8) | (second & 0xFF);
 
         self.current_opcode = opcode;
     }
 
-    fn execute_opcode(&mut self) {
-        let opcode_parts = (
-            (self.current_opcode & 0xF000) >> 12,
-            (self.current_opcode & 0x0F00) >> 8,
-            (self.current_opcode & 0x00F0) >> 4,
-            (self.current_opcode & 0x000F) 
-        );
-
-        match opcode_parts {
-            (0x0, 0x0, 0xE, 0x0) => self.op_00E0(),
-            (0x0, 0x0, 0xE, 0xE) => {
+    fn execute_instruction(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Cls => self.op_00E0(),
+            Instruction::Ret => {
                 self.op_00EE();
                 return;
             },
-            (0x1, _, _, _) => {
-                self.op_1nnn();
+            Instruction::Jp(address) => {
+                self.op_1nnn(address);
                 return;
             },
-            (0x2, _, _, _) => {
-                self.op_2nnn();
+            Instruction::Call(address) => {
+                self.op_2nnn(address);
                 return;
             },
-            (0x3, _, _, _) => self.op_3xkk(),
-            (0x4, _, _, _) => self.op_4xkk(),
-            (0x5, _, _, _) => self.op_5xy0(),
-            (0x6, _, _, _) => self.op_6xkk(),
-            (0x7, _, _, _) => self.op_7xkk(),
-            (0x8, _, _, 0x0) => self.op_8xy0(),
-            (0x8, _, _, 0x1) => self.op_8xy1(),
-            (0x8, _, _, 0x2) => self.op_8xy2(),
-            (0x8, _, _, 0x3) => self.op_8xy3(),
-            (0x8, _, _, 0x4) => self.op_8xy4(),
-            (0x8, _, _, 0x5) => self.op_8xy5(),
-            (0x8, _, _, 0x6) => self.op_8xy6(),
-            (0x8, _, _, 0x7) => self.op_8xy7(),
-            (0x8, _, _, 0xE) => self.op_8xyE(),
-            (0x9, _, _, _) => self.op_9xy0(),
-            (0xA, _, _, _) => self.op_Annn(),
-            (0xB, _, _, _) => {
-                self.op_Bnnn();
+            Instruction::SeByte { x, kk } => self.op_3xkk(x as usize, kk),
+            Instruction::SneByte { x, kk } => self.op_4xkk(x as usize, kk),
+            Instruction::SeRegister { x, y } => self.op_5xy0(x as usize, y as usize),
+            Instruction::LdByte { x, kk } => self.op_6xkk(x as usize, kk),
+            Instruction::AddByte { x, kk } => self.op_7xkk(x as usize, kk),
+            Instruction::LdRegister { x, y } => self.op_8xy0(x as usize, y as usize),
+            Instruction::Or { x, y } => self.op_8xy1(x as usize, y as usize),
+            Instruction::And { x, y } => self.op_8xy2(x as usize, y as usize),
+            Instruction::Xor { x, y } => self.op_8xy3(x as usize, y as usize),
+            Instruction::AddRegister { x, y } => self.op_8xy4(x as usize, y as usize),
+            Instruction::Sub { x, y } => self.op_8xy5(x as usize, y as usize),
+            Instruction::Shr { x, y } => self.op_8xy6(x as usize, y as usize),
+            Instruction::Subn { x, y } => self.op_8xy7(x as usize, y as usize),
+            Instruction::Shl { x, y } => self.op_8xyE(x as usize, y as usize),
+            Instruction::SneRegister { x, y } => self.op_9xy0(x as usize, y as usize),
+            Instruction::LdIndex(address) => self.op_Annn(address),
+            Instruction::JpOffset(address) => {
+                self.op_Bnnn(address);
                 return;
             },
-            (0xC, _, _, _) => self.op_Cxkk(),
-            (0xD, _, _, _) => self.op_Dxyn(),
-            (0xE, _, 0x9, 0xE) => self.op_Ex9E(),
-            (0xE, _, 0xA, 0x1) => self.op_ExA1(),
-            (0xF, _, 0x0, 0x7) => self.op_Fx07(),
-            (0xF, _, 0x0, 0xA) => self.op_Fx0A(),
-            (0xF, _, 0x1, 0x5) => self.op_Fx15(),
-            (0xF, _, 0x1, 0x8) => self.op_Fx18(),
-            (0xF, _, 0x1, 0xE) => self.op_Fx1E(),
-            (0xF, _, 0x2, 0x9) => self.op_Fx29(),
-            (0xF, _, 0x3, 0x3) => self.op_Fx33(),
-            (0xF, _, 0x5, 0x5) => self.op_Fx55(),
-            (0xF, _, 0x6, 0x5) => self.op_Fx65(),
-            _ => ()
+            Instruction::Rnd { x, kk } => self.op_Cxkk(x as usize, kk),
+            Instruction::Drw { x, y, n } => self.op_Dxyn(x as usize, y as usize, n),
+            Instruction::Skp { x } => self.op_Ex9E(x as usize),
+            Instruction::Sknp { x } => self.op_ExA1(x as usize),
+            Instruction::LdFromDelay { x } => self.op_Fx07(x as usize),
+            Instruction::LdKey { x } => self.op_Fx0A(x as usize),
+            Instruction::LdDelay { x } => self.op_Fx15(x as usize),
+            Instruction::LdSound { x } => self.op_Fx18(x as usize),
+            Instruction::AddIndex { x } => self.op_Fx1E(x as usize),
+            Instruction::LdFont { x } => self.op_Fx29(x as usize),
+            Instruction::LdBcd { x } => self.op_Fx33(x as usize),
+            Instruction::LdStore { x } => self.op_Fx55(x as usize),
+            Instruction::LdLoad { x } => self.op_Fx65(x as usize),
+            Instruction::Unknown(_) => ()
         };
 
-        self.program_counter += 2;
+        self.increment_program_counter();
     }
 
-    fn update_timers(&mut self) {
+    fn increment_program_counter(&mut self) {
+        self.program_counter = self.program_counter.wrapping_add(2) & ADDRESS_MASK;
+    }
+
+    /// Stores a byte and drops the cached instructions that started at or just before it
+    fn write_memory(&mut self, address: usize, value: u8) {
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record_write(address);
+        }
+
+        self.memory[address] = value;
+        self.instruction_cache[address] = None;
+        self.instruction_cache[address.wrapping_sub(1) & ADDRESS_MASK as usize] = None;
+        self.recompiler.invalidate(address as u16);
+    }
+
+    /// Address in memory that is `offset` bytes past I, wrapped to the 4 KiB address space
+    fn indexed_address(&self, offset: u16) -> usize {
+        (self.index_register.wrapping_add(offset) & ADDRESS_MASK) as usize
+    }
+
+    /// Reads a byte of data, as opposed to an instruction
+    fn read_memory(&mut self, address: usize) -> u8 {
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record_read(address);
+        }
+
+        self.memory[address]
+    }
+
+    /// Counts both timers down by one, which should happen at 60Hz. This is also the vertical blank that
+    /// ends a draw's wait for the next frame
+    pub fn update_timers(&mut self) {
+        self.waiting_for_vblank = false;
+
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
         }
 
         if self.sound_timer > 0 {
-            println!("Beep!");
             self.sound_timer -= 1;
         }
     }
 
     /// CLS - Clears the display
     fn op_00E0(&mut self) {
-        for i in 0..self.graphics.len() {
-            for j in 0..self.graphics[i].len() {
-                self.graphics[i][j] = false;
-            }
-        }
-        
+        self.graphics.clear();
         self.can_draw = true;
     }
 
-    /// RET - Sets program counter to top of stack and then decrements pointer 
+    /// RET - Sets program counter to top of stack and then decrements pointer.
+    /// Returning with an empty stack is ignored
     fn op_00EE(&mut self) {
+        if self.stack_pointer == 0 {
+            self.increment_program_counter();
+            return;
+        }
+
         self.stack_pointer -= 1;
         self.program_counter = self.execution_stack[self.stack_pointer];
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record_return();
+        }
     }
 
     /// JP addr - Sets program counter to nnn
-    fn op_1nnn(&mut self) {
-        let address = self.current_opcode & 0x0FFF;
+    fn op_1nnn(&mut self, address: u16) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record_jump(self.program_counter & ADDRESS_MASK, address);
+        }
+
         self.program_counter = address;
     }
 
     /// CALL addr - Increments the pointer and sets execution stack to program counter.
-    /// It then sets the program counter to nnn
-    fn op_2nnn(&mut self) {
-        let address = self.current_opcode & 0x0FFF;
+    /// It then sets the program counter to nnn. Calling with a full stack is ignored
+    fn op_2nnn(&mut self, address: u16) {
+        if self.stack_pointer >= STACK_SIZE {
+            self.increment_program_counter();
+            return;
+        }
 
-        self.execution_stack[self.stack_pointer as usize] = self.program_counter + 2;
+        self.execution_stack[self.stack_pointer] = self.program_counter.wrapping_add(2) & ADDRESS_MASK;
         self.stack_pointer += 1;
         self.program_counter = address;
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record_call(address);
+        }
     }
 
     /// SE Vx, byte - if Vx equals kk then increment program counter by 2
-    fn op_3xkk(&mut self) {
-        let x = self.get_x();
-        let kk = self.get_kk();
-
-        if self.cpu_registers[x as usize] != kk {
+    fn op_3xkk(&mut self, x: usize, kk: u8) {
+        if self.cpu_registers[x] != kk {
             return;
         }
 
-        self.program_counter += 2;
+        self.increment_program_counter();
     }
 
     /// SNE Vx, byte - if Vx does not equal kk then increment program counter by 2
-    fn op_4xkk(&mut self) {
-        let x = self.get_x();
-        let kk = self.get_kk();
-
-        if self.cpu_registers[x as usize] == kk {
+    fn op_4xkk(&mut self, x: usize, kk: u8) {
+        if self.cpu_registers[x] == kk {
             return;
         }
 
-        self.program_counter += 2;
+        self.increment_program_counter();
     }
 
     /// SE Vx, Vy - Compare Vx to Vy. If they are equal, then increment counter by 2
-    fn op_5xy0(&mut self) {
-        let x = self.get_x();
-        let y = self.get_y();
-
-        if self.cpu_registers[x as usize] != self.cpu_registers[y as usize] {
+    fn op_5xy0(&mut self, x: usize, y: usize) {
+        if self.cpu_registers[x] != self.cpu_registers[y] {
             return;
         }
 
-        self.program_counter += 2;
+        self.increment_program_counter();
     }
 
     /// LD Vx, byte - Sets Vx to kk 
-    fn op_6xkk(&mut self) {
-        let x = self.get_x();
-        let kk = self.get_kk();
-
-        self.cpu_registers[x as usize] = kk;
+    fn op_6xkk(&mut self, x: usize, kk: u8) {
+        self.cpu_registers[x] = kk;
     }
 
     /// ADD Vx, byte - Adds kk to Vx
-    fn op_7xkk(&mut self) {
-        let x = self.get_x();
-        let kk = self.get_kk() as u16;
-        let sum = (self.cpu_registers[x] as u16) + kk;
+    fn op_7xkk(&mut self, x: usize, kk: u8) {
+        let sum = (self.cpu_registers[x] as u16) + kk as u16;
 
         self.cpu_registers[x] = sum as u8;
     }
 
     /// LD Vx, Vy - Sets Vx to Vy
-    fn op_8xy0(&mut self) {
-        let x = self.get_x();
-        let y = self.get_y();
-
+    fn op_8xy0(&mut self, x: usize, y: usize) {
         self.cpu_registers[x] = self.cpu_registers[y];
     }
 
     /// OR Vx, Vy - Does a bitwise OR on Vx and Vy and stores it in Vx
-    fn op_8xy1(&mut self) {
-        let x = self.get_x();
-        let y = self.get_y();
-
-        self.cpu_registers[x] = self.cpu_registers[x] | self.cpu_registers[y]; 
+    fn op_8xy1(&mut self, x: usize, y: usize) {
+        self.cpu_registers[x] |= self.cpu_registers[y];
+        self.reset_vf_after_logic();
     }
 
     /// AND Vx, Vy - Does a bitwise AND on Vx and Vy and stores it in Vx
-    fn op_8xy2(&mut self) {
-        let x = self.get_x();
-        let y = self.get_y();
-
-        self.cpu_registers[x] = self.cpu_registers[x] & self.cpu_registers[y]; 
+    fn op_8xy2(&mut self, x: usize, y: usize) {
+        self.cpu_registers[x] &= self.cpu_registers[y];
+        self.reset_vf_after_logic();
     }
 
     /// XOR Vx, Vy - Does a bitwise XOR on Vx and Vy and stores it in Vx
-    fn op_8xy3(&mut self) {
-        let x = self.get_x();
-        let y = self.get_y();
+    fn op_8xy3(&mut self, x: usize, y: usize) {
+        self.cpu_registers[x] ^= self.cpu_registers[y];
+        self.reset_vf_after_logic();
+    }
 
-        self.cpu_registers[x] = self.cpu_registers[x] ^ self.cpu_registers[y];
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.cpu_registers[0xF] = 0;
+        }
     }
 
     /// ADD Vx, Vy
-    fn op_8xy4(&mut self) {
-        let x = self.get_x();
-        let y = self.get_y();
-
+    fn op_8xy4(&mut self, x: usize, y: usize) {
         let sum = (self.cpu_registers[x] as u16) + (self.cpu_registers[y] as u16);
-        self.cpu_registers[0xF as usize] = if sum > 0xFF { 1 } else { 0 };
+        self.cpu_registers[0xF] = if sum > 0xFF { 1 } else { 0 };
         self.cpu_registers[x] = sum as u8;
     }
 
     /// SUB Vx, Vy
-    fn op_8xy5(&mut self) {
-        let x = self.get_x();
-        let y = self.get_y();
-
+    fn op_8xy5(&mut self, x: usize, y: usize) {
         if self.cpu_registers[x] > self.cpu_registers[y] {
-            self.cpu_registers[0xF as usize] = 1;
+            self.cpu_registers[0xF] = 1;
         } else {
-            self.cpu_registers[0xF as usize] = 0;
+            self.cpu_registers[0xF] = 0;
         }
 
         self.cpu_registers[x] = self.cpu_registers[x].wrapping_sub(self.cpu_registers[y]);
     }
 
     /// SHR Vx {, Vy}
-    fn op_8xy6(&mut self) {
-        let x = self.get_x();
+    fn op_8xy6(&mut self, x: usize, y: usize) {
+        let value = self.cpu_registers[if self.quirks.shift_uses_vy { y } else { x }];
 
-        self.cpu_registers[0xF as usize] = self.cpu_registers[x] & 0x1;
-        self.cpu_registers[x] = self.cpu_registers[x] >> 1;
+        self.cpu_registers[0xF] = value & 0x1;
+        self.cpu_registers[x] = value >> 1;
     }
 
     /// SUBN Vx, Vy
-    fn op_8xy7(&mut self) {
-        let x = self.get_x();
-        let y = self.get_y();
-
+    fn op_8xy7(&mut self, x: usize, y: usize) {
         if self.cpu_registers[y] > self.cpu_registers[x] {
-            self.cpu_registers[0xF as usize] = 1;
+            self.cpu_registers[0xF] = 1;
         } else {
-            self.cpu_registers[0xF as usize] = 0;
+            self.cpu_registers[0xF] = 0;
         }
 
         self.cpu_registers[x] = self.cpu_registers[y].wrapping_sub(self.cpu_registers[x]);
     }
 
     /// SHL Vx {, Vy}
-    fn op_8xyE(&mut self) {
-        let x = self.get_x();
+    fn op_8xyE(&mut self, x: usize, y: usize) {
+        let value = self.cpu_registers[if self.quirks.shift_uses_vy { y } else { x }];
 
-        self.cpu_registers[0xF as usize] = (self.cpu_registers[x] & 0x80) >> 7;
-        self.cpu_registers[x] = self.cpu_registers[x] << 1;
+        self.cpu_registers[0xF] = (value & 0x80) >> 7;
+        self.cpu_registers[x] = value << 1;
     }
 
     /// SNE Vx, Vy
-    fn op_9xy0(&mut self) {
-        let x = self.get_x();
-        let y = self.get_y();
-
-        if (self.cpu_registers[x] == self.cpu_registers[y]) {
+    fn op_9xy0(&mut self, x: usize, y: usize) {
+        if self.cpu_registers[x] == self.cpu_registers[y] {
             return;
         }
 
-        self.program_counter += 2;
+        self.increment_program_counter();
     }
 
     /// LD I, addr
-    fn op_Annn(&mut self) {
-        let address = self.current_opcode & 0x0FFF;
+    fn op_Annn(&mut self, address: u16) {
         self.index_register = address;
     }
 
     /// JP V0, addr
-    fn op_Bnnn(&mut self) {
-        let address = self.current_opcode & 0x0FFF;
-        self.program_counter = (self.cpu_registers[0] as u16) + address;
+    fn op_Bnnn(&mut self, address: u16) {
+        let register = if self.quirks.jump_uses_vx { ((address & 0x0F00) >> 8) as usize } else { 0 };
+        self.program_counter = ((self.cpu_registers[register] as u16) + address) & ADDRESS_MASK;
     }
 
     /// RND Vx, byte
-    fn op_Cxkk(&mut self) {
-        let x = self.get_x();
-        let kk = self.get_kk();
-        let mut rand = rand::thread_rng();
-        let random = rand.gen_range(0, 255);
+    fn op_Cxkk(&mut self, x: usize, kk: u8) {
+        let random = self.rng.gen_range(0, 255);
 
         self.cpu_registers[x] = kk & random;
     }
 
     /// DRW Vx, Vy, nibble
-    fn op_Dxyn(&mut self) {
-        let x = self.get_x();
-        let y = self.get_y();
-        let n = (self.current_opcode & 0xF) as u8;
-
-        self.cpu_registers[0xF] = 0;
-        for sprite_row in 0..n {
-            let screen_row: usize = (self.cpu_registers[y] + sprite_row) as usize;
-            for sprite_col in 0..8 {
-                let screen_col: usize = (self.cpu_registers[x] + sprite_col) as usize;
-                let sprite_pixel = (self.memory
-                    [(self.index_register + sprite_row as u16) as usize]
-                    & (0x80 >> sprite_col))
-                    >> 7 - sprite_col;
-
-                if screen_row < SCREEN_HEIGHT as usize && screen_col < SCREEN_WIDTH as usize {
-                    if sprite_pixel == 1 {
-                        if self.graphics[screen_row][screen_col] == true {
-                            self.cpu_registers[0xF] = 1;
-                        }
-                        self.graphics[screen_row][screen_col] ^= true;
-                    }
-                }
-            }
+    fn op_Dxyn(&mut self, x: usize, y: usize, n: u8) {
+        // The starting coordinates always wrap around the screen, the rest of the sprite only does with the quirk
+        let origin_row = self.cpu_registers[y] as usize % self.graphics.height();
+        let origin_col = self.cpu_registers[x] as usize % self.graphics.width();
+
+        let mut sprite = [0; 15];
+        for (row, byte) in sprite.iter_mut().enumerate().take(n as usize) {
+            *byte = self.read_memory(self.indexed_address(row as u16));
+        }
+
+        if n > 0 {
+            self.sprites_drawn.insert(Sprite::new(self.index_register & ADDRESS_MASK, n as usize));
         }
 
+        let collision = self.graphics.draw_sprite_on_plane(0, origin_col, origin_row, &sprite[..n as usize], self.quirks.wrap_sprites);
+        self.cpu_registers[0xF] = collision as u8;
+
         self.can_draw = true;
+        self.waiting_for_vblank = self.quirks.display_wait;
     }
 
     /// SKP Vx
-    fn op_Ex9E(&mut self) {
-        let x = self.get_x();
-
-        if !self.keypad[self.cpu_registers[x] as usize] {
+    fn op_Ex9E(&mut self, x: usize) {
+        if !self.keypad[(self.cpu_registers[x] & 0xF) as usize] {
             return;
         }
 
-        self.program_counter += 2;
+        self.increment_program_counter();
     }
 
     /// SKNP Vx
-    fn op_ExA1(&mut self) {
-        let x = self.get_x();
-
-        if self.keypad[self.cpu_registers[x] as usize] {
+    fn op_ExA1(&mut self, x: usize) {
+        if self.keypad[(self.cpu_registers[x] & 0xF) as usize] {
             return;
         }
 
-        self.program_counter += 2;
+        self.increment_program_counter();
     }
 
     /// LD Vx, DT
-    fn op_Fx07(&mut self) {
-        let x = self.get_x();
-
+    fn op_Fx07(&mut self, x: usize) {
         self.cpu_registers[x] = self.delay_timer;
     }
 
-    /// LD Vx, K
-    fn op_Fx0A(&mut self) {
-        let x = self.get_x();
-
-        let mut key_pressed = false;
-        for i in 0..self.keypad.len() {
-            let key = self.keypad[i];
-            if key {
-                key_pressed = true;
-                self.cpu_registers[x] = i as u8;
-                break;
-            }
-        }
-
-        if !key_pressed {
-            self.program_counter -= 4;
-        }
+    /// LD Vx, K - Halts until a key is pressed and released, then stores it in Vx. Like on the COSMAC VIP,
+    /// a key only counts once it's let go, so holding it down doesn't repeat it
+    fn op_Fx0A(&mut self, x: usize) {
+        self.key_wait = Some(KeyWait { register: x, pressed: None });
     }
 
     /// LD DT, Vx
-    fn op_Fx15(&mut self) {
-        let x = self.get_x();
-
+    fn op_Fx15(&mut self, x: usize) {
         self.delay_timer = self.cpu_registers[x];
     }
 
     /// LD ST, Vx
-    fn op_Fx18(&mut self) {
-        let x = self.get_x();
-
+    fn op_Fx18(&mut self, x: usize) {
         self.sound_timer = self.cpu_registers[x];
     }
 
     /// ADD I, Vx
-    fn op_Fx1E(&mut self) {
-        let x = self.get_x();
-
-        self.index_register += self.cpu_registers[x] as u16;
+    fn op_Fx1E(&mut self, x: usize) {
+        self.index_register = self.index_register.wrapping_add(self.cpu_registers[x] as u16);
     }
 
     /// LD F, Vx
-    fn op_Fx29(&mut self) {
-        let x = self.get_x();
+    fn op_Fx29(&mut self, x: usize) {
         let digit = self.cpu_registers[x] as u16;
 
         self.index_register = FONT_START_ADDRESS as u16 + (5 * digit);
     }
 
     /// LD B, Vx
-    fn op_Fx33(&mut self) {
-        let x = self.get_x();
+    fn op_Fx33(&mut self, x: usize) {
         let mut vx = self.cpu_registers[x];
         
-        self.memory[(self.index_register + 2) as usize] = vx % 10;
+        self.write_memory(self.indexed_address(2), vx % 10);
         vx /= 10;
 
-        self.memory[(self.index_register + 1) as usize] = vx % 10;
+        self.write_memory(self.indexed_address(1), vx % 10);
         vx /= 10;
 
-        self.memory[(self.index_register) as usize] = vx % 10;
+        self.write_memory(self.indexed_address(0), vx % 10);
     }
 
     /// LD [I], Vx
-    fn op_Fx55(&mut self) {
-        let x = self.get_x();
+    fn op_Fx55(&mut self, x: usize) {
         for i in 0..=x {
-            self.memory[self.index_register as usize + i] = self.cpu_registers[i];
+            self.write_memory(self.indexed_address(i as u16), self.cpu_registers[i]);
         }
+
+        self.advance_index_after_load_store(x);
     }
 
     /// LD Vx, [I]
-    fn op_Fx65(&mut self) {
-        let x = self.get_x();
+    fn op_Fx65(&mut self, x: usize) {
         for i in 0..=x {
-            self.cpu_registers[i] = self.memory[self.index_register as usize + i];
+            self.cpu_registers[i] = self.read_memory(self.indexed_address(i as u16));
         }
-    }
 
-    fn get_x(&mut self) -> usize {
-        ((self.current_opcode & 0x0F00) >> 8) as usize
+        self.advance_index_after_load_store(x);
     }
 
-    fn get_y(&mut self) -> usize {
-        ((self.current_opcode & 0x00F0) >> 4) as usize
-    }
-
-    fn get_kk(&mut self) -> u8 {
-        (self.current_opcode & 0x00FF) as u8
+    /// Moves I past the registers Fx55 and Fx65 touched, if the quirks say so
+    fn advance_index_after_load_store(&mut self, x: usize) {
+        if self.quirks.load_store_increments_i {
+            let step = if self.quirks.load_store_increments_by_x { x } else { x + 1 };
+            self.index_register = self.index_register.wrapping_add(step as u16);
+        }
     }
 
     fn initialize_fontset(&mut self) {
@@ -513,4 +715,165 @@ impl Cpu {
             self.memory[FONT_START_ADDRESS + index] = b;
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A CPU with the program loaded at the start address
+    fn load(program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.seed_rng(0);
+        cpu.load_rom(program.to_vec()).unwrap();
+        cpu
+    }
+
+    /// LD V3, K followed by ADD V4, 1 and a jump to itself, on each backend
+    fn key_wait_programs() -> Vec<Cpu> {
+        [Backend::Interpreter, Backend::Recompiler]
+            .iter()
+            .map(|&backend| {
+                let mut cpu = load(&[0xF3, 0x0A, 0x74, 0x01, 0x12, 0x04]);
+                cpu.set_backend(backend);
+                cpu
+            })
+            .collect()
+    }
+
+    #[test]
+    fn key_wait_halts_until_a_key_is_pressed_and_released() {
+        for mut cpu in key_wait_programs() {
+            cpu.run_frame(10);
+            assert!(cpu.waiting_for_key(), "{:?}", cpu.backend());
+            assert_eq!(cpu.program_counter, 0x202);
+
+            cpu.set_key(7, true);
+            cpu.run_frame(10);
+            assert!(cpu.waiting_for_key(), "{:?}", cpu.backend());
+            assert_eq!(cpu.cpu_registers[4], 0);
+
+            cpu.set_key(7, false);
+            cpu.run_frame(10);
+            assert!(!cpu.waiting_for_key(), "{:?}", cpu.backend());
+            assert_eq!(cpu.cpu_registers[3], 7);
+            assert_eq!(cpu.cpu_registers[4], 1);
+        }
+    }
+
+    #[test]
+    fn key_held_from_before_the_wait_only_counts_once_released() {
+        for mut cpu in key_wait_programs() {
+            cpu.set_key(0xB, true);
+            for _ in 0..5 {
+                cpu.run_frame(10);
+            }
+            assert!(cpu.waiting_for_key(), "{:?}", cpu.backend());
+
+            // Other keys going down and up don't count while the first one is still held
+            cpu.set_key(2, true);
+            cpu.set_key(2, false);
+            cpu.run_frame(10);
+            assert!(cpu.waiting_for_key(), "{:?}", cpu.backend());
+
+            cpu.set_key(0xB, false);
+            cpu.run_frame(10);
+            assert!(!cpu.waiting_for_key(), "{:?}", cpu.backend());
+            assert_eq!(cpu.cpu_registers[3], 0xB);
+        }
+    }
+
+    #[test]
+    fn key_press_set_directly_on_the_keypad_completes_the_wait() {
+        for mut cpu in key_wait_programs() {
+            cpu.run_frame(10);
+            cpu.keypad[5] = true;
+            cpu.run_frame(10);
+            cpu.keypad[5] = false;
+            cpu.run_frame(10);
+            assert!(!cpu.waiting_for_key(), "{:?}", cpu.backend());
+            assert_eq!(cpu.cpu_registers[3], 5);
+        }
+    }
+
+    #[test]
+    fn timers_count_down_during_a_key_wait() {
+        for mut cpu in key_wait_programs() {
+            cpu.delay_timer = 10;
+            cpu.sound_timer = 3;
+            let sounding: Vec<bool> = (0..5).map(|_| cpu.run_frame(10)).collect();
+            assert!(cpu.waiting_for_key(), "{:?}", cpu.backend());
+            assert_eq!(cpu.delay_timer, 5);
+            assert_eq!(cpu.sound_timer, 0);
+            assert_eq!(sounding, vec![true, true, true, false, false]);
+        }
+    }
+
+    #[test]
+    fn shifts_set_vf_to_the_bit_shifted_out() {
+        let mut cpu = load(&[0x81, 0x06, 0x82, 0x0E]);
+        cpu.cpu_registers[1] = 0b0000_0011;
+        cpu.cpu_registers[2] = 0b1000_0001;
+        cpu.run_instructions(1);
+        assert_eq!((cpu.cpu_registers[1], cpu.cpu_registers[0xF]), (0b0000_0001, 1));
+        cpu.run_instructions(1);
+        assert_eq!((cpu.cpu_registers[2], cpu.cpu_registers[0xF]), (0b0000_0010, 1));
+    }
+
+    #[test]
+    fn shifting_vf_keeps_the_result_over_the_flag() {
+        let mut cpu = load(&[0x8F, 0x06, 0x8F, 0x0E]);
+        cpu.cpu_registers[0xF] = 0b0000_0010;
+        cpu.run_instructions(1);
+        assert_eq!(cpu.cpu_registers[0xF], 0b0000_0001);
+
+        cpu.cpu_registers[0xF] = 0b0100_0000;
+        cpu.run_instructions(1);
+        assert_eq!(cpu.cpu_registers[0xF], 0b1000_0000);
+    }
+
+    #[test]
+    fn load_store_quirks_move_i() {
+        let expected = [(Quirks::default(), 0x300), (Quirks::chip8(), 0x304), (Quirks::chip48(), 0x303)];
+        for &(quirks, index) in &expected {
+            let mut cpu = load(&[0xF3, 0x55]);
+            cpu.quirks = quirks;
+            cpu.index_register = 0x300;
+            cpu.run_instructions(1);
+            assert_eq!(cpu.index_register, index, "{:?}", quirks);
+        }
+    }
+
+    #[test]
+    fn store_over_a_cached_instruction_runs_the_new_one() {
+        // F155 stores V0 and V1 over the LD VA, 0x11 after it
+        let mut cpu = load(&[0xF1, 0x55, 0x6A, 0x11]);
+        cpu.program_counter = 0x202;
+        cpu.run_instructions(1);
+        assert_eq!(cpu.cpu_registers[0xA], 0x11);
+
+        cpu.program_counter = 0x200;
+        cpu.index_register = 0x202;
+        cpu.cpu_registers[0] = 0x6A;
+        cpu.cpu_registers[1] = 0x22;
+        cpu.run_instructions(2);
+        assert_eq!(cpu.cpu_registers[0xA], 0x22);
+    }
+
+    #[test]
+    fn bcd_over_a_cached_instruction_runs_the_new_one() {
+        // F233 writes 1, 2, 3 over the LD VA, 0x11 after it, which makes it 0x0102, which does nothing
+        let mut cpu = load(&[0xF2, 0x33, 0x6A, 0x11]);
+        cpu.program_counter = 0x202;
+        cpu.run_instructions(1);
+        cpu.cpu_registers[0xA] = 0;
+
+        cpu.program_counter = 0x200;
+        cpu.index_register = 0x202;
+        cpu.cpu_registers[2] = 123;
+        cpu.run_instructions(2);
+        assert_eq!(&cpu.memory[0x202..0x205], &[1, 2, 3]);
+        assert_eq!(cpu.cpu_registers[0xA], 0);
+        assert_eq!(cpu.program_counter, 0x204);
+    }
+}
@@ -0,0 +1,432 @@
+use std::fmt;
+use std::sync::Arc;
+use crate::instruction::Instruction;
+use super::{Backend, Cpu, ADDRESS_MASK, MEMORY_SIZE};
+
+/// Longest run of instructions that gets compiled into a single block
+const MAX_BLOCK_LENGTH: usize = 32;
+
+type Operation = Box<dyn Fn(&mut Cpu) + Send + Sync>;
+
+struct CompiledInstruction {
+    opcode: u16,
+    run: Operation
+}
+
+/// A straight-line run of instructions. Only the last one can jump, skip or write to memory.
+/// The program counter is only brought up to date before that last instruction and when the block exits
+struct Block {
+    start: u16,
+    length: u16, // In bytes
+    instructions: Vec<CompiledInstruction>,
+    ends_block: bool // Whether the last instruction updates the program counter itself
+}
+
+impl Block {
+    fn address_of(&self, index: usize) -> u16 {
+        self.start.wrapping_add(index as u16 * 2) & ADDRESS_MASK
+    }
+
+    fn contains(&self, address: u16) -> bool {
+        (address.wrapping_sub(self.start) & ADDRESS_MASK) < self.length
+    }
+
+    fn addresses(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.length).map(move |offset| (self.start.wrapping_add(offset) & ADDRESS_MASK) as usize)
+    }
+}
+
+/// Compiled blocks indexed by their start address
+#[derive(Clone)]
+pub struct Recompiler {
+    blocks: Vec<Option<Arc<Block>>>,
+    coverage: Vec<u16> // How many blocks were compiled from each address, so writes elsewhere stay cheap
+}
+
+impl Recompiler {
+    pub fn new() -> Self {
+        Recompiler {
+            blocks: vec![None; MEMORY_SIZE],
+            coverage: vec![0; MEMORY_SIZE]
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for block in self.blocks.iter_mut() {
+            *block = None;
+        }
+
+        for count in self.coverage.iter_mut() {
+            *count = 0;
+        }
+    }
+
+    /// Drops every block compiled from the byte at `address`. Only blocks starting up to a block's length before it
+    /// can cover it, so only those slots are looked at
+    pub fn invalidate(&mut self, address: u16) {
+        if self.coverage[address as usize] == 0 {
+            return;
+        }
+
+        for distance in 0..MAX_BLOCK_LENGTH as u16 * 2 {
+            let start = (address.wrapping_sub(distance) & ADDRESS_MASK) as usize;
+            let slot = &mut self.blocks[start];
+            if !slot.as_ref().is_some_and(|block| block.contains(address)) {
+                continue;
+            }
+
+            if let Some(block) = slot.take() {
+                for covered in block.addresses() {
+                    self.coverage[covered] -= 1;
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, block: Arc<Block>) {
+        for covered in block.addresses() {
+            self.coverage[covered] += 1;
+        }
+
+        let start = block.start as usize;
+        self.blocks[start] = Some(block);
+    }
+}
+
+/// The first point where the recompiler and the interpreter disagreed
+#[derive(Debug)]
+pub struct Divergence {
+    pub instruction: usize,
+    pub block_address: u16,
+    pub field: &'static str
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} differs after instruction {} in the block at {:#05X}", self.field, self.instruction, self.block_address)
+    }
+}
+
+impl Cpu {
+    /// Runs the block at the program counter, compiling it first if needed, and returns how many instructions ran.
+    /// Stops early once `budget` instructions have run
+    pub(super) fn run_block(&mut self, budget: usize) -> usize {
+        let start = self.program_counter & ADDRESS_MASK;
+        let block = match &self.recompiler.blocks[start as usize] {
+            Some(block) => Arc::clone(block),
+            None => {
+                let block = Arc::new(self.compile_block(start));
+                self.recompiler.insert(Arc::clone(&block));
+                block
+            }
+        };
+
+        let last = block.instructions.len() - 1;
+        let mut executed = 0;
+        for (index, instruction) in block.instructions.iter().take(budget).enumerate() {
+            if index == last && block.ends_block {
+                self.program_counter = block.address_of(index);
+            }
+
+            self.current_opcode = instruction.opcode;
+            (instruction.run)(self);
+            executed += 1;
+
+            if self.halted() {
+                break;
+            }
+        }
+
+        if executed <= last || !block.ends_block {
+            self.program_counter = block.address_of(executed);
+        }
+
+        executed
+    }
+
+    /// Runs copies of this CPU on both backends side by side for a number of instructions, comparing them after every block
+    pub fn verify_recompiler(&self, instructions: usize) -> Result<(), Divergence> {
+        let mut interpreted = self.clone();
+        interpreted.set_backend(Backend::Interpreter);
+        let mut recompiled = self.clone();
+        recompiled.set_backend(Backend::Recompiler);
+
+        let mut instruction = 0;
+        while instruction < instructions {
+            // Both stopped at the same draw, so start the next frame on both
+            if recompiled.waiting_for_vblank {
+                interpreted.update_timers();
+                recompiled.update_timers();
+            }
+
+            // Nothing runs until a key is pressed
+            if recompiled.waiting_for_key() {
+                break;
+            }
+
+            let block_address = recompiled.program_counter;
+            let executed = recompiled.run_block(instructions - instruction);
+            interpreted.run_instructions(executed);
+            instruction += executed;
+
+            if let Some(field) = first_difference(&interpreted, &recompiled) {
+                return Err(Divergence { instruction, block_address, field });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compile_block(&self, start: u16) -> Block {
+        let mut instructions = Vec::new();
+        let mut address = start;
+        let mut ended = false;
+
+        loop {
+            let first = self.memory[address as usize] as u16;
+            let second = self.memory[(address.wrapping_add(1) & ADDRESS_MASK) as usize] as u16;
+            let opcode = (first << 8) | second;
+            let instruction = Instruction::decode(opcode);
+
+            instructions.push(CompiledInstruction { opcode, run: translate(instruction) });
+            address = address.wrapping_add(2) & ADDRESS_MASK;
+
+            if ends_block(instruction) {
+                ended = true;
+                break;
+            }
+
+            if instructions.len() == MAX_BLOCK_LENGTH || address == 0 {
+                break;
+            }
+        }
+
+        Block {
+            start,
+            length: instructions.len() as u16 * 2,
+            instructions,
+            ends_block: ended
+        }
+    }
+}
+
+/// Anything that might not continue with the next instruction, or that could overwrite the code after it
+fn ends_block(instruction: Instruction) -> bool {
+    match instruction {
+        Instruction::Ret
+        | Instruction::Jp(_)
+        | Instruction::Call(_)
+        | Instruction::JpOffset(_)
+        | Instruction::SeByte { .. }
+        | Instruction::SneByte { .. }
+        | Instruction::SeRegister { .. }
+        | Instruction::SneRegister { .. }
+        | Instruction::Skp { .. }
+        | Instruction::Sknp { .. }
+        | Instruction::LdKey { .. } => true,
+        _ => instruction.writes_memory()
+    }
+}
+
+/// Builds a closure that runs the instruction with its operands already extracted. Only the instructions
+/// that end a block touch the program counter
+fn translate(instruction: Instruction) -> Operation {
+    match instruction {
+        Instruction::Cls => Box::new(|cpu| cpu.op_00E0()),
+        Instruction::Ret => Box::new(|cpu| cpu.op_00EE()),
+        Instruction::Jp(address) => Box::new(move |cpu| cpu.op_1nnn(address)),
+        Instruction::Call(address) => Box::new(move |cpu| cpu.op_2nnn(address)),
+        Instruction::SeByte { x, kk } => then_advance(move |cpu| cpu.op_3xkk(x as usize, kk)),
+        Instruction::SneByte { x, kk } => then_advance(move |cpu| cpu.op_4xkk(x as usize, kk)),
+        Instruction::SeRegister { x, y } => then_advance(move |cpu| cpu.op_5xy0(x as usize, y as usize)),
+        Instruction::LdByte { x, kk } => Box::new(move |cpu| cpu.op_6xkk(x as usize, kk)),
+        Instruction::AddByte { x, kk } => Box::new(move |cpu| cpu.op_7xkk(x as usize, kk)),
+        Instruction::LdRegister { x, y } => Box::new(move |cpu| cpu.op_8xy0(x as usize, y as usize)),
+        Instruction::Or { x, y } => Box::new(move |cpu| cpu.op_8xy1(x as usize, y as usize)),
+        Instruction::And { x, y } => Box::new(move |cpu| cpu.op_8xy2(x as usize, y as usize)),
+        Instruction::Xor { x, y } => Box::new(move |cpu| cpu.op_8xy3(x as usize, y as usize)),
+        Instruction::AddRegister { x, y } => Box::new(move |cpu| cpu.op_8xy4(x as usize, y as usize)),
+        Instruction::Sub { x, y } => Box::new(move |cpu| cpu.op_8xy5(x as usize, y as usize)),
+        Instruction::Shr { x, y } => Box::new(move |cpu| cpu.op_8xy6(x as usize, y as usize)),
+        Instruction::Subn { x, y } => Box::new(move |cpu| cpu.op_8xy7(x as usize, y as usize)),
+        Instruction::Shl { x, y } => Box::new(move |cpu| cpu.op_8xyE(x as usize, y as usize)),
+        Instruction::SneRegister { x, y } => then_advance(move |cpu| cpu.op_9xy0(x as usize, y as usize)),
+        Instruction::LdIndex(address) => Box::new(move |cpu| cpu.op_Annn(address)),
+        Instruction::JpOffset(address) => Box::new(move |cpu| cpu.op_Bnnn(address)),
+        Instruction::Rnd { x, kk } => Box::new(move |cpu| cpu.op_Cxkk(x as usize, kk)),
+        Instruction::Drw { x, y, n } => Box::new(move |cpu| cpu.op_Dxyn(x as usize, y as usize, n)),
+        Instruction::Skp { x } => then_advance(move |cpu| cpu.op_Ex9E(x as usize)),
+        Instruction::Sknp { x } => then_advance(move |cpu| cpu.op_ExA1(x as usize)),
+        Instruction::LdFromDelay { x } => Box::new(move |cpu| cpu.op_Fx07(x as usize)),
+        Instruction::LdKey { x } => then_advance(move |cpu| cpu.op_Fx0A(x as usize)),
+        Instruction::LdDelay { x } => Box::new(move |cpu| cpu.op_Fx15(x as usize)),
+        Instruction::LdSound { x } => Box::new(move |cpu| cpu.op_Fx18(x as usize)),
+        Instruction::AddIndex { x } => Box::new(move |cpu| cpu.op_Fx1E(x as usize)),
+        Instruction::LdFont { x } => Box::new(move |cpu| cpu.op_Fx29(x as usize)),
+        Instruction::LdBcd { x } => then_advance(move |cpu| cpu.op_Fx33(x as usize)),
+        Instruction::LdStore { x } => then_advance(move |cpu| cpu.op_Fx55(x as usize)),
+        Instruction::LdLoad { x } => Box::new(move |cpu| cpu.op_Fx65(x as usize)),
+        Instruction::Unknown(_) => Box::new(|_| ())
+    }
+}
+
+fn then_advance(operation: impl Fn(&mut Cpu) + Send + Sync + 'static) -> Operation {
+    Box::new(move |cpu| {
+        operation(cpu);
+        cpu.increment_program_counter();
+    })
+}
+
+fn first_difference(expected: &Cpu, actual: &Cpu) -> Option<&'static str> {
+    let fields = [
+        ("current_opcode", expected.current_opcode == actual.current_opcode),
+        ("program_counter", expected.program_counter == actual.program_counter),
+        ("cpu_registers", expected.cpu_registers == actual.cpu_registers),
+        ("index_register", expected.index_register == actual.index_register),
+        ("stack_pointer", expected.stack_pointer == actual.stack_pointer),
+        ("execution_stack", expected.execution_stack == actual.execution_stack),
+        ("delay_timer", expected.delay_timer == actual.delay_timer),
+        ("sound_timer", expected.sound_timer == actual.sound_timer),
+        ("memory", expected.memory[..] == actual.memory[..]),
+        ("graphics", expected.graphics == actual.graphics),
+        ("can_draw", expected.can_draw == actual.can_draw),
+        ("waiting_for_vblank", expected.waiting_for_vblank == actual.waiting_for_vblank),
+        ("key_wait", expected.key_wait == actual.key_wait),
+        ("sprites_drawn", expected.sprites_drawn == actual.sprites_drawn)
+    ];
+
+    fields.iter().find(|(_, same)| !same).map(|(field, _)| *field)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use crate::cpu::{Backend, Cpu};
+    use crate::quirks::{Quirks, PROFILES};
+
+    /// A CPU with the program loaded at the start address
+    fn load(program: &[u8], quirks: Quirks) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.quirks = quirks;
+        cpu.seed_rng(0);
+        cpu.load_rom(program.to_vec()).unwrap();
+        cpu
+    }
+
+    /// Checks the backends agree from a number of points along the interpreter's run, pressing and
+    /// releasing a key whenever the program waits for one
+    fn verify_run(name: &str, program: &[u8], quirks: Quirks) {
+        let mut cpu = load(program, quirks);
+        for frame in 0..600 {
+            if frame % 30 == 0 {
+                if let Err(divergence) = cpu.verify_recompiler(500) {
+                    panic!("{} with {:?}: {}", name, quirks, divergence);
+                }
+            }
+
+            if cpu.waiting_for_key() {
+                cpu.set_key(frame % 16, true);
+                cpu.set_key(frame % 16, false);
+            }
+            cpu.run_frame(10);
+        }
+    }
+
+    #[test]
+    fn backends_agree_on_the_test_roms() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/test_roms");
+        for entry in fs::read_dir(directory).expect("Unable to list the test ROMs") {
+            let path = entry.expect("Unable to list the test ROMs").path();
+            let rom = fs::read(&path).expect("Unable to read a test ROM");
+            for profile in PROFILES.iter() {
+                let quirks = Quirks::from_profile(profile).expect("Profiles are valid");
+                verify_run(&path.display().to_string(), &rom, quirks);
+            }
+        }
+    }
+
+    #[test]
+    fn backends_agree_on_display_wait_and_key_waits() {
+        let program = [
+            0x60, 0x05, // LD V0, 5
+            0xF0, 0x29, // LD F, V0
+            0xD1, 0x25, // DRW V1, V2, 5
+            0x71, 0x05, // ADD V1, 5
+            0xF3, 0x0A, // LD V3, K
+            0xD1, 0x25, // DRW V1, V2, 5
+            0xD3, 0x35, // DRW V3, V3, 5
+            0x12, 0x04  // JP 0x204
+        ];
+        for &display_wait in &[false, true] {
+            verify_run("the key wait program", &program, Quirks { display_wait, ..Quirks::default() });
+        }
+    }
+
+    /// Runs a program that overwrites the ADD VA, 1 at the start of its own block on the first pass through.
+    /// The second pass has to run whatever was written there instead
+    fn run_self_modifying(program: &[u8], backend: Backend) -> Cpu {
+        let mut cpu = load(program, Quirks::default());
+        if let Err(divergence) = cpu.verify_recompiler(40) {
+            panic!("{}", divergence);
+        }
+
+        cpu.set_backend(backend);
+        cpu.run_instructions(40);
+        cpu
+    }
+
+    #[test]
+    fn store_over_the_running_block_is_recompiled() {
+        let program = [
+            0x7A, 0x01, // ADD VA, 1, which becomes ADD VA, 5
+            0xA2, 0x00, // LD I, 0x200
+            0x60, 0x7A, // LD V0, 0x7A
+            0x61, 0x05, // LD V1, 0x05
+            0xF1, 0x55, // LD [I], V1
+            0x7B, 0x01, // ADD VB, 1
+            0x3B, 0x02, // SE VB, 2
+            0x12, 0x00, // JP 0x200
+            0x12, 0x10  // JP 0x210
+        ];
+        for &backend in &[Backend::Interpreter, Backend::Recompiler] {
+            assert_eq!(run_self_modifying(&program, backend).cpu_registers[0xA], 6, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn bcd_over_the_running_block_is_recompiled() {
+        let program = [
+            0x7A, 0x01, // ADD VA, 1, which becomes 0x0102 and does nothing
+            0x00, 0x00, // Becomes 0x0300 and does nothing
+            0xA2, 0x00, // LD I, 0x200
+            0x62, 0x7B, // LD V2, 123
+            0xF2, 0x33, // LD B, V2
+            0x7B, 0x01, // ADD VB, 1
+            0x3B, 0x02, // SE VB, 2
+            0x12, 0x00, // JP 0x200
+            0x12, 0x10  // JP 0x210
+        ];
+        for &backend in &[Backend::Interpreter, Backend::Recompiler] {
+            assert_eq!(run_self_modifying(&program, backend).cpu_registers[0xA], 1, "{:?}", backend);
+        }
+    }
+    #[test]
+    fn store_over_the_end_of_a_full_length_block_is_recompiled() {
+        // 32 ADD VA, 1 make a block as long as they get. Only the last byte of the block is written, making the
+        // last one ADD VA, 5
+        let mut program = [0x7A, 0x01].repeat(super::MAX_BLOCK_LENGTH);
+        program.extend_from_slice(&[
+            0x60, 0x05, // LD V0, 0x05
+            0xA2, 0x3F, // LD I, 0x23F
+            0xF0, 0x55, // LD [I], V0
+            0x12, 0x00  // JP 0x200
+        ]);
+        for &backend in &[Backend::Interpreter, Backend::Recompiler] {
+            let mut cpu = load(&program, Quirks::default());
+            cpu.set_backend(backend);
+            cpu.run_instructions(2 * super::MAX_BLOCK_LENGTH + 4);
+            assert_eq!(cpu.cpu_registers[0xA], 2 * super::MAX_BLOCK_LENGTH as u8 + 4, "{:?}", backend);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use crate::framebuffer::{Framebuffer, MAX_HEIGHT, MAX_PLANES, MAX_WIDTH};
+use crate::quirks::Quirks;
+use super::{Cpu, KeyWait, MEMORY_SIZE, SCREEN_HEIGHT, SCREEN_WIDTH, STACK_SIZE};
+
+/// Bumped whenever `SavedState` changes so older states are turned away rather than misread
+const STATE_VERSION: u32 = 1;
+
+/// The display sizes a machine can be in, low resolution and high
+const RESOLUTIONS: [(usize, usize); 2] = [(SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize), (MAX_WIDTH, MAX_HEIGHT)];
+
+/// Everything a program can see of the machine, as it's saved. Debugging aids like breakpoints and coverage
+/// aren't part of it, and neither is the random number generator
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    version: u32,
+    memory: Vec<u8>,
+    registers: Vec<u8>,
+    index_register: u16,
+    program_counter: u16,
+    stack: Vec<u16>,
+    stack_pointer: usize,
+    delay_timer: u8,
+    sound_timer: u8,
+    keypad: Vec<bool>,
+    quirks: Quirks,
+    screen_width: usize,
+    screen_height: usize,
+    /// Each plane's rows, leftmost pixel in the most significant bit
+    planes: Vec<Vec<u128>>,
+    waiting_for_vblank: bool,
+    /// The register an `Fx0A` is waiting to fill, and the key that went down if one has
+    key_wait: Option<(usize, Option<usize>)>
+}
+
+impl Cpu {
+    /// The machine's state as JSON, to be restored later with `load_state`
+    pub fn save_state(&self) -> Vec<u8> {
+        let graphics = &self.graphics;
+        let state = SavedState {
+            version: STATE_VERSION,
+            memory: self.memory.to_vec(),
+            registers: self.cpu_registers.to_vec(),
+            index_register: self.index_register,
+            program_counter: self.program_counter,
+            stack: self.execution_stack.to_vec(),
+            stack_pointer: self.stack_pointer,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+            keypad: self.keypad.to_vec(),
+            quirks: self.quirks,
+            screen_width: graphics.width(),
+            screen_height: graphics.height(),
+            planes: (0..graphics.plane_count())
+                .map(|plane| (0..graphics.height()).map(|y| graphics.row(plane, y)).collect())
+                .collect(),
+            waiting_for_vblank: self.waiting_for_vblank,
+            key_wait: self.key_wait.map(|wait| (wait.register, wait.pressed))
+        };
+
+        serde_json::to_vec(&state).expect("the state only holds plain data")
+    }
+
+    /// Restores a state from `save_state`, leaving the CPU as it was if the state isn't valid
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let state: SavedState = serde_json::from_slice(bytes).map_err(|error| format!("not a saved state: {}", error))?;
+        if state.version != STATE_VERSION {
+            return Err(format!("the state is version {}, but only version {} can be loaded", state.version, STATE_VERSION));
+        }
+
+        let valid = state.memory.len() == MEMORY_SIZE
+            && state.registers.len() == 16
+            && state.stack.len() == STACK_SIZE
+            && state.stack_pointer <= STACK_SIZE
+            && state.keypad.len() == 16
+            && RESOLUTIONS.contains(&(state.screen_width, state.screen_height))
+            && (1..=MAX_PLANES).contains(&state.planes.len())
+            && state.planes.iter().all(|rows| rows.len() == state.screen_height)
+            && state.key_wait.is_none_or(|(register, pressed)| register < 16 && pressed.is_none_or(|key| key < 16));
+        if !valid {
+            return Err(String::from("the saved state is damaged"));
+        }
+
+        self.memory.copy_from_slice(&state.memory);
+        self.cpu_registers.copy_from_slice(&state.registers);
+        self.index_register = state.index_register;
+        self.program_counter = state.program_counter;
+        self.execution_stack.copy_from_slice(&state.stack);
+        self.stack_pointer = state.stack_pointer;
+        self.delay_timer = state.delay_timer;
+        self.sound_timer = state.sound_timer;
+        self.keypad.copy_from_slice(&state.keypad);
+        self.quirks = state.quirks;
+
+        let mut graphics = Framebuffer::with_planes(state.screen_width, state.screen_height, state.planes.len());
+        for (plane, rows) in state.planes.iter().enumerate() {
+            for (y, &bits) in rows.iter().enumerate() {
+                graphics.set_row(plane, y, bits);
+            }
+        }
+        self.graphics = graphics;
+
+        self.waiting_for_vblank = state.waiting_for_vblank;
+        self.key_wait = state.key_wait.map(|(register, pressed)| KeyWait { register, pressed });
+        self.breakpoint_hit = None;
+        self.invalidate_instruction_cache();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A saved state with one field swapped for another value
+    fn corrupt(cpu: &Cpu, field: &str, value: serde_json::Value) -> Vec<u8> {
+        let mut state: serde_json::Value = serde_json::from_slice(&cpu.save_state()).unwrap();
+        state[field] = value;
+        serde_json::to_vec(&state).unwrap()
+    }
+
+    #[test]
+    fn damaged_states_leave_the_machine_alone() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(vec![0x60, 0x05, 0xA2, 0x00, 0xD0, 0x05, 0x12, 0x04]).unwrap();
+        cpu.run_instructions(3);
+        assert!(cpu.graphics.pixel(6, 5));
+        let before = cpu.save_state();
+
+        let rows = |count: usize| serde_json::json!(vec![0u64; count]);
+        let damaged = [
+            corrupt(&cpu, "screen_width", serde_json::json!(0)),
+            corrupt(&cpu, "screen_height", serde_json::json!(0)),
+            corrupt(&cpu, "screen_width", serde_json::json!(MAX_WIDTH + 1)),
+            corrupt(&cpu, "screen_height", serde_json::json!(64)),
+            corrupt(&cpu, "planes", serde_json::json!(Vec::<Vec<u64>>::new())),
+            corrupt(&cpu, "planes", serde_json::json!(vec![rows(32); MAX_PLANES + 1])),
+            corrupt(&cpu, "planes", serde_json::json!([rows(31)])),
+            corrupt(&cpu, "key_wait", serde_json::json!([16, null])),
+            corrupt(&cpu, "version", serde_json::json!(STATE_VERSION + 1)),
+            b"{}".to_vec()
+        ];
+        for state in damaged.iter() {
+            assert!(cpu.load_state(state).is_err(), "{}", String::from_utf8_lossy(state));
+            assert_eq!(cpu.save_state(), before);
+        }
+
+        cpu.run_instructions(2);
+        assert!(!cpu.graphics.pixel(6, 5));
+        assert_eq!(cpu.cpu_registers[0xF], 1);
+    }
+
+    #[test]
+    fn states_in_either_resolution_load() {
+        let mut cpu = Cpu::new();
+        let high = corrupt(&cpu, "screen_width", serde_json::json!(MAX_WIDTH));
+        let mut state: serde_json::Value = serde_json::from_slice(&high).unwrap();
+        state["screen_height"] = serde_json::json!(MAX_HEIGHT);
+        state["planes"] = serde_json::json!(vec![vec![0u64; MAX_HEIGHT]; MAX_PLANES]);
+        cpu.load_state(&serde_json::to_vec(&state).unwrap()).unwrap();
+        assert_eq!((cpu.graphics.width(), cpu.graphics.height(), cpu.graphics.plane_count()), (MAX_WIDTH, MAX_HEIGHT, MAX_PLANES));
+
+        let saved = cpu.save_state();
+        let mut restored = Cpu::new();
+        restored.load_state(&saved).unwrap();
+        assert_eq!(restored.save_state(), saved);
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+use crate::config::{QuirkSetting, Settings};
+use crate::cpu::FRAMES_PER_SECOND;
+use crate::palette::{Palette, Rgb};
+use crate::quirks::Quirks;
+
+const PROGRAMS: &str = include_str!("../database/programs.json");
+const HASHES: &str = include_str!("../database/sha1-hashes.json");
+const PLATFORMS: &str = include_str!("../database/platforms.json");
+
+/// Host keys that the database's key hints are mapped to, named the way keymap files name them
+const KEY_HINTS: [(&str, &str); 12] = [
+    ("up", "Up"), ("down", "Down"), ("left", "Left"), ("right", "Right"),
+    ("a", "Space"), ("b", "Return"),
+    ("player2Up", "Numpad8"), ("player2Down", "Numpad2"),
+    ("player2Left", "Numpad4"), ("player2Right", "Numpad6"),
+    ("player2A", "Numpad0"), ("player2B", "Numpad5")
+];
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    release: Option<String>,
+    #[serde(default)]
+    authors: Vec<String>,
+    roms: HashMap<String, RomEntry>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RomEntry {
+    #[serde(default)]
+    platforms: Vec<String>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, PlatformQuirks>,
+    #[serde(default)]
+    tickrate: Option<u32>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+    #[serde(default)]
+    colors: Option<Colors>
+}
+
+#[derive(Deserialize)]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<String>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Platform {
+    id: String,
+    name: String,
+    #[serde(default)]
+    default_tickrate: Option<u32>,
+    quirks: PlatformQuirks
+}
+
+/// Quirks as the database names them. `quirkyPlatforms` entries only list the ones that differ from the platform
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct PlatformQuirks {
+    shift: Option<bool>,
+    memory_increment_by_x: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    logic: Option<bool>,
+    vblank: Option<bool>
+}
+
+impl PlatformQuirks {
+    fn or(self, fallback: PlatformQuirks) -> Self {
+        PlatformQuirks {
+            shift: self.shift.or(fallback.shift),
+            memory_increment_by_x: self.memory_increment_by_x.or(fallback.memory_increment_by_x),
+            memory_leave_i_unchanged: self.memory_leave_i_unchanged.or(fallback.memory_leave_i_unchanged),
+            wrap: self.wrap.or(fallback.wrap),
+            jump: self.jump.or(fallback.jump),
+            logic: self.logic.or(fallback.logic),
+            vblank: self.vblank.or(fallback.vblank)
+        }
+    }
+
+    fn to_quirks(self) -> Quirks {
+        Quirks {
+            shift_uses_vy: !self.shift.unwrap_or(false),
+            load_store_increments_i: !self.memory_leave_i_unchanged.unwrap_or(false),
+            load_store_increments_by_x: self.memory_increment_by_x.unwrap_or(false),
+            jump_uses_vx: self.jump.unwrap_or(false),
+            logic_resets_vf: self.logic.unwrap_or(false),
+            wrap_sprites: self.wrap.unwrap_or(false),
+            display_wait: self.vblank.unwrap_or(false)
+        }
+    }
+}
+
+/// What the database knows about a ROM
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    pub description: Option<String>,
+    pub release: Option<String>,
+    pub authors: Vec<String>,
+    /// Name of the platform the ROM was written for, if the database has one
+    pub platform: Option<String>,
+    pub quirks: Option<Quirks>,
+    /// Instructions per frame
+    pub tickrate: Option<u32>,
+    pub palette: Option<Palette>,
+    /// Names of host keys to add to the keymap for this ROM
+    pub keys: Vec<(&'static str, usize)>
+}
+
+impl RomInfo {
+    /// The database's recommendations as settings that can sit between the user's per-ROM and global settings
+    pub fn settings(&self) -> Settings {
+        Settings {
+            clock: self.tickrate.map(|tickrate| tickrate * FRAMES_PER_SECOND),
+            quirks: self.quirks.map(QuirkSetting::from_quirks),
+            palette: self.palette.as_ref().map(Palette::to_string),
+            ..Settings::default()
+        }
+    }
+}
+
+/// ROM metadata in the format of the community chip-8-database: `programs.json` lists programs and their ROMs,
+/// `sha1-hashes.json` maps the SHA-1 of a ROM to its program and `platforms.json` describes each platform's quirks
+pub struct Database {
+    programs: Vec<Program>,
+    hashes: HashMap<String, usize>,
+    platforms: Vec<Platform>
+}
+
+impl Database {
+    /// The database that ships with the emulator
+    pub fn bundled() -> Self {
+        Database::parse(PROGRAMS, HASHES, PLATFORMS).expect("the bundled ROM database is valid")
+    }
+
+    pub fn parse(programs: &str, hashes: &str, platforms: &str) -> Result<Self, String> {
+        Ok(Database {
+            programs: serde_json::from_str(programs).map_err(|error| format!("programs: {}", error))?,
+            hashes: serde_json::from_str(hashes).map_err(|error| format!("hashes: {}", error))?,
+            platforms: serde_json::from_str(platforms).map_err(|error| format!("platforms: {}", error))?
+        })
+    }
+
+    /// Looks a ROM up by the lowercase hex SHA-1 of its bytes
+    pub fn lookup(&self, rom_hash: &str) -> Option<RomInfo> {
+        let program = self.programs.get(*self.hashes.get(rom_hash)?)?;
+        let rom = program.roms.get(rom_hash)?;
+
+        // The first platform listed is the one the ROM plays best on
+        let platform = rom.platforms.first().and_then(|id| self.platforms.iter().find(|platform| &platform.id == id));
+        let quirks = platform.map(|platform| {
+            let overrides = rom.quirky_platforms.get(&platform.id).cloned().unwrap_or_default();
+            overrides.or(platform.quirks).to_quirks()
+        });
+
+        let palette = rom.colors.as_ref().and_then(|colors| {
+            let colors = colors.pixels.iter().map(|color| Rgb::parse(color)).collect::<Result<Vec<Rgb>, String>>().ok()?;
+            Palette::new(colors).ok()
+        });
+
+        let keys = KEY_HINTS
+            .iter()
+            .filter_map(|&(hint, host_key)| rom.keys.get(hint).filter(|&&key| key < 16).map(|&key| (host_key, key as usize)))
+            .collect();
+
+        Some(RomInfo {
+            title: program.title.clone(),
+            description: program.description.clone(),
+            release: program.release.clone(),
+            authors: program.authors.clone(),
+            platform: platform.map(|platform| platform.name.clone()),
+            quirks,
+            tickrate: rom.tickrate.or_else(|| platform.and_then(|platform| platform.default_tickrate)),
+            palette,
+            keys
+        })
+    }
+}
+
+/// Describes key hints like `Up -> 5, Space -> 6`
+pub fn describe_keys(keys: &[(&str, usize)]) -> String {
+    keys.iter().map(|&(host_key, key)| format!("{} -> {:X}", host_key, key)).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use crate::config::rom_hash;
+    use crate::cpu::Cpu;
+    use crate::cpu::DEFAULT_INSTRUCTIONS_PER_FRAME;
+    use crate::quirks::Quirks;
+    use super::Database;
+
+    /// What the test ROMs show once every check has passed
+    const PASS_SCREENS: [(&str, &str); 2] = [("BC_test.ch8", BC_TEST_PASS), ("test_opcode.ch8", TEST_OPCODE_PASS)];
+
+    // "BON"
+    const BC_TEST_PASS: &str = "\
+        ................................................................\n\
+        ................................................................\n\
+        ................................................................\n\
+        ................................................................\n\
+        ................................................................\n\
+        ................................................................\n\
+        ................................................................\n\
+        ................................................................\n\
+        ................................................................\n\
+        ................................................................\n\
+        ................................................................\n\
+        .....................####.....####...#....#.....................\n\
+        .....................#...#...#....#..##...#.....................\n\
+        .....................#...#...#....#..#.#..#.....................\n\
+        .....................####....#....#..#..#.#.....................\n\
+        .....................#...#...#....#..#...##.....................\n\
+        .....................#...#...#....#..#....#.....................\n\
+        .....................#...#...#....#..#....#.....................\n\
+        .....................####.....####...#....#.....................\n\
+        ................................................................\n\
+        ................................................................\n\
+        ................................................................\n\
+        ................................................................\n\
+        ................................................................\n\
+        ..##.............##.............#....###.........#..............\n\
+        ..#.#............#.#............#....#...........#..............\n\
+        ..#.#..#.#.......#.#...##...##..##...#.....#.....#...##.........\n\
+        ..##...#.#.......##...#.#..#....#....#....#.#...##..#.#...##....\n\
+        ..#.#..###.......#.#..##....#...#....#....#.#..#.#..##....#.....\n\
+        ..#.#....#.......#.#..#......#..#....#....#.#..#.#..#.....#.....\n\
+        ..##.....#.......##....##..##....##..###...#....##...##...#.#...\n\
+        .......###......................................................";
+
+    // An "ok" next to every instruction
+    const TEST_OPCODE_PASS: &str = "\
+        ................................................................\n\
+        .###.#.#..###.#.#......###.###..###.#.#.....###..##.###.#.#.....\n\
+        ..##..#...#.#.##.......#.#.##...#.#.##......###..#..#.#.##......\n\
+        ...#.#.#..#.#.#.#......#.#.#....#.#.#.#.....#.#...#.#.#.#.#.....\n\
+        .###.#.#..###.#.#......###.###..###.#.#.....###..#..###.#.#.....\n\
+        ................................................................\n\
+        .#.#.#.#..###.#.#......###.###..###.#.#.....###.###.###.#.#.....\n\
+        .###..#...#.#.##.......###.#.#..#.#.##......###.#...#.#.##......\n\
+        ...#.#.#..#.#.#.#......#.#.#.#..#.#.#.#.....#.#.###.#.#.#.#.....\n\
+        ...#.#.#..###.#.#......###.###..###.#.#.....###.###.###.#.#.....\n\
+        ................................................................\n\
+        ..##.#.#..###.#.#......###.##...###.#.#.....###.###.###.#.#.....\n\
+        ..#...#...#.#.##.......###..#...#.#.##......###.##..#.#.##......\n\
+        ...#.#.#..#.#.#.#......#.#..#...#.#.#.#.....#.#.#...#.#.#.#.....\n\
+        ..#..#.#..###.#.#......###.###..###.#.#.....###.###.###.#.#.....\n\
+        ................................................................\n\
+        .###.#.#..###.#.#......###.###..###.#.#.....###..##.###.#.#.....\n\
+        ...#..#...#.#.##.......###...#..#.#.##......#....#..#.#.##......\n\
+        ...#.#.#..#.#.#.#......#.#.##...#.#.#.#.....##....#.#.#.#.#.....\n\
+        ...#.#.#..###.#.#......###.###..###.#.#.....#....#..###.#.#.....\n\
+        ................................................................\n\
+        .###.#.#..###.#.#......###.###..###.#.#.....###.###.###.#.#.....\n\
+        .###..#...#.#.##.......###..##..#.#.##......#....##.#.#.##......\n\
+        ...#.#.#..#.#.#.#......#.#...#..#.#.#.#.....##....#.#.#.#.#.....\n\
+        .###.#.#..###.#.#......###.###..###.#.#.....#...###.###.#.#.....\n\
+        ................................................................\n\
+        ..#..#.#..###.#.#......###.#.#..###.#.#.....##..#.#.###.#.#.....\n\
+        .#.#..#...#.#.##.......###.###..#.#.##.......#...#..#.#.##......\n\
+        .###.#.#..#.#.#.#......#.#...#..#.#.#.#......#..#.#.#.#.#.#.....\n\
+        .#.#.#.#..###.#.#......###...#..###.#.#.....###.#.#.###.#.#.....\n\
+        ................................................................\n\
+        ................................................................";
+
+    fn screen(cpu: &Cpu) -> String {
+        let graphics = &cpu.graphics;
+        (0..graphics.height())
+            .map(|y| (0..graphics.width()).map(|x| if graphics.pixel(x, y) { '#' } else { '.' }).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_roms_pass_with_the_settings_the_database_recommends() {
+        let database = Database::bundled();
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/test_roms");
+        for entry in fs::read_dir(directory).expect("Unable to list the test ROMs") {
+            let path = entry.expect("Unable to list the test ROMs").path();
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string();
+            let rom = fs::read(&path).expect("Unable to read a test ROM");
+            let info = database.lookup(&rom_hash(&rom)).unwrap_or_else(|| panic!("{} isn't in the database", name));
+
+            let mut cpu = Cpu::new();
+            cpu.quirks = info.quirks.unwrap_or_default();
+            cpu.seed_rng(1);
+            cpu.load_rom(rom).unwrap();
+            let instructions_per_frame = info.tickrate.map_or(DEFAULT_INSTRUCTIONS_PER_FRAME, |tickrate| tickrate as usize);
+            for _ in 0..600 {
+                cpu.run_frame(instructions_per_frame);
+            }
+
+            match PASS_SCREENS.iter().find(|(rom, _)| *rom == name) {
+                Some((_, pass)) => assert_eq!(screen(&cpu), *pass, "{} didn't pass with {:?}", name, cpu.quirks),
+                None => assert!(cpu.graphics.lit_pixels().next().is_some(), "{} drew nothing", name)
+            }
+        }
+    }
+
+    #[test]
+    fn memory_quirks_map_to_how_i_moves() {
+        let programs = r#"[{"title": "Test", "roms": {
+            "a": {"platforms": ["chip48"]},
+            "b": {"platforms": ["superchip"]},
+            "c": {"platforms": ["originalChip8"]}
+        }}]"#;
+        let database = Database::parse(programs, r#"{"a": 0, "b": 0, "c": 0}"#, super::PLATFORMS).expect("The test database is valid");
+        let quirks = |hash| database.lookup(hash).and_then(|info| info.quirks).expect("The test ROMs have quirks");
+
+        assert_eq!(quirks("a"), Quirks::chip48());
+        assert!(!quirks("b").load_store_increments_i);
+        assert!(quirks("c").load_store_increments_i && !quirks("c").load_store_increments_by_x);
+    }
+    #[cfg(feature = "frontend")]
+    #[test]
+    fn key_hints_name_real_keys() {
+        for &(hint, host_key) in super::KEY_HINTS.iter() {
+            assert!(crate::keymap::key_from_name(host_key).is_some(), "{} maps to {}", hint, host_key);
+        }
+    }
+}
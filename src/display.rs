@@ -1,161 +1,694 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use ggez::*;
 use ggez::event::KeyCode;
 use ggez::event::KeyMods;
 
-const PIXEL_SIZE: i32 = 20;
-pub const WINDOW_WIDTH: f32 = cpu::SCREEN_WIDTH as f32 * PIXEL_SIZE as f32;
-pub const WINDOW_HEIGHT: f32 = cpu::SCREEN_HEIGHT as f32 * PIXEL_SIZE as f32;
+pub const DEFAULT_PIXEL_SIZE: u32 = 20;
 
+pub use crate::cpu::{DEFAULT_INSTRUCTIONS_PER_FRAME, FRAMES_PER_SECOND};
+
+// How long an uncapped update keeps running frames before letting the window redraw
+const UNCAPPED_FRAME_BUDGET: Duration = Duration::from_millis(14);
+// How long a notice like the new palette's name stays on screen
+const NOTICE_DURATION: Duration = Duration::from_secs(2);
+
+use crate::audio::{Pattern, WavRecorder};
+use crate::capture::{self, Recorder};
+use crate::cheats::{Cheats, MemorySearch};
+use crate::config::Config;
+use crate::console::Console;
 use crate::cpu;
+use crate::filter::PhosphorFilter;
+use crate::keymap::Keymap;
+use crate::memory_viewer::{Highlight, MemoryViewer};
+use crate::palette::{Palette, Rgb};
+use crate::script::Script;
+use crate::settings_menu::SettingsMenu;
+use crate::sprite_viewer::SpriteViewer;
+use crate::symbols::Symbols;
+
+/// How fast emulated frames run compared to the 60Hz display
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speed {
+    /// One emulated frame every n display frames
+    SlowMotion(u32),
+    Normal,
+    /// n emulated frames every display frame
+    FastForward(u32),
+    /// As many emulated frames as the host can run
+    Uncapped
+}
+
+const SPEEDS: [Speed; 7] = [
+    Speed::SlowMotion(4),
+    Speed::SlowMotion(2),
+    Speed::Normal,
+    Speed::FastForward(2),
+    Speed::FastForward(4),
+    Speed::FastForward(8),
+    Speed::Uncapped
+];
 
-impl ggez::event::EventHandler for cpu::Cpu {
-    fn update(&mut self, _ctx: &mut Context) -> GameResult {
-        self.cycle();
+impl Speed {
+    fn faster(self) -> Self {
+        let index = SPEEDS.iter().position(|&speed| speed == self).unwrap_or(2);
+        SPEEDS[(index + 1).min(SPEEDS.len() - 1)]
+    }
+
+    fn slower(self) -> Self {
+        let index = SPEEDS.iter().position(|&speed| speed == self).unwrap_or(2);
+        SPEEDS[index.saturating_sub(1)]
+    }
+
+    fn label(self) -> String {
+        match self {
+            Speed::SlowMotion(divisor) => format!("1/{}x", divisor),
+            Speed::Normal => String::from("1x"),
+            Speed::FastForward(multiplier) => format!("{}x", multiplier),
+            Speed::Uncapped => String::from("Uncapped")
+        }
+    }
+}
+
+/// The ggez frontend. Runs the CPU a frame at a time and handles the hotkeys:
+/// P pauses/resumes, N advances one frame and I one instruction while paused,
+/// = and - change the speed, 0 resets it and holding Tab fast-forwards uncapped. F1 opens the settings menu
+/// and F2 switches to the next palette preset, F3 to the next persistence filter. F5 saves a screenshot
+/// and F6 starts and stops recording. F4 shows memory, F7 sprites and F8 opens the cheat console
+pub struct Emulator {
+    pub cpu: cpu::Cpu,
+    pub instructions_per_frame: usize,
+    pub pixel_size: u32,
+    pub palette: Palette,
+    pub keymap: Keymap,
+    /// What's drawn is the display after this filter
+    pub filter: PhosphorFilter,
+    /// Config file the settings menu saves to, if one is in use
+    pub config: Option<Config>,
+    /// SHA-1 of the running ROM, which its settings are saved under
+    pub rom_hash: Option<String>,
+    /// Labels shown in place of addresses
+    pub symbols: Symbols,
+    /// Hooks run every frame, and every instruction if the script asks for them
+    pub script: Option<Script>,
+    /// Values written back at the start of every frame
+    pub cheats: Cheats,
+    /// The console's memory search, kept while the console is closed to play on
+    pub ram_search: Option<MemorySearch>,
+    menu: Option<SettingsMenu>,
+    console: Option<Console>,
+    memory_viewer: Option<MemoryViewer>,
+    sprite_viewer: Option<SpriteViewer>,
+    /// Message shown for a moment after a hotkey, and when it was shown
+    notice: Option<(String, Instant)>,
+    showing_key_wait: bool,
+    /// The recording in progress and where it's going
+    recorder: Option<(Recorder, PathBuf)>,
+    /// The sound recording in progress and where it's going
+    audio_recorder: Option<(WavRecorder, PathBuf)>,
+    paused: bool,
+    speed: Speed,
+    fast_forward_held: bool,
+    slow_motion_ticks: u32,
+    redraw: bool
+}
+
+impl Emulator {
+    pub fn new(cpu: cpu::Cpu) -> Self {
+        Emulator {
+            cpu,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            pixel_size: DEFAULT_PIXEL_SIZE,
+            palette: Palette::default(),
+            keymap: Keymap::default(),
+            filter: PhosphorFilter::new(Default::default()),
+            config: None,
+            rom_hash: None,
+            symbols: Symbols::new(),
+            script: None,
+            cheats: Cheats::new(),
+            ram_search: None,
+            menu: None,
+            console: None,
+            memory_viewer: None,
+            sprite_viewer: None,
+            notice: None,
+            showing_key_wait: false,
+            recorder: None,
+            audio_recorder: None,
+            paused: false,
+            speed: Speed::Normal,
+            fast_forward_held: false,
+            slow_motion_ticks: 0,
+            redraw: true
+        }
+    }
+
+    /// Size of the window needed to show the whole display
+    pub fn window_size(&self) -> (f32, f32) {
+        (
+            (self.cpu.graphics.width() as u32 * self.pixel_size) as f32,
+            (self.cpu.graphics.height() as u32 * self.pixel_size) as f32
+        )
+    }
+
+    fn run_frame(&mut self) {
+        self.cheats.apply(&mut self.cpu);
+        let (cpu, instructions_per_frame) = (&mut self.cpu, self.instructions_per_frame);
+        let sounding = match self.script.as_mut().map(|script| script.run_frame(cpu, instructions_per_frame)) {
+            Some(Ok(sounding)) => sounding,
+            Some(Err(error)) => {
+                eprintln!("Script error: {}", error);
+                self.script = None;
+                self.paused = true;
+                self.show_notice(format!("Script stopped: {}", error));
+                false
+            },
+            None => self.cpu.run_frame(self.instructions_per_frame)
+        };
+        self.update_filter();
+        if let Some(address) = self.cpu.breakpoint_hit() {
+            self.paused = true;
+            self.show_notice(format!("Breakpoint at {}", self.symbols.describe(address)));
+        }
+        if self.script.as_mut().is_some_and(Script::take_stop_request) {
+            self.paused = true;
+            self.show_notice(String::from("Paused by the script"));
+        }
+        if self.memory_viewer.is_some() || self.sprite_viewer.is_some() || self.console.is_some() {
+            self.redraw = true;
+        }
+
+        if let Some((audio_recorder, _)) = &mut self.audio_recorder {
+            if let Err(error) = audio_recorder.record_frame(sounding, &Pattern::beeper()) {
+                self.audio_recorder = None;
+                self.show_notice(format!("Audio recording stopped: {}", error));
+            }
+        }
+
+        if let Some((recorder, _)) = &mut self.recorder {
+            if let Err(error) = recorder.record_frame(&self.cpu.graphics) {
+                self.recorder = None;
+                self.show_notice(format!("Recording stopped: {}", error));
+            }
+        }
+    }
+
+    /// Saves the display as a PNG at the window's scale in the current palette
+    pub fn save_screenshot(&self, path: &Path) -> Result<(), String> {
+        capture::save_screenshot(path, &self.cpu.graphics, &self.palette, self.pixel_size as usize)
+    }
+
+    /// Starts recording every emulated frame to a GIF, or raw frames for any other extension
+    pub fn start_recording(&mut self, path: &Path) -> Result<(), String> {
+        let graphics = &self.cpu.graphics;
+        let recorder = Recorder::create(path, graphics.width(), graphics.height(), &self.palette, self.pixel_size as usize)?;
+        self.recorder = Some((recorder, path.to_path_buf()));
         Ok(())
     }
 
-    fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
-        if !self.can_draw {
-            return Ok(());
+    /// Finishes the recording in progress, returning where it went and how many frames it has
+    pub fn stop_recording(&mut self) -> Result<Option<(PathBuf, usize)>, String> {
+        match self.recorder.take() {
+            Some((recorder, path)) => recorder.finish().map(|frames| Some((path, frames))),
+            None => Ok(None)
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Starts recording the sound of every emulated frame to a WAV file
+    pub fn start_audio_recording(&mut self, path: &Path) -> Result<(), String> {
+        self.audio_recorder = Some((WavRecorder::create(path)?, path.to_path_buf()));
+        Ok(())
+    }
+
+    /// Finishes the sound recording in progress, returning where it went and how many frames it has
+    pub fn stop_audio_recording(&mut self) -> Result<Option<(PathBuf, usize)>, String> {
+        match self.audio_recorder.take() {
+            Some((audio_recorder, path)) => audio_recorder.finish().map(|frames| Some((path, frames))),
+            None => Ok(None)
+        }
+    }
+
+    /// Starts or stops recording a GIF and a WAV file of the sound next to it
+    fn toggle_recording(&mut self) {
+        let notice = if self.is_recording() {
+            let audio = self.stop_audio_recording();
+            match (self.stop_recording(), audio) {
+                (Ok(Some((path, frames))), Ok(Some((audio_path, _)))) => {
+                    format!("Saved {} frames to {} and {}", frames, path.display(), audio_path.display())
+                },
+                (Ok(Some((path, frames))), Ok(None)) => format!("Saved {} frames to {}", frames, path.display()),
+                (Ok(None), _) => return,
+                (Err(error), _) | (_, Err(error)) => error
+            }
+        } else {
+            let path = capture_path("gif");
+            let audio_path = path.with_extension("wav");
+            match self.start_recording(&path).and_then(|()| self.start_audio_recording(&audio_path)) {
+                Ok(()) => format!("Recording to {} and {}", path.display(), audio_path.display()),
+                Err(error) => error
+            }
+        };
+
+        self.show_notice(notice);
+    }
+
+    fn update_filter(&mut self) {
+        if self.filter.update(&self.cpu.graphics) {
+            self.redraw = true;
         }
+    }
 
-        graphics::clear(ctx, graphics::BLACK);
+    fn effective_speed(&self) -> Speed {
+        if self.fast_forward_held { Speed::Uncapped } else { self.speed }
+    }
 
-        for row in 0..cpu::SCREEN_HEIGHT {
-            for column in 0..cpu::SCREEN_WIDTH {
-                if self.graphics[row as usize][column as usize] == false {
-                    continue;
+    /// Runs however many frames the current speed calls for in one 60Hz tick
+    fn tick(&mut self) {
+        match self.effective_speed() {
+            Speed::SlowMotion(divisor) => {
+                self.slow_motion_ticks += 1;
+                if self.slow_motion_ticks >= divisor {
+                    self.slow_motion_ticks = 0;
+                    self.run_frame();
+                }
+            },
+            Speed::Normal => self.run_frame(),
+            Speed::FastForward(multiplier) => {
+                for _ in 0..multiplier {
+                    if self.paused {
+                        break;
+                    }
+                    self.run_frame();
                 }
+            },
+            Speed::Uncapped => {
+                let start = Instant::now();
+                while !self.paused && start.elapsed() < UNCAPPED_FRAME_BUDGET {
+                    self.run_frame();
+                }
+            }
+        }
+    }
+
+    fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.slow_motion_ticks = 0;
+        self.redraw = true;
+    }
+
+    fn show_notice(&mut self, notice: String) {
+        self.notice = Some((notice, Instant::now()));
+        self.redraw = true;
+    }
+
+    /// Text for the on-screen indicator, if there's anything worth showing
+    fn status(&self) -> Option<String> {
+        let speed = self.effective_speed();
+        let mut parts = Vec::new();
+        match (self.paused, speed) {
+            (true, Speed::Normal) => parts.push(String::from("Paused")),
+            (true, _) => parts.push(format!("Paused ({})", speed.label())),
+            (false, Speed::Normal) => (),
+            (false, _) => parts.push(speed.label())
+        }
+
+        if self.is_recording() {
+            parts.push(String::from("REC"));
+        }
+
+        if self.cpu.waiting_for_key() {
+            parts.push(String::from("Waiting for key"));
+        }
+
+        if let Some((notice, _)) = &self.notice {
+            parts.push(notice.clone());
+        }
+
+        if parts.is_empty() { None } else { Some(parts.join(" - ")) }
+    }
+
+    /// Passes a key press to the settings menu while it's open, returning whether it took the key
+    fn handle_menu_key(&mut self, keycode: KeyCode) -> bool {
+        let mut menu = match self.menu.take() {
+            Some(menu) => menu,
+            None => return false
+        };
+
+        if menu.handle_key(self, keycode) {
+            self.menu = Some(menu);
+        }
+        self.redraw = true;
 
-                let rect = ggez::graphics::Rect::new_i32(
-                    column as i32 * PIXEL_SIZE,
-                    row as i32 * PIXEL_SIZE,
-                    PIXEL_SIZE,
-                    PIXEL_SIZE
-                );
-
-                let rectangle = ggez::graphics::Mesh::new_rectangle(
-                    ctx,
-                    graphics::DrawMode::fill(),
-                    rect,
-                    graphics::WHITE
-                )?;
-
-                graphics::draw(ctx, &rectangle, (nalgebra::Point2::new(0.0, 0.0),))?;
+        true
+    }
+
+    /// Passes a key press to the console while it's open, which takes every key
+    fn handle_console_key(&mut self, keycode: KeyCode) -> bool {
+        let mut console = match self.console.take() {
+            Some(console) => console,
+            None => return false
+        };
+
+        if console.handle_key(self, keycode) {
+            self.console = Some(console);
+        }
+        self.redraw = true;
+
+        true
+    }
+
+    /// Passes a key press to the memory viewer while it's open, returning whether it used the key
+    fn handle_memory_viewer_key(&mut self, keycode: KeyCode) -> bool {
+        let used = match &mut self.memory_viewer {
+            Some(viewer) => viewer.handle_key(&mut self.cpu, keycode, self.paused),
+            None => false
+        };
+
+        if used {
+            self.redraw = true;
+        }
+        used
+    }
+
+    /// Passes a key press to the sprite viewer while it's open, returning whether it used the key
+    fn handle_sprite_viewer_key(&mut self, keycode: KeyCode) -> bool {
+        let used = match &mut self.sprite_viewer {
+            Some(viewer) => viewer.handle_key(&self.cpu, &self.palette, keycode),
+            None => false
+        };
+
+        if used {
+            self.redraw = true;
+        }
+        used
+    }
+
+    /// Draws the sprite list on the left and the selected sprite on the right
+    fn draw_sprite_viewer(&self, ctx: &mut Context) -> GameResult<()> {
+        let viewer = match &self.sprite_viewer {
+            Some(viewer) => viewer,
+            None => return Ok(())
+        };
+
+        draw_backdrop(ctx)?;
+
+        for (index, line) in viewer.lines(&self.cpu).into_iter().enumerate() {
+            let text = graphics::Text::new(line);
+            graphics::draw(ctx, &text, (nalgebra::Point2::new(16.0, 16.0 + index as f32 * 20.0), graphics::WHITE))?;
+        }
+
+        let sprite = viewer.selected(&self.cpu);
+        let (width, height) = graphics::drawable_size(ctx);
+        let left = width / 2.0 + 16.0;
+        let size = ((width / 2.0 - 32.0) / sprite.width as f32).min((height - 32.0) / sprite.height as f32).max(1.0).floor();
+
+        let mut pixels = graphics::MeshBuilder::new();
+        let frame = graphics::Rect::new(left, 16.0, sprite.width as f32 * size, sprite.height as f32 * size);
+        pixels.rectangle(graphics::DrawMode::fill(), frame, to_color(self.palette.background()));
+        for (row, line) in sprite.pixels(&self.cpu.memory).into_iter().enumerate() {
+            for (column, lit) in line.into_iter().enumerate() {
+                if lit {
+                    let rect = graphics::Rect::new(left + column as f32 * size, 16.0 + row as f32 * size, size, size);
+                    pixels.rectangle(graphics::DrawMode::fill(), rect, to_color(self.palette.foreground()));
+                }
             }
         }
-        
-        graphics::present(ctx)?;
-        self.can_draw = false;
+        let mesh = pixels.build(ctx)?;
+        graphics::draw(ctx, &mesh, (nalgebra::Point2::new(0.0, 0.0),))
+    }
+
+    fn draw_memory_viewer(&self, ctx: &mut Context) -> GameResult<()> {
+        let viewer = match &self.memory_viewer {
+            Some(viewer) => viewer,
+            None => return Ok(())
+        };
+
+        draw_backdrop(ctx)?;
+
+        for (index, line) in viewer.lines(&self.cpu, &self.symbols).into_iter().enumerate() {
+            let mut text = graphics::Text::default();
+            for (piece, highlight) in line {
+                let color = match highlight {
+                    Some(Highlight::Cursor) => graphics::Color::new(1.0, 0.8, 0.0, 1.0),
+                    Some(Highlight::ProgramCounter) => graphics::Color::new(0.3, 1.0, 0.3, 1.0),
+                    Some(Highlight::Index) => graphics::Color::new(0.3, 0.8, 1.0, 1.0),
+                    Some(Highlight::Stack) => graphics::Color::new(1.0, 0.4, 1.0, 1.0),
+                    Some(Highlight::Font) => graphics::Color::new(0.6, 0.6, 0.6, 1.0),
+                    None => graphics::WHITE
+                };
+                text.add(graphics::TextFragment::new(piece).color(color));
+            }
+            graphics::draw(ctx, &text, (nalgebra::Point2::new(16.0, 16.0 + index as f32 * 20.0),))?;
+        }
 
         Ok(())
     }
 
-    fn key_down_event(&mut self, _ctx: &mut Context, keycode: KeyCode, _keymods: KeyMods, _repeat: bool) {
+    fn draw_console(&self, ctx: &mut Context) -> GameResult<()> {
+        let console = match &self.console {
+            Some(console) => console,
+            None => return Ok(())
+        };
+
+        draw_backdrop(ctx)?;
+
+        for (index, line) in console.lines().into_iter().enumerate() {
+            let text = graphics::Text::new(line);
+            graphics::draw(ctx, &text, (nalgebra::Point2::new(16.0, 16.0 + index as f32 * 20.0), graphics::WHITE))?;
+        }
+
+        Ok(())
+    }
+
+    fn draw_menu(&self, ctx: &mut Context) -> GameResult<()> {
+        let menu = match &self.menu {
+            Some(menu) => menu,
+            None => return Ok(())
+        };
+
+        draw_backdrop(ctx)?;
+
+        for (index, line) in menu.lines(self).into_iter().enumerate() {
+            let text = graphics::Text::new(line);
+            graphics::draw(ctx, &text, (nalgebra::Point2::new(16.0, 16.0 + index as f32 * 20.0), graphics::WHITE))?;
+        }
+
+        Ok(())
+    }
+
+    /// Handles the emulator's own hotkeys, returning whether the key was one of them
+    fn handle_hotkey(&mut self, keycode: KeyCode, repeat: bool) -> bool {
         match keycode {
-            KeyCode::Key1 => {
-                self.keypad[0x1] = true;
-            },
-            KeyCode::Key2 => {
-                self.keypad[0x2] = true;
-            },
-            KeyCode::Key3 => {
-                self.keypad[0x3] = true;
-            },
-            KeyCode::Key4 => {
-                self.keypad[0xC] = true;
-            },
-            KeyCode::Q => {
-                self.keypad[0x4] = true;
-            },
-            KeyCode::W => {
-                self.keypad[0x5] = true;
-            },
-            KeyCode::E => {
-                self.keypad[0x6] = true;
-            },
-            KeyCode::R => {
-                self.keypad[0xD] = true;
-            },
-            KeyCode::A => {
-                self.keypad[0x7] = true;
-            },
-            KeyCode::S => {
-                self.keypad[0x8] = true;
-            },
-            KeyCode::D => {
-                self.keypad[0x9] = true;
-            },
-            KeyCode::F => {
-                self.keypad[0xE] = true;
-            },
-            KeyCode::Z => {
-                self.keypad[0xA] = true;
-            },
-            KeyCode::X => {
-                self.keypad[0x0] = true;
-            },
-            KeyCode::C => {
-                self.keypad[0xB] = true;
+            KeyCode::P if !repeat => {
+                self.paused = !self.paused;
+                self.redraw = true;
+            },
+            KeyCode::N => {
+                if self.paused {
+                    self.run_frame();
+                    self.redraw = true;
+                }
             },
-            KeyCode::V => {
-                self.keypad[0xF] = true;
+            KeyCode::I => {
+                if self.paused {
+                    self.cpu.run_instructions(1);
+                    self.update_filter();
+                    self.redraw = true;
+                }
             },
-            _ => ()
+            KeyCode::Equals if !repeat => self.set_speed(self.speed.faster()),
+            KeyCode::Minus if !repeat => self.set_speed(self.speed.slower()),
+            KeyCode::Key0 if !repeat => self.set_speed(Speed::Normal),
+            KeyCode::Tab => {
+                self.fast_forward_held = true;
+                self.redraw = true;
+            },
+            KeyCode::F1 if !repeat => {
+                self.menu = Some(SettingsMenu::new());
+                self.redraw = true;
+            },
+            KeyCode::F2 if !repeat => {
+                self.palette = self.palette.next_preset();
+                self.show_notice(format!("Palette: {}", self.palette));
+            },
+            KeyCode::F3 if !repeat => {
+                let persistence = self.filter.persistence().next();
+                self.filter.set_persistence(persistence);
+                self.show_notice(format!("Persistence: {}", persistence));
+            },
+            KeyCode::F4 if !repeat => {
+                self.memory_viewer = match self.memory_viewer {
+                    Some(_) => None,
+                    None => Some(MemoryViewer::new(&self.cpu))
+                };
+                self.sprite_viewer = None;
+                self.redraw = true;
+            },
+            KeyCode::F5 if !repeat => {
+                let path = capture_path("png");
+                let notice = match self.save_screenshot(&path) {
+                    Ok(()) => format!("Saved {}", path.display()),
+                    Err(error) => error
+                };
+                self.show_notice(notice);
+            },
+            KeyCode::F6 if !repeat => self.toggle_recording(),
+            KeyCode::F7 if !repeat => {
+                self.sprite_viewer = match self.sprite_viewer {
+                    Some(_) => None,
+                    None => Some(SpriteViewer::new())
+                };
+                self.memory_viewer = None;
+                self.redraw = true;
+            },
+            KeyCode::F8 if !repeat => {
+                self.console = Some(Console::new(self));
+                self.redraw = true;
+            },
+            KeyCode::F1 | KeyCode::F2 | KeyCode::F3 | KeyCode::F4 | KeyCode::F5 | KeyCode::F6 | KeyCode::F7 | KeyCode::F8 | KeyCode::Equals | KeyCode::Minus | KeyCode::Key0 => (),
+            _ => return false
+        }
+
+        true
+    }
+}
+
+impl ggez::event::EventHandler for Emulator {
+    fn update(&mut self, ctx: &mut Context) -> GameResult {
+        if self.notice.as_ref().is_some_and(|(_, shown)| shown.elapsed() >= NOTICE_DURATION) {
+            self.notice = None;
+            self.redraw = true;
+        }
+
+        while timer::check_update_time(ctx, FRAMES_PER_SECOND) {
+            if !self.paused && self.menu.is_none() {
+                self.tick();
+            }
+        }
+
+        // Show or hide the indicator
+        if self.cpu.waiting_for_key() != self.showing_key_wait {
+            self.showing_key_wait = self.cpu.waiting_for_key();
+            self.redraw = true;
         }
+
+        Ok(())
+    }
+
+    fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
+        if !self.redraw {
+            return Ok(());
+        }
+
+        graphics::clear(ctx, to_color(self.palette.background()));
+
+        let pixel_size = self.pixel_size as i32;
+        let mut pixels = graphics::MeshBuilder::new();
+        let mut any_lit = false;
+        for (column, row, color, brightness) in self.filter.pixels() {
+            let rect = ggez::graphics::Rect::new_i32(
+                column as i32 * pixel_size,
+                row as i32 * pixel_size,
+                pixel_size,
+                pixel_size
+            );
+
+            let color = PhosphorFilter::shade(&self.palette, color, brightness);
+            pixels.rectangle(graphics::DrawMode::fill(), rect, to_color(color));
+            any_lit = true;
+        }
+
+        // Building an empty mesh fails, so only draw when something's lit
+        if any_lit {
+            let mesh = pixels.build(ctx)?;
+            graphics::draw(ctx, &mesh, (nalgebra::Point2::new(0.0, 0.0),))?;
+        }
+
+        if let Some(status) = self.status() {
+            let text = graphics::Text::new(status);
+            let color = graphics::Color::new(1.0, 0.8, 0.0, 1.0);
+            graphics::draw(ctx, &text, (nalgebra::Point2::new(8.0, 8.0), color))?;
+        }
+
+        self.draw_memory_viewer(ctx)?;
+        self.draw_sprite_viewer(ctx)?;
+        self.draw_console(ctx)?;
+        self.draw_menu(ctx)?;
+
+        graphics::present(ctx)?;
+        self.cpu.can_draw = false;
+        self.redraw = false;
+
+        Ok(())
+    }
+
+    fn key_down_event(&mut self, _ctx: &mut Context, keycode: KeyCode, _keymods: KeyMods, repeat: bool) {
+        let handled = self.handle_menu_key(keycode)
+            || self.handle_console_key(keycode)
+            || self.handle_memory_viewer_key(keycode)
+            || self.handle_sprite_viewer_key(keycode)
+            || self.handle_hotkey(keycode, repeat);
+        if handled {
+            return;
+        }
+
+        if let Some(key) = self.keymap.get(keycode) {
+            self.cpu.set_key(key, true);
+        }
+    }
+
+    fn text_input_event(&mut self, _ctx: &mut Context, character: char) {
+        if let Some(console) = &mut self.console {
+            console.type_char(character);
+            self.redraw = true;
+        }
+    }
+
+    fn quit_event(&mut self, _ctx: &mut Context) -> bool {
+        if let Err(error) = self.stop_recording().and(self.stop_audio_recording()) {
+            eprintln!("error: {}", error);
+        }
+
+        false
     }
 
     fn key_up_event(&mut self, _ctx: &mut Context, keycode: KeyCode, _keymods: KeyMods) {
-        match keycode {
-            KeyCode::Key1 => {
-                self.keypad[0x1] = false;
-            },
-            KeyCode::Key2 => {
-                self.keypad[0x2] = false;
-            },
-            KeyCode::Key3 => {
-                self.keypad[0x3] = false;
-            },
-            KeyCode::Key4 => {
-                self.keypad[0xC] = false;
-            },
-            KeyCode::Q => {
-                self.keypad[0x4] = false;
-            },
-            KeyCode::W => {
-                self.keypad[0x5] = false;
-            },
-            KeyCode::E => {
-                self.keypad[0x6] = false;
-            },
-            KeyCode::R => {
-                self.keypad[0xD] = false;
-            },
-            KeyCode::A => {
-                self.keypad[0x7] = false;
-            },
-            KeyCode::S => {
-                self.keypad[0x8] = false;
-            },
-            KeyCode::D => {
-                self.keypad[0x9] = false;
-            },
-            KeyCode::F => {
-                self.keypad[0xE] = false;
-            },
-            KeyCode::Z => {
-                self.keypad[0xA] = false;
-            },
-            KeyCode::X => {
-                self.keypad[0x0] = false;
-            },
-            KeyCode::C => {
-                self.keypad[0xB] = false;
-            },
-            KeyCode::V => {
-                self.keypad[0xF] = false;
-            },
-            _ => ()
+        if keycode == KeyCode::Tab {
+            self.fast_forward_held = false;
+            self.redraw = true;
+            return;
+        }
+
+        if let Some(key) = self.keymap.get(keycode) {
+            self.cpu.set_key(key, false);
         }
     }
-}
\ No newline at end of file
+}
+
+/// A file in the current directory named after the current time, e.g. `chip8-1700000000.png`
+fn capture_path(extension: &str) -> PathBuf {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+    PathBuf::from(format!("chip8-{}.{}", seconds, extension))
+}
+
+/// Darkens the whole window so an overlay's text stands out
+fn draw_backdrop(ctx: &mut Context) -> GameResult<()> {
+    let (width, height) = graphics::drawable_size(ctx);
+    let backdrop = graphics::Mesh::new_rectangle(
+        ctx,
+        graphics::DrawMode::fill(),
+        graphics::Rect::new(0.0, 0.0, width, height),
+        graphics::Color::new(0.0, 0.0, 0.0, 0.8)
+    )?;
+    graphics::draw(ctx, &backdrop, (nalgebra::Point2::new(0.0, 0.0),))
+}
+
+fn to_color(rgb: Rgb) -> graphics::Color {
+    graphics::Color::from_rgb(rgb.r, rgb.g, rgb.b)
+}
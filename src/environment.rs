@@ -0,0 +1,277 @@
+use std::sync::Arc;
+use crate::cpu::{Backend, Cpu, DEFAULT_INSTRUCTIONS_PER_FRAME, START_ADDRESS};
+use crate::quirks::Quirks;
+
+/// Frames each step runs with the same keys held unless set otherwise
+pub const DEFAULT_FRAME_SKIP: usize = 4;
+
+/// Scores a step and decides when an episode is over. Any `Fn(&[u8], &Cpu) -> f64` is a reward function that
+/// never ends the episode
+pub trait RewardFunction: Send + Sync {
+    /// The reward for a step, from memory as it was before the step and the CPU after it
+    fn reward(&self, before: &[u8], after: &Cpu) -> f64;
+
+    /// Whether the episode ended with the step
+    fn done(&self, _cpu: &Cpu) -> bool {
+        false
+    }
+}
+
+impl<F: Fn(&[u8], &Cpu) -> f64 + Send + Sync> RewardFunction for F {
+    fn reward(&self, before: &[u8], after: &Cpu) -> f64 {
+        self(before, after)
+    }
+}
+
+/// Rewards a change in a byte of memory, such as where a game keeps the score, and optionally ends the
+/// episode when another byte holds a value, such as lives reaching zero
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryReward {
+    pub address: u16,
+    /// Reward per unit the byte goes up. Negative to punish increases, such as the opponent's score
+    pub scale: f64,
+    pub done_when: Option<(u16, u8)>
+}
+
+impl MemoryReward {
+    pub fn new(address: u16) -> Self {
+        MemoryReward { address, scale: 1.0, done_when: None }
+    }
+}
+
+impl RewardFunction for MemoryReward {
+    fn reward(&self, before: &[u8], after: &Cpu) -> f64 {
+        let address = self.address as usize;
+        (after.memory[address] as f64 - before[address] as f64) * self.scale
+    }
+
+    fn done(&self, cpu: &Cpu) -> bool {
+        self.done_when.is_some_and(|(address, value)| cpu.memory[address as usize] == value)
+    }
+}
+
+/// What a step returns
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub observation: Vec<u8>,
+    pub reward: f64,
+    /// Whether the reward function ended the episode or it ran for `max_frames`
+    pub done: bool
+}
+
+/// A reinforcement learning environment around a ROM, in the style of OpenAI Gym. Each action is a set of
+/// keypad keys held for `frame_skip` frames, and each observation is the screen as one color index per pixel.
+/// Cloning copies the whole machine, so one environment can be set up, reset and cloned for every worker of a
+/// vectorized rollout, and clones can be sent to other threads
+#[derive(Clone)]
+pub struct Environment {
+    rom: Vec<u8>,
+    pub start_address: u16,
+    pub quirks: Quirks,
+    pub backend: Backend,
+    pub instructions_per_frame: usize,
+    pub frame_skip: usize,
+    /// Episodes end after this many frames even if the reward function hasn't ended them
+    pub max_frames: Option<usize>,
+    /// The keys each action holds down, as a bit per key. Defaults to no keys, then each key on its own
+    pub actions: Vec<u16>,
+    pub reward: Arc<dyn RewardFunction>,
+    cpu: Cpu,
+    frames: usize
+}
+
+impl Environment {
+    /// An environment for the ROM that rewards nothing, until `reward` is set. Fails if the ROM doesn't fit in memory
+    pub fn new(rom: Vec<u8>) -> Result<Self, String> {
+        let mut environment = Environment {
+            rom,
+            start_address: START_ADDRESS,
+            quirks: Quirks::default(),
+            backend: Backend::Interpreter,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            frame_skip: DEFAULT_FRAME_SKIP,
+            max_frames: None,
+            actions: (0..=16).map(|key| if key == 0 { 0 } else { 1 << (key - 1) }).collect(),
+            reward: Arc::new(|_: &[u8], _: &Cpu| 0.0),
+            cpu: Cpu::new(),
+            frames: 0
+        };
+        environment.reset(0)?;
+        Ok(environment)
+    }
+
+    /// Starts a new episode from a freshly loaded ROM, returning the first observation. Fails if the ROM doesn't
+    /// fit in memory from `start_address`
+    pub fn reset(&mut self, seed: u64) -> Result<Vec<u8>, String> {
+        let mut cpu = Cpu::new();
+        cpu.quirks = self.quirks;
+        cpu.set_backend(self.backend);
+        cpu.seed_rng(seed);
+        cpu.load_rom_at(self.rom.clone(), self.start_address)?;
+
+        self.cpu = cpu;
+        self.frames = 0;
+        Ok(self.observation())
+    }
+
+    /// Holds the action's keys, runs `frame_skip` frames and scores them
+    pub fn step(&mut self, action: usize) -> Result<Step, String> {
+        let keys = *self
+            .actions
+            .get(action)
+            .ok_or_else(|| format!("action {} doesn't exist, there are {}", action, self.actions.len()))?;
+        for key in 0..16 {
+            let pressed = keys & 1 << key != 0;
+            if self.cpu.keypad[key] != pressed {
+                self.cpu.set_key(key, pressed);
+            }
+        }
+
+        let before = self.cpu.memory;
+        let mut done = false;
+        for _ in 0..self.frame_skip.max(1) {
+            self.cpu.run_frame(self.instructions_per_frame);
+            self.frames += 1;
+            if self.reward.done(&self.cpu) || self.max_frames.is_some_and(|max_frames| self.frames >= max_frames) {
+                done = true;
+                break;
+            }
+        }
+
+        Ok(Step {
+            observation: self.observation(),
+            reward: self.reward.reward(&before, &self.cpu),
+            done
+        })
+    }
+
+    /// The screen, row by row, as the color index of each pixel: 0 or 1, or up to 15 on several planes
+    pub fn observation(&self) -> Vec<u8> {
+        let graphics = &self.cpu.graphics;
+        (0..graphics.height())
+            .flat_map(|y| (0..graphics.width()).map(move |x| graphics.color_index(x, y) as u8))
+            .collect()
+    }
+
+    /// Height and width of the observation, which changes when a program switches resolution
+    pub fn observation_shape(&self) -> (usize, usize) {
+        (self.cpu.graphics.height(), self.cpu.graphics.width())
+    }
+
+    pub fn action_count(&self) -> usize {
+        self.actions.len()
+    }
+
+    /// Frames run since the last reset
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// The machine, for reading anything the observation leaves out
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::MEMORY_SIZE;
+
+    /// Draws a random digit over and over, counting the times round the loop key 5 is held in V3, which it keeps at 0x303
+    const GAME: [u8; 32] = [
+        0x00, 0xE0, 0xC0, 0x0F, 0xF0, 0x29, 0xD1, 0x15, // CLS, RND V0, 0xF, LD F, V0, DRW V1, V1, 5
+        0x62, 0x05, 0xE2, 0x9E, 0x12, 0x10, 0x73, 0x01, // LD V2, 5, SKP V2, JP 0x210, ADD V3, 1
+        0xA3, 0x00, 0xF3, 0x55, 0x64, 0x01, 0xF4, 0x15, // LD I, 0x300, LD [I], V3, LD V4, 1, LD DT, V4
+        0xF4, 0x07, 0x34, 0x00, 0x12, 0x18, 0x12, 0x00 // LD V4, DT, SE V4, 0, JP 0x218, JP 0x200
+    ];
+    const HOLD_5: usize = 6;
+
+    fn environment() -> Environment {
+        let mut environment = Environment::new(GAME.to_vec()).unwrap();
+        environment.reward = Arc::new(MemoryReward { address: 0x303, scale: 0.5, done_when: Some((0x303, 10)) });
+        environment
+    }
+
+    #[test]
+    fn steps_score_the_frames_they_run() {
+        let mut environment = environment();
+        let first = environment.reset(1).unwrap();
+        assert_eq!(first, vec![0; 64 * 32]);
+        assert_eq!(environment.observation_shape(), (32, 64));
+
+        let step = environment.step(0).unwrap();
+        assert_eq!((step.reward, step.done), (0.0, false));
+        assert!(step.observation.contains(&1));
+        assert_eq!(environment.frames(), DEFAULT_FRAME_SKIP);
+
+        let step = environment.step(HOLD_5).unwrap();
+        let score = environment.cpu().memory[0x303];
+        assert!(score > 0);
+        assert_eq!((step.reward, step.done), (0.5 * score as f64, false));
+        assert!(environment.cpu().keypad[5]);
+
+        // Letting go of the key stops the score
+        assert_eq!(environment.step(0).unwrap().reward, 0.0);
+        assert!(!environment.cpu().keypad[5]);
+        assert!(environment.step(environment.action_count()).is_err());
+    }
+
+    #[test]
+    fn episodes_end_when_the_reward_says_or_after_max_frames() {
+        let mut environment = environment();
+        let mut steps = vec![environment.step(HOLD_5).unwrap()];
+        while !steps.last().unwrap().done {
+            steps.push(environment.step(HOLD_5).unwrap());
+        }
+        assert_eq!(environment.cpu().memory[0x303], 10);
+        assert_eq!(steps.iter().map(|step| step.reward).sum::<f64>(), 5.0);
+
+        environment.reset(0).unwrap();
+        environment.reward = Arc::new(|_: &[u8], _: &Cpu| 1.0);
+        environment.max_frames = Some(10);
+        let done: Vec<bool> = (0..3).map(|_| environment.step(0).unwrap().done).collect();
+        assert_eq!(done, [false, false, true]);
+        assert_eq!(environment.frames(), 10);
+
+        environment.reset(0).unwrap();
+        assert_eq!(environment.frames(), 0);
+    }
+
+    /// Steps through a fixed list of actions
+    fn rollout(mut environment: Environment) -> Vec<Step> {
+        [0, HOLD_5, 3, HOLD_5, HOLD_5, 0, 16, 0, 0, HOLD_5].iter().map(|&action| environment.step(action).unwrap()).collect()
+    }
+
+    #[test]
+    fn clones_given_the_same_actions_see_the_same_screens() {
+        let mut environment = environment();
+        environment.reset(7).unwrap();
+        environment.step(HOLD_5).unwrap();
+
+        let here = rollout(environment.clone());
+        let there = std::thread::spawn({
+            let environment = environment.clone();
+            move || rollout(environment)
+        });
+        assert_eq!(here, there.join().unwrap());
+
+        // The seed picks the digits, and a reset with the same one plays them again
+        environment.reset(7).unwrap();
+        environment.step(HOLD_5).unwrap();
+        assert_eq!(rollout(environment.clone()), here);
+        environment.reset(8).unwrap();
+        environment.step(HOLD_5).unwrap();
+        assert_ne!(rollout(environment), here);
+    }
+
+    #[test]
+    fn roms_that_do_not_fit_are_refused() {
+        let too_big = vec![0; MEMORY_SIZE - START_ADDRESS as usize + 1];
+        assert!(Environment::new(too_big).is_err());
+
+        let mut environment = environment();
+        environment.start_address = 0xFF0;
+        assert!(environment.reset(0).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice};
+use crate::cpu::Cpu;
+use crate::quirks::Quirks;
+
+/// A CPU for C programs embedding the emulator, along with the pixels last handed out by `chip8_framebuffer`.
+/// Opaque to C, which only ever holds a pointer from `chip8_create`
+pub struct Chip8 {
+    cpu: Cpu,
+    framebuffer: Vec<u8>
+}
+
+/// Runs `body` on the CPU behind `chip8`, returning `failure` instead if the pointer is null or the emulator panics,
+/// since unwinding into C is undefined behavior
+unsafe fn with_chip8<T>(chip8: *mut Chip8, failure: T, body: impl FnOnce(&mut Chip8) -> T) -> T {
+    match chip8.as_mut() {
+        Some(chip8) => panic::catch_unwind(AssertUnwindSafe(|| body(chip8))).unwrap_or(failure),
+        None => failure
+    }
+}
+
+/// `with_chip8` for the entry points that only read, which C hands a const pointer
+unsafe fn with_chip8_ref<T>(chip8: *const Chip8, failure: T, body: impl FnOnce(&Chip8) -> T) -> T {
+    match chip8.as_ref() {
+        Some(chip8) => panic::catch_unwind(AssertUnwindSafe(|| body(chip8))).unwrap_or(failure),
+        None => failure
+    }
+}
+
+/// `length` bytes at `data`, where a null pointer is only allowed for an empty slice
+unsafe fn bytes<'a>(data: *const u8, length: usize) -> Option<&'a [u8]> {
+    match (data.is_null(), length) {
+        (_, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(slice::from_raw_parts(data, length))
+    }
+}
+
+/// A CPU with the built-in font loaded and default quirks, or null if it couldn't be made. Free it with
+/// `chip8_destroy`
+#[no_mangle]
+pub extern "C" fn chip8_create() -> *mut Chip8 {
+    panic::catch_unwind(|| Box::into_raw(Box::new(Chip8 { cpu: Cpu::new(), framebuffer: Vec::new() })))
+        .unwrap_or(ptr::null_mut())
+}
+
+/// # Safety
+/// `chip8` must come from `chip8_create` and not be used again. Null is ignored
+#[no_mangle]
+pub unsafe extern "C" fn chip8_destroy(chip8: *mut Chip8) {
+    if !chip8.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(chip8))));
+    }
+}
+
+/// Seeds the random number generator so runs can be repeated
+///
+/// # Safety
+/// `chip8` must come from `chip8_create`
+#[no_mangle]
+pub unsafe extern "C" fn chip8_seed(chip8: *mut Chip8, seed: u64) {
+    with_chip8(chip8, (), |chip8| chip8.cpu.seed_rng(seed));
+}
+
+/// Switches to a quirk profile like "chip8" or "schip", returning false if there's no such profile
+///
+/// # Safety
+/// `chip8` must come from `chip8_create` and `profile` must be null or a null-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_quirks(chip8: *mut Chip8, profile: *const c_char) -> bool {
+    if profile.is_null() {
+        return false;
+    }
+
+    let quirks = CStr::from_ptr(profile).to_str().ok().and_then(Quirks::from_profile);
+    with_chip8(chip8, false, |chip8| match quirks {
+        Some(quirks) => {
+            chip8.cpu.quirks = quirks;
+            true
+        },
+        None => false
+    })
+}
+
+/// Copies a ROM into memory at 0x200 and starts running from there, returning false if it doesn't fit or `rom` is
+/// null with a nonzero length
+///
+/// # Safety
+/// `chip8` must come from `chip8_create` and `rom` must point to `length` bytes. It may be null when `length` is 0
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_rom(chip8: *mut Chip8, rom: *const u8, length: usize) -> bool {
+    match bytes(rom, length) {
+        Some(rom) => with_chip8(chip8, false, |chip8| chip8.cpu.load_rom(rom.to_vec()).is_ok()),
+        None => false
+    }
+}
+
+/// Runs a number of instructions without touching the timers
+///
+/// # Safety
+/// `chip8` must come from `chip8_create`
+#[no_mangle]
+pub unsafe extern "C" fn chip8_step(chip8: *mut Chip8, instructions: usize) {
+    with_chip8(chip8, (), |chip8| chip8.cpu.run_instructions(instructions));
+}
+
+/// Runs a 60Hz frame, returning whether the buzzer sounded. Returns false without running for a null CPU
+///
+/// # Safety
+/// `chip8` must come from `chip8_create`
+#[no_mangle]
+pub unsafe extern "C" fn chip8_run_frame(chip8: *mut Chip8, instructions_per_frame: usize) -> bool {
+    with_chip8(chip8, false, |chip8| chip8.cpu.run_frame(instructions_per_frame))
+}
+
+/// Whether the sound timer is running
+///
+/// # Safety
+/// `chip8` must come from `chip8_create`
+#[no_mangle]
+pub unsafe extern "C" fn chip8_sound_active(chip8: *const Chip8) -> bool {
+    with_chip8_ref(chip8, false, |chip8| chip8.cpu.sound_timer > 0)
+}
+
+/// Presses or releases a key from 0 to 15, returning false for any other key
+///
+/// # Safety
+/// `chip8` must come from `chip8_create`
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_key(chip8: *mut Chip8, key: u8, pressed: bool) -> bool {
+    if key >= 16 {
+        return false;
+    }
+
+    with_chip8(chip8, false, |chip8| {
+        chip8.cpu.set_key(key as usize, pressed);
+        true
+    })
+}
+
+/// Width of the display in pixels, which changes when a program switches resolution
+///
+/// # Safety
+/// `chip8` must come from `chip8_create`
+#[no_mangle]
+pub unsafe extern "C" fn chip8_screen_width(chip8: *const Chip8) -> usize {
+    with_chip8_ref(chip8, 0, |chip8| chip8.cpu.graphics.width())
+}
+
+/// # Safety
+/// `chip8` must come from `chip8_create`
+#[no_mangle]
+pub unsafe extern "C" fn chip8_screen_height(chip8: *const Chip8) -> usize {
+    with_chip8_ref(chip8, 0, |chip8| chip8.cpu.graphics.height())
+}
+
+/// The display row by row as one color index per pixel, `chip8_screen_width` by `chip8_screen_height` bytes.
+/// It stays valid until the next call with this CPU. Null for a null CPU
+///
+/// # Safety
+/// `chip8` must come from `chip8_create`
+#[no_mangle]
+pub unsafe extern "C" fn chip8_framebuffer(chip8: *mut Chip8) -> *const u8 {
+    with_chip8(chip8, ptr::null(), |chip8| {
+        chip8.framebuffer.clear();
+        chip8.framebuffer.extend(chip8.cpu.graphics.pixels().map(|(_, _, color)| color as u8));
+        chip8.framebuffer.as_ptr()
+    })
+}
+
+/// Saves the machine, apart from the random number generator, into `buffer` if it has room and returns the size
+/// of the snapshot either way, so it can be called with a null buffer first to find out how much room is needed.
+/// Returns 0 for a null CPU
+///
+/// # Safety
+/// `chip8` must come from `chip8_create` and `buffer` must be null or point to `capacity` writable bytes
+#[no_mangle]
+pub unsafe extern "C" fn chip8_save_state(chip8: *const Chip8, buffer: *mut u8, capacity: usize) -> usize {
+    with_chip8_ref(chip8, 0, |chip8| {
+        let state = chip8.cpu.save_state();
+        if !buffer.is_null() && state.len() <= capacity {
+            slice::from_raw_parts_mut(buffer, state.len()).copy_from_slice(&state);
+        }
+        state.len()
+    })
+}
+
+/// Restores a snapshot from `chip8_save_state`, returning false and leaving the machine alone if it's damaged
+///
+/// # Safety
+/// `chip8` must come from `chip8_create` and `state` must point to `length` bytes. It may be null when `length` is 0
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_state(chip8: *mut Chip8, state: *const u8, length: usize) -> bool {
+    match bytes(state, length) {
+        Some(state) => with_chip8(chip8, false, |chip8| chip8.cpu.load_state(state).is_ok()),
+        None => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{MEMORY_SIZE, START_ADDRESS};
+
+    #[test]
+    fn null_pointers_fail_instead_of_crashing() {
+        unsafe {
+            assert!(!chip8_run_frame(ptr::null_mut(), 10));
+            assert_eq!(chip8_screen_width(ptr::null()), 0);
+            assert_eq!(chip8_save_state(ptr::null(), ptr::null_mut(), 0), 0);
+            assert!(chip8_framebuffer(ptr::null_mut()).is_null());
+            chip8_destroy(ptr::null_mut());
+
+            let chip8 = chip8_create();
+            assert!(chip8_load_rom(chip8, ptr::null(), 0));
+            assert!(!chip8_load_rom(chip8, ptr::null(), 2));
+            assert!(!chip8_set_quirks(chip8, ptr::null()));
+            assert!(!chip8_load_state(chip8, ptr::null(), 0));
+            assert!(!chip8_load_state(chip8, ptr::null(), 10));
+            chip8_destroy(chip8);
+        }
+    }
+
+    #[test]
+    fn roms_that_dont_fit_are_turned_away() {
+        let rom = vec![0; MEMORY_SIZE - START_ADDRESS as usize + 1];
+        unsafe {
+            let chip8 = chip8_create();
+            assert!(!chip8_load_rom(chip8, rom.as_ptr(), rom.len()));
+            assert!(chip8_load_rom(chip8, rom.as_ptr(), rom.len() - 1));
+            chip8_destroy(chip8);
+        }
+    }
+}
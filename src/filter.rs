@@ -0,0 +1,268 @@
+use std::collections::VecDeque;
+use std::fmt;
+use crate::framebuffer::Framebuffer;
+use crate::palette::{Palette, Rgb};
+
+const DEFAULT_DECAY: f32 = 0.6;
+const DEFAULT_FRAMES: usize = 2;
+// Below this a decaying pixel is treated as dark
+const MIN_BRIGHTNESS: f32 = 0.05;
+
+/// How pixels linger after they're turned off, to hide the flicker of sprites being erased and redrawn
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Persistence {
+    #[default]
+    Off,
+    /// A pixel's brightness is multiplied by this factor every frame it's off, like CRT phosphor fading
+    Decay(f32),
+    /// A pixel stays lit if it was lit in any of the last n frames
+    FrameOr(usize)
+}
+
+impl Persistence {
+    /// Parses `off`, `decay`, `decay:0.7`, `or` or `or:3`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parts = text.splitn(2, ':');
+        let mode = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().map(str::trim);
+
+        match (mode.as_str(), value) {
+            ("off", None) => Ok(Persistence::Off),
+            ("decay", None) => Ok(Persistence::Decay(DEFAULT_DECAY)),
+            ("decay", Some(value)) => value
+                .parse()
+                .ok()
+                .filter(|decay: &f32| *decay > 0.0 && *decay < 1.0)
+                .map(Persistence::Decay)
+                .ok_or_else(|| format!("'{}' isn't a decay factor, expected a number between 0 and 1", value)),
+            ("or", None) => Ok(Persistence::FrameOr(DEFAULT_FRAMES)),
+            ("or", Some(value)) => value
+                .parse()
+                .ok()
+                .filter(|&frames| frames > 0)
+                .map(Persistence::FrameOr)
+                .ok_or_else(|| format!("'{}' isn't a frame count, expected a whole number above 0", value)),
+            _ => Err(format!("'{}' isn't a persistence mode, expected off, decay[:FACTOR] or or[:FRAMES]", text))
+        }
+    }
+
+    /// The mode after this one, for cycling through them with a hotkey
+    pub fn next(self) -> Self {
+        match self {
+            Persistence::Off => Persistence::Decay(DEFAULT_DECAY),
+            Persistence::Decay(_) => Persistence::FrameOr(DEFAULT_FRAMES),
+            Persistence::FrameOr(_) => Persistence::Off
+        }
+    }
+}
+
+impl fmt::Display for Persistence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Persistence::Off => write!(f, "off"),
+            Persistence::Decay(decay) => write!(f, "decay:{}", decay),
+            Persistence::FrameOr(frames) => write!(f, "or:{}", frames)
+        }
+    }
+}
+
+/// Blends the display over the last few frames. It's fed the framebuffer once per emulated frame and works out
+/// every pixel's brightness on the CPU, so it doesn't depend on what draws the result
+pub struct PhosphorFilter {
+    persistence: Persistence,
+    width: usize,
+    height: usize,
+    /// Brightness of each pixel, row by row, from 0 (dark) to 1 (lit)
+    brightness: Vec<f32>,
+    /// Color index each pixel last had while lit
+    colors: Vec<usize>,
+    /// The most recent frames, newest first, for `Persistence::FrameOr`
+    history: VecDeque<Framebuffer>
+}
+
+impl PhosphorFilter {
+    pub fn new(persistence: Persistence) -> Self {
+        PhosphorFilter {
+            persistence,
+            width: 0,
+            height: 0,
+            brightness: Vec::new(),
+            colors: Vec::new(),
+            history: VecDeque::new()
+        }
+    }
+
+    pub fn persistence(&self) -> Persistence {
+        self.persistence
+    }
+
+    pub fn set_persistence(&mut self, persistence: Persistence) {
+        self.persistence = persistence;
+        self.history.clear();
+    }
+
+    /// Takes in the display at the end of a frame. Returns whether anything on screen changed
+    pub fn update(&mut self, framebuffer: &Framebuffer) -> bool {
+        if framebuffer.width() != self.width || framebuffer.height() != self.height {
+            self.width = framebuffer.width();
+            self.height = framebuffer.height();
+            self.brightness = vec![0.0; self.width * self.height];
+            self.colors = vec![0; self.width * self.height];
+            self.history.clear();
+        }
+
+        if let Persistence::FrameOr(frames) = self.persistence {
+            self.history.push_front(framebuffer.clone());
+            self.history.truncate(frames);
+        }
+
+        let mut changed = false;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let index = y * self.width + x;
+                let (brightness, color) = self.next_pixel(framebuffer, x, y, index);
+                changed |= brightness != self.brightness[index] || (brightness > 0.0 && color != self.colors[index]);
+                self.brightness[index] = brightness;
+                self.colors[index] = color;
+            }
+        }
+
+        changed
+    }
+
+    fn next_pixel(&self, framebuffer: &Framebuffer, x: usize, y: usize, index: usize) -> (f32, usize) {
+        let color = framebuffer.color_index(x, y);
+        if color != 0 {
+            return (1.0, color);
+        }
+
+        match self.persistence {
+            Persistence::Off => (0.0, 0),
+            Persistence::Decay(decay) => {
+                let brightness = self.brightness[index] * decay;
+                if brightness < MIN_BRIGHTNESS { (0.0, 0) } else { (brightness, self.colors[index]) }
+            },
+            Persistence::FrameOr(_) => {
+                match self.history.iter().map(|frame| frame.color_index(x, y)).find(|&color| color != 0) {
+                    Some(color) => (1.0, color),
+                    None => (0.0, 0)
+                }
+            }
+        }
+    }
+
+    /// Pixels that aren't dark as (x, y, color index, brightness)
+    pub fn pixels(&self) -> impl Iterator<Item = (usize, usize, usize, f32)> + '_ {
+        self.brightness
+            .iter()
+            .enumerate()
+            .filter(|&(_, &brightness)| brightness > 0.0)
+            .map(move |(index, &brightness)| (index % self.width, index / self.width, self.colors[index], brightness))
+    }
+
+    /// Color of a pixel, its palette color faded towards the background by its brightness
+    pub fn shade(palette: &Palette, color: usize, brightness: f32) -> Rgb {
+        palette.background().blend(palette.color(color), brightness)
+    }
+
+    /// The filtered display as packed 24-bit RGB, row by row
+    pub fn render(&self, palette: &Palette) -> Vec<u8> {
+        let background = palette.background();
+        let mut rgb = Vec::with_capacity(self.width * self.height * 3);
+        for index in 0..self.width * self.height {
+            let color = if self.brightness[index] > 0.0 {
+                PhosphorFilter::shade(palette, self.colors[index], self.brightness[index])
+            } else {
+                background
+            };
+            rgb.extend_from_slice(&[color.r, color.g, color.b]);
+        }
+        rgb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame with one pixel lit in the given planes, or nothing lit
+    fn frame(color: usize) -> Framebuffer {
+        let mut framebuffer = Framebuffer::with_planes(64, 32, 2);
+        for plane in 0..2 {
+            framebuffer.set_pixel(plane, 3, 2, color & (1 << plane) != 0);
+        }
+        framebuffer
+    }
+
+    fn lit(filter: &PhosphorFilter) -> Vec<(usize, usize, usize, f32)> {
+        filter.pixels().collect()
+    }
+
+    #[test]
+    fn persistence_modes_parse() {
+        assert_eq!(Persistence::parse("off"), Ok(Persistence::Off));
+        assert_eq!(Persistence::parse(" Decay "), Ok(Persistence::Decay(DEFAULT_DECAY)));
+        assert_eq!(Persistence::parse("decay: 0.25"), Ok(Persistence::Decay(0.25)));
+        assert_eq!(Persistence::parse("or"), Ok(Persistence::FrameOr(DEFAULT_FRAMES)));
+        assert_eq!(Persistence::parse("or:4"), Ok(Persistence::FrameOr(4)));
+        assert_eq!(Persistence::parse(&Persistence::Decay(0.7).to_string()), Ok(Persistence::Decay(0.7)));
+
+        let factor = |value: &str| format!("'{}' isn't a decay factor, expected a number between 0 and 1", value);
+        assert_eq!(Persistence::parse("decay:1"), Err(factor("1")));
+        assert_eq!(Persistence::parse("decay:0"), Err(factor("0")));
+        assert_eq!(Persistence::parse("decay:fast"), Err(factor("fast")));
+        let frames = |value: &str| format!("'{}' isn't a frame count, expected a whole number above 0", value);
+        assert_eq!(Persistence::parse("or:0"), Err(frames("0")));
+        assert_eq!(Persistence::parse("or:-1"), Err(frames("-1")));
+        assert_eq!(
+            Persistence::parse("off:2"),
+            Err(String::from("'off:2' isn't a persistence mode, expected off, decay[:FACTOR] or or[:FRAMES]"))
+        );
+    }
+
+    #[test]
+    fn decaying_pixels_fade_until_they_are_too_dim_to_see() {
+        let mut filter = PhosphorFilter::new(Persistence::Decay(0.5));
+        assert!(filter.update(&frame(2)));
+        assert_eq!(lit(&filter), [(3, 2, 2, 1.0)]);
+
+        // 0.5, 0.25, 0.125 and 0.0625 are still above MIN_BRIGHTNESS, 0.03125 isn't
+        for brightness in &[0.5, 0.25, 0.125, 0.0625] {
+            assert!(filter.update(&frame(0)));
+            assert_eq!(lit(&filter), [(3, 2, 2, *brightness)]);
+        }
+        assert!(filter.update(&frame(0)));
+        assert_eq!(lit(&filter), []);
+        assert!(!filter.update(&frame(0)));
+    }
+
+    #[test]
+    fn frame_or_keeps_pixels_lit_for_the_window_only() {
+        let mut filter = PhosphorFilter::new(Persistence::FrameOr(3));
+        filter.update(&frame(1));
+        filter.update(&frame(3));
+        assert_eq!(lit(&filter), [(3, 2, 3, 1.0)]);
+
+        // The newest lit frame in the window gives the color
+        assert!(!filter.update(&frame(0)));
+        assert!(!filter.update(&frame(0)));
+        assert_eq!(lit(&filter), [(3, 2, 3, 1.0)]);
+        assert!(filter.update(&frame(0)));
+        assert_eq!(lit(&filter), []);
+
+        // Changing the mode forgets the old frames
+        filter.update(&frame(1));
+        filter.set_persistence(Persistence::FrameOr(3));
+        filter.update(&frame(0));
+        assert_eq!(lit(&filter), []);
+    }
+
+    #[test]
+    fn without_persistence_pixels_go_dark_at_once() {
+        let mut filter = PhosphorFilter::new(Persistence::Off);
+        filter.update(&frame(1));
+        assert!(filter.update(&frame(0)));
+        assert_eq!(lit(&filter), []);
+        assert_eq!(filter.render(&Palette::default())[..3], [0, 0, 0]);
+    }
+}
@@ -0,0 +1,282 @@
+pub const MAX_WIDTH: usize = 128;
+pub const MAX_HEIGHT: usize = 64;
+/// Four planes give 16 colors, as many as the largest palette has
+pub const MAX_PLANES: usize = 4;
+
+/// Rows are packed into a `u128` with the leftmost pixel in the most significant bit
+type Plane = [u128; MAX_HEIGHT];
+
+/// Monochrome display planes of up to 128x64 pixels. A pixel's color index has bit `n` set when it's lit in plane `n`
+#[derive(Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    planes: Vec<Plane>
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Framebuffer::with_planes(width, height, 1)
+    }
+
+    pub fn with_planes(width: usize, height: usize, plane_count: usize) -> Self {
+        assert!(width <= MAX_WIDTH && height <= MAX_HEIGHT, "framebuffer can't be larger than {}x{}", MAX_WIDTH, MAX_HEIGHT);
+        assert!(plane_count > 0 && plane_count <= MAX_PLANES, "framebuffer needs 1 to {} planes", MAX_PLANES);
+
+        Framebuffer {
+            width,
+            height,
+            planes: vec![[0; MAX_HEIGHT]; plane_count]
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn plane_count(&self) -> usize {
+        self.planes.len()
+    }
+
+    pub fn clear(&mut self) {
+        for plane in self.planes.iter_mut() {
+            *plane = [0; MAX_HEIGHT];
+        }
+    }
+
+    /// Whether the pixel is lit in any plane
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.color_index(x, y) != 0
+    }
+
+    pub fn color_index(&self, x: usize, y: usize) -> usize {
+        if x >= self.width || y >= self.height {
+            return 0;
+        }
+
+        self.planes
+            .iter()
+            .enumerate()
+            .fold(0, |index, (plane, rows)| index | (((rows[y] >> (MAX_WIDTH - 1 - x)) & 1) as usize) << plane)
+    }
+
+    pub fn set_pixel(&mut self, plane: usize, x: usize, y: usize, lit: bool) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+
+        let bit = 1u128 << (MAX_WIDTH - 1 - x);
+        if lit {
+            self.planes[plane][y] |= bit;
+        } else {
+            self.planes[plane][y] &= !bit;
+        }
+    }
+
+    /// The packed pixels of a row, leftmost pixel in the most significant bit
+    pub fn row(&self, plane: usize, y: usize) -> u128 {
+        self.planes[plane][y]
+    }
+
+    /// Replaces the packed pixels of a row, dropping any past the right edge
+    pub fn set_row(&mut self, plane: usize, y: usize, bits: u128) {
+        if y < self.height {
+            self.planes[plane][y] = bits & self.visible_mask();
+        }
+    }
+
+    /// XORs an 8 pixel wide sprite onto the first plane, clipping it at the edges. See `draw_sprite_on_plane`
+    pub fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
+        self.draw_sprite_on_plane(0, x, y, sprite, false)
+    }
+
+    /// XORs an 8 pixel wide sprite onto a plane with its top left corner at (x, y). Anything past the
+    /// right or bottom edge either wraps around to the opposite edge or is clipped.
+    /// Returns whether any lit pixel was turned off
+    pub fn draw_sprite_on_plane(&mut self, plane: usize, x: usize, y: usize, sprite: &[u8], wrap: bool) -> bool {
+        if x >= self.width || y >= self.height {
+            return false;
+        }
+
+        let visible = self.visible_mask();
+        let width = self.width;
+        let height = self.height;
+        let rows = &mut self.planes[plane];
+        let mut collision = false;
+
+        for (offset, &byte) in sprite.iter().enumerate() {
+            let mut row = y + offset;
+            if row >= height {
+                if !wrap {
+                    break;
+                }
+                row %= height;
+            }
+
+            let aligned = (byte as u128) << (MAX_WIDTH - 8);
+            let mut bits = aligned >> x;
+            if wrap && x + 8 > width {
+                bits |= aligned << (width - x);
+            }
+            bits &= visible;
+
+            collision |= rows[row] & bits != 0;
+            rows[row] ^= bits;
+        }
+
+        collision
+    }
+
+    pub fn scroll_up(&mut self, amount: usize) {
+        let height = self.height;
+        for rows in self.planes.iter_mut() {
+            let amount = amount.min(height);
+            rows.copy_within(amount..height, 0);
+            for row in rows[height - amount..height].iter_mut() {
+                *row = 0;
+            }
+        }
+    }
+
+    pub fn scroll_down(&mut self, amount: usize) {
+        let height = self.height;
+        for rows in self.planes.iter_mut() {
+            let amount = amount.min(height);
+            rows.copy_within(0..height - amount, amount);
+            for row in rows[..amount].iter_mut() {
+                *row = 0;
+            }
+        }
+    }
+
+    pub fn scroll_left(&mut self, amount: usize) {
+        let visible = self.visible_mask();
+        for row in self.planes.iter_mut().flat_map(|rows| rows.iter_mut()) {
+            *row = row.checked_shl(amount as u32).unwrap_or(0) & visible;
+        }
+    }
+
+    pub fn scroll_right(&mut self, amount: usize) {
+        let visible = self.visible_mask();
+        for row in self.planes.iter_mut().flat_map(|rows| rows.iter_mut()) {
+            *row = row.checked_shr(amount as u32).unwrap_or(0) & visible;
+        }
+    }
+
+    /// Every pixel as (x, y, color index), row by row
+    pub fn pixels(&self) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+        (0..self.height).flat_map(move |y| (0..self.width).map(move |x| (x, y, self.color_index(x, y))))
+    }
+
+    /// Coordinates of the pixels lit in any plane, skipping empty rows entirely
+    pub fn lit_pixels(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..self.height)
+            .filter(move |&y| self.planes.iter().any(|rows| rows[y] != 0))
+            .flat_map(move |y| (0..self.width).filter(move |&x| self.pixel(x, y)).map(move |x| (x, y)))
+    }
+
+    /// Bits of a row that fall inside the screen
+    fn visible_mask(&self) -> u128 {
+        !u128::MAX.checked_shr(self.width as u32).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lit(framebuffer: &Framebuffer) -> Vec<(usize, usize)> {
+        framebuffer.lit_pixels().collect()
+    }
+
+    #[test]
+    fn visible_mask_covers_the_width() {
+        assert_eq!(Framebuffer::new(64, 32).visible_mask(), u128::MAX << 64);
+        assert_eq!(Framebuffer::new(128, 64).visible_mask(), u128::MAX);
+    }
+
+    #[test]
+    fn sprites_clip_at_the_right_and_bottom_edges() {
+        let mut framebuffer = Framebuffer::new(64, 32);
+        framebuffer.draw_sprite_on_plane(0, 62, 30, &[0xFF, 0xFF, 0xFF], false);
+        assert_eq!(lit(&framebuffer), vec![(62, 30), (63, 30), (62, 31), (63, 31)]);
+        assert_eq!(framebuffer.row(0, 30), 0b11 << 64);
+    }
+
+    #[test]
+    fn sprites_wrap_to_the_opposite_edges() {
+        let mut framebuffer = Framebuffer::new(64, 32);
+        framebuffer.draw_sprite_on_plane(0, 62, 31, &[0b1110_0001, 0b1000_0000], true);
+        assert_eq!(lit(&framebuffer), vec![(62, 0), (0, 31), (5, 31), (62, 31), (63, 31)]);
+    }
+
+    #[test]
+    fn sprites_wrap_on_a_wide_screen() {
+        let mut framebuffer = Framebuffer::new(128, 64);
+        framebuffer.draw_sprite_on_plane(0, 124, 0, &[0xFF], true);
+        assert_eq!(lit(&framebuffer), vec![(0, 0), (1, 0), (2, 0), (3, 0), (124, 0), (125, 0), (126, 0), (127, 0)]);
+    }
+
+    #[test]
+    fn sprites_starting_off_screen_draw_nothing() {
+        let mut framebuffer = Framebuffer::new(64, 32);
+        assert!(!framebuffer.draw_sprite_on_plane(0, 64, 0, &[0xFF], true));
+        assert!(!framebuffer.draw_sprite_on_plane(0, 0, 32, &[0xFF], true));
+        assert!(lit(&framebuffer).is_empty());
+    }
+
+    #[test]
+    fn drawing_over_a_lit_pixel_reports_a_collision() {
+        let mut framebuffer = Framebuffer::new(64, 32);
+        assert!(!framebuffer.draw_sprite(10, 5, &[0b1100_0000]));
+        assert!(!framebuffer.draw_sprite(12, 5, &[0b1100_0000]));
+        assert!(framebuffer.draw_sprite(11, 5, &[0b1000_0000]));
+        assert_eq!(lit(&framebuffer), vec![(10, 5), (12, 5), (13, 5)]);
+
+        // Pixels clipped off the edge never collide
+        framebuffer.set_pixel(0, 0, 6, true);
+        assert!(!framebuffer.draw_sprite(60, 6, &[0xFF]));
+    }
+
+    #[test]
+    fn collisions_on_one_plane_ignore_the_others() {
+        let mut framebuffer = Framebuffer::with_planes(64, 32, 2);
+        framebuffer.draw_sprite_on_plane(0, 0, 0, &[0x80], false);
+        assert!(!framebuffer.draw_sprite_on_plane(1, 0, 0, &[0x80], false));
+        assert_eq!(framebuffer.color_index(0, 0), 0b11);
+    }
+
+    #[test]
+    fn scrolling_moves_pixels_and_drops_those_pushed_off() {
+        let mut framebuffer = Framebuffer::new(64, 32);
+        framebuffer.set_pixel(0, 0, 0, true);
+        framebuffer.set_pixel(0, 63, 31, true);
+
+        let mut up = framebuffer.clone();
+        up.scroll_up(1);
+        assert_eq!(lit(&up), vec![(63, 30)]);
+
+        let mut down = framebuffer.clone();
+        down.scroll_down(1);
+        assert_eq!(lit(&down), vec![(0, 1)]);
+
+        let mut left = framebuffer.clone();
+        left.scroll_left(4);
+        assert_eq!(lit(&left), vec![(59, 31)]);
+        assert_eq!(left.row(0, 0), 0);
+
+        let mut right = framebuffer.clone();
+        right.scroll_right(4);
+        assert_eq!(lit(&right), vec![(4, 0)]);
+        assert_eq!(right.row(0, 31) & !right.visible_mask(), 0);
+
+        let mut cleared = framebuffer.clone();
+        cleared.scroll_down(40);
+        cleared.scroll_left(200);
+        assert!(lit(&cleared).is_empty());
+    }
+}
@@ -0,0 +1,248 @@
+use std::fmt;
+
+/// A decoded CHIP-8 instruction. Register operands are stored as their index (0x0 - 0xF)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// 00E0 - CLS
+    Cls,
+    /// 00EE - RET
+    Ret,
+    /// 1nnn - JP addr
+    Jp(u16),
+    /// 2nnn - CALL addr
+    Call(u16),
+    /// 3xkk - SE Vx, byte
+    SeByte { x: u8, kk: u8 },
+    /// 4xkk - SNE Vx, byte
+    SneByte { x: u8, kk: u8 },
+    /// 5xy0 - SE Vx, Vy
+    SeRegister { x: u8, y: u8 },
+    /// 6xkk - LD Vx, byte
+    LdByte { x: u8, kk: u8 },
+    /// 7xkk - ADD Vx, byte
+    AddByte { x: u8, kk: u8 },
+    /// 8xy0 - LD Vx, Vy
+    LdRegister { x: u8, y: u8 },
+    /// 8xy1 - OR Vx, Vy
+    Or { x: u8, y: u8 },
+    /// 8xy2 - AND Vx, Vy
+    And { x: u8, y: u8 },
+    /// 8xy3 - XOR Vx, Vy
+    Xor { x: u8, y: u8 },
+    /// 8xy4 - ADD Vx, Vy
+    AddRegister { x: u8, y: u8 },
+    /// 8xy5 - SUB Vx, Vy
+    Sub { x: u8, y: u8 },
+    /// 8xy6 - SHR Vx {, Vy}
+    Shr { x: u8, y: u8 },
+    /// 8xy7 - SUBN Vx, Vy
+    Subn { x: u8, y: u8 },
+    /// 8xyE - SHL Vx {, Vy}
+    Shl { x: u8, y: u8 },
+    /// 9xy0 - SNE Vx, Vy
+    SneRegister { x: u8, y: u8 },
+    /// Annn - LD I, addr
+    LdIndex(u16),
+    /// Bnnn - JP V0, addr
+    JpOffset(u16),
+    /// Cxkk - RND Vx, byte
+    Rnd { x: u8, kk: u8 },
+    /// Dxyn - DRW Vx, Vy, nibble
+    Drw { x: u8, y: u8, n: u8 },
+    /// Ex9E - SKP Vx
+    Skp { x: u8 },
+    /// ExA1 - SKNP Vx
+    Sknp { x: u8 },
+    /// Fx07 - LD Vx, DT
+    LdFromDelay { x: u8 },
+    /// Fx0A - LD Vx, K
+    LdKey { x: u8 },
+    /// Fx15 - LD DT, Vx
+    LdDelay { x: u8 },
+    /// Fx18 - LD ST, Vx
+    LdSound { x: u8 },
+    /// Fx1E - ADD I, Vx
+    AddIndex { x: u8 },
+    /// Fx29 - LD F, Vx
+    LdFont { x: u8 },
+    /// Fx33 - LD B, Vx
+    LdBcd { x: u8 },
+    /// Fx55 - LD [I], Vx
+    LdStore { x: u8 },
+    /// Fx65 - LD Vx, [I]
+    LdLoad { x: u8 },
+    /// Any opcode that isn't a CHIP-8 instruction. Executing it does nothing. That includes 5xyn and 9xyn
+    /// with n other than 0, which the original decoder ran as SE and SNE
+    Unknown(u16)
+}
+
+impl Instruction {
+    /// Only the exact opcodes of the instructions above decode to them, so 5xy1 is `Unknown` rather than SE
+    /// Vx, Vy. That keeps `encode` exact, and leaves room for extensions that use those opcodes
+    pub fn decode(opcode: u16) -> Self {
+        let nnn = opcode & 0x0FFF;
+        let kk = (opcode & 0x00FF) as u8;
+        let n = (opcode & 0x000F) as u8;
+        let x = ((opcode & 0x0F00) >> 8) as u8;
+        let y = ((opcode & 0x00F0) >> 4) as u8;
+
+        match (opcode & 0xF000) >> 12 {
+            0x0 if opcode == 0x00E0 => Instruction::Cls,
+            0x0 if opcode == 0x00EE => Instruction::Ret,
+            0x1 => Instruction::Jp(nnn),
+            0x2 => Instruction::Call(nnn),
+            0x3 => Instruction::SeByte { x, kk },
+            0x4 => Instruction::SneByte { x, kk },
+            0x5 if n == 0x0 => Instruction::SeRegister { x, y },
+            0x6 => Instruction::LdByte { x, kk },
+            0x7 => Instruction::AddByte { x, kk },
+            0x8 => match n {
+                0x0 => Instruction::LdRegister { x, y },
+                0x1 => Instruction::Or { x, y },
+                0x2 => Instruction::And { x, y },
+                0x3 => Instruction::Xor { x, y },
+                0x4 => Instruction::AddRegister { x, y },
+                0x5 => Instruction::Sub { x, y },
+                0x6 => Instruction::Shr { x, y },
+                0x7 => Instruction::Subn { x, y },
+                0xE => Instruction::Shl { x, y },
+                _ => Instruction::Unknown(opcode)
+            },
+            0x9 if n == 0x0 => Instruction::SneRegister { x, y },
+            0xA => Instruction::LdIndex(nnn),
+            0xB => Instruction::JpOffset(nnn),
+            0xC => Instruction::Rnd { x, kk },
+            0xD => Instruction::Drw { x, y, n },
+            0xE => match kk {
+                0x9E => Instruction::Skp { x },
+                0xA1 => Instruction::Sknp { x },
+                _ => Instruction::Unknown(opcode)
+            },
+            0xF => match kk {
+                0x07 => Instruction::LdFromDelay { x },
+                0x0A => Instruction::LdKey { x },
+                0x15 => Instruction::LdDelay { x },
+                0x18 => Instruction::LdSound { x },
+                0x1E => Instruction::AddIndex { x },
+                0x29 => Instruction::LdFont { x },
+                0x33 => Instruction::LdBcd { x },
+                0x55 => Instruction::LdStore { x },
+                0x65 => Instruction::LdLoad { x },
+                _ => Instruction::Unknown(opcode)
+            },
+            _ => Instruction::Unknown(opcode)
+        }
+    }
+
+    /// Turns the instruction back into its opcode. `decode` followed by `encode` always returns the original opcode
+    pub fn encode(&self) -> u16 {
+        let xkk = |prefix: u16, x: u8, kk: u8| prefix << 12 | (x as u16) << 8 | kk as u16;
+        let xyn = |prefix: u16, x: u8, y: u8, n: u8| prefix << 12 | (x as u16) << 8 | (y as u16) << 4 | n as u16;
+
+        match *self {
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::Jp(address) => 0x1000 | address,
+            Instruction::Call(address) => 0x2000 | address,
+            Instruction::SeByte { x, kk } => xkk(0x3, x, kk),
+            Instruction::SneByte { x, kk } => xkk(0x4, x, kk),
+            Instruction::SeRegister { x, y } => xyn(0x5, x, y, 0x0),
+            Instruction::LdByte { x, kk } => xkk(0x6, x, kk),
+            Instruction::AddByte { x, kk } => xkk(0x7, x, kk),
+            Instruction::LdRegister { x, y } => xyn(0x8, x, y, 0x0),
+            Instruction::Or { x, y } => xyn(0x8, x, y, 0x1),
+            Instruction::And { x, y } => xyn(0x8, x, y, 0x2),
+            Instruction::Xor { x, y } => xyn(0x8, x, y, 0x3),
+            Instruction::AddRegister { x, y } => xyn(0x8, x, y, 0x4),
+            Instruction::Sub { x, y } => xyn(0x8, x, y, 0x5),
+            Instruction::Shr { x, y } => xyn(0x8, x, y, 0x6),
+            Instruction::Subn { x, y } => xyn(0x8, x, y, 0x7),
+            Instruction::Shl { x, y } => xyn(0x8, x, y, 0xE),
+            Instruction::SneRegister { x, y } => xyn(0x9, x, y, 0x0),
+            Instruction::LdIndex(address) => 0xA000 | address,
+            Instruction::JpOffset(address) => 0xB000 | address,
+            Instruction::Rnd { x, kk } => xkk(0xC, x, kk),
+            Instruction::Drw { x, y, n } => xyn(0xD, x, y, n),
+            Instruction::Skp { x } => xkk(0xE, x, 0x9E),
+            Instruction::Sknp { x } => xkk(0xE, x, 0xA1),
+            Instruction::LdFromDelay { x } => xkk(0xF, x, 0x07),
+            Instruction::LdKey { x } => xkk(0xF, x, 0x0A),
+            Instruction::LdDelay { x } => xkk(0xF, x, 0x15),
+            Instruction::LdSound { x } => xkk(0xF, x, 0x18),
+            Instruction::AddIndex { x } => xkk(0xF, x, 0x1E),
+            Instruction::LdFont { x } => xkk(0xF, x, 0x29),
+            Instruction::LdBcd { x } => xkk(0xF, x, 0x33),
+            Instruction::LdStore { x } => xkk(0xF, x, 0x55),
+            Instruction::LdLoad { x } => xkk(0xF, x, 0x65),
+            Instruction::Unknown(opcode) => opcode
+        }
+    }
+
+    /// Whether the instruction writes to memory, which can change the code that runs after it
+    pub fn writes_memory(&self) -> bool {
+        matches!(self, Instruction::LdBcd { .. } | Instruction::LdStore { .. })
+    }
+}
+
+/// Formats the instruction in the usual assembler syntax, e.g. `DRW V1, V2, 5`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Jp(address) => write!(f, "JP {:#05X}", address),
+            Instruction::Call(address) => write!(f, "CALL {:#05X}", address),
+            Instruction::SeByte { x, kk } => write!(f, "SE V{:X}, {:#04X}", x, kk),
+            Instruction::SneByte { x, kk } => write!(f, "SNE V{:X}, {:#04X}", x, kk),
+            Instruction::SeRegister { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::LdByte { x, kk } => write!(f, "LD V{:X}, {:#04X}", x, kk),
+            Instruction::AddByte { x, kk } => write!(f, "ADD V{:X}, {:#04X}", x, kk),
+            Instruction::LdRegister { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddRegister { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneRegister { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdIndex(address) => write!(f, "LD I, {:#05X}", address),
+            Instruction::JpOffset(address) => write!(f, "JP V0, {:#05X}", address),
+            Instruction::Rnd { x, kk } => write!(f, "RND V{:X}, {:#04X}", x, kk),
+            Instruction::Drw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp { x } => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp { x } => write!(f, "SKNP V{:X}", x),
+            Instruction::LdFromDelay { x } => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdKey { x } => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDelay { x } => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdSound { x } => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddIndex { x } => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdFont { x } => write!(f, "LD F, V{:X}", x),
+            Instruction::LdBcd { x } => write!(f, "LD B, V{:X}", x),
+            Instruction::LdStore { x } => write!(f, "LD [I], V{:X}", x),
+            Instruction::LdLoad { x } => write!(f, "LD V{:X}, [I]", x),
+            Instruction::Unknown(opcode) => write!(f, "DW {:#06X}", opcode)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Instruction;
+
+    #[test]
+    fn encode_reverses_decode() {
+        for opcode in 0..=0xFFFF {
+            assert_eq!(Instruction::decode(opcode).encode(), opcode, "{:#06X}", opcode);
+        }
+    }
+
+    #[test]
+    fn register_comparisons_need_a_zero_nibble() {
+        assert_eq!(Instruction::decode(0x5120), Instruction::SeRegister { x: 1, y: 2 });
+        assert_eq!(Instruction::decode(0x5122), Instruction::Unknown(0x5122));
+        assert_eq!(Instruction::decode(0x9120), Instruction::SneRegister { x: 1, y: 2 });
+        assert_eq!(Instruction::decode(0x9123), Instruction::Unknown(0x9123));
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use ggez::event::KeyCode;
+
+/// Every key that can be named in a keymap file
+const KEY_NAMES: [KeyCode; 73] = [
+    KeyCode::Key0, KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4,
+    KeyCode::Key5, KeyCode::Key6, KeyCode::Key7, KeyCode::Key8, KeyCode::Key9,
+    KeyCode::A, KeyCode::B, KeyCode::C, KeyCode::D, KeyCode::E, KeyCode::F, KeyCode::G,
+    KeyCode::H, KeyCode::I, KeyCode::J, KeyCode::K, KeyCode::L, KeyCode::M, KeyCode::N,
+    KeyCode::O, KeyCode::P, KeyCode::Q, KeyCode::R, KeyCode::S, KeyCode::T, KeyCode::U,
+    KeyCode::V, KeyCode::W, KeyCode::X, KeyCode::Y, KeyCode::Z,
+    KeyCode::Numpad0, KeyCode::Numpad1, KeyCode::Numpad2, KeyCode::Numpad3, KeyCode::Numpad4,
+    KeyCode::Numpad5, KeyCode::Numpad6, KeyCode::Numpad7, KeyCode::Numpad8, KeyCode::Numpad9,
+    KeyCode::Up, KeyCode::Down, KeyCode::Left, KeyCode::Right,
+    KeyCode::Space, KeyCode::Return, KeyCode::Back, KeyCode::Escape,
+    KeyCode::LShift, KeyCode::RShift, KeyCode::LControl, KeyCode::RControl, KeyCode::LAlt, KeyCode::RAlt,
+    KeyCode::Comma, KeyCode::Period, KeyCode::Slash, KeyCode::Semicolon, KeyCode::Apostrophe,
+    KeyCode::LBracket, KeyCode::RBracket, KeyCode::Backslash, KeyCode::Grave,
+    KeyCode::Add, KeyCode::Subtract, KeyCode::Multiply, KeyCode::Divide
+];
+
+/// Which host key presses which CHIP-8 key
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    keys: HashMap<KeyCode, usize>
+}
+
+/// The usual layout on the left side of a QWERTY keyboard:
+/// ```text
+/// 1 2 3 4        1 2 3 C
+/// Q W E R   ->   4 5 6 D
+/// A S D F        7 8 9 E
+/// Z X C V        A 0 B F
+/// ```
+impl Default for Keymap {
+    fn default() -> Self {
+        let layout = [
+            (KeyCode::X, 0x0), (KeyCode::Key1, 0x1), (KeyCode::Key2, 0x2), (KeyCode::Key3, 0x3),
+            (KeyCode::Q, 0x4), (KeyCode::W, 0x5), (KeyCode::E, 0x6), (KeyCode::A, 0x7),
+            (KeyCode::S, 0x8), (KeyCode::D, 0x9), (KeyCode::Z, 0xA), (KeyCode::C, 0xB),
+            (KeyCode::Key4, 0xC), (KeyCode::R, 0xD), (KeyCode::F, 0xE), (KeyCode::V, 0xF)
+        ];
+
+        Keymap {
+            keys: layout.iter().cloned().collect()
+        }
+    }
+}
+
+impl Keymap {
+    /// Parses one `<chip-8 key> = <host key>` mapping per line, e.g. `5 = Up`. The CHIP-8 key is a hex digit
+    /// and the host key is a name like `Key1`, `Q`, `Numpad5` or `Space`. Blank lines and `#` comments are skipped.
+    /// Several host keys can press the same CHIP-8 key
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut keys = HashMap::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let chip8_key = parts.next().unwrap_or("").trim();
+            let host_key = parts.next().ok_or_else(|| format!("line {}: expected <chip-8 key> = <host key>", number + 1))?.trim();
+
+            let (host_key, chip8_key) = parse_mapping(chip8_key, host_key).map_err(|error| format!("line {}: {}", number + 1, error))?;
+            keys.insert(host_key, chip8_key);
+        }
+
+        Ok(Keymap { keys })
+    }
+
+    /// Builds a keymap from (host key name, CHIP-8 key) pairs, named the same way as in `parse`
+    pub fn from_pairs<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<Self, String> {
+        let mut keys = HashMap::new();
+        for (host_key, chip8_key) in pairs {
+            let (host_key, chip8_key) = parse_mapping(chip8_key, host_key)?;
+            keys.insert(host_key, chip8_key);
+        }
+
+        Ok(Keymap { keys })
+    }
+
+    /// Maps a host key to a CHIP-8 key unless it's already mapped
+    pub fn insert_missing(&mut self, keycode: KeyCode, key: usize) {
+        self.keys.entry(keycode).or_insert(key);
+    }
+
+    /// Makes a host key the only one pressing a CHIP-8 key, returning the CHIP-8 key it pressed before if it
+    /// was mapped to a different one
+    pub fn rebind(&mut self, key: usize, keycode: KeyCode) -> Option<usize> {
+        let previous = self.get(keycode).filter(|&previous| previous != key);
+        self.keys.retain(|_, &mut mapped| mapped != key);
+        self.keys.insert(keycode, key);
+        previous
+    }
+
+    /// Names of the host keys mapped to a CHIP-8 key, in alphabetical order
+    pub fn host_keys(&self, key: usize) -> Vec<String> {
+        let mut names: Vec<String> = self.keys.iter().filter(|&(_, &mapped)| mapped == key).map(|(&keycode, _)| key_name(keycode)).collect();
+        names.sort();
+        names
+    }
+
+    /// The CHIP-8 key a host key is mapped to
+    pub fn get(&self, keycode: KeyCode) -> Option<usize> {
+        self.keys.get(&keycode).cloned()
+    }
+
+    /// Mappings as (CHIP-8 key, host key name), ordered by CHIP-8 key
+    pub fn entries(&self) -> Vec<(usize, String)> {
+        let mut entries: Vec<(usize, String)> = self.keys.iter().map(|(&keycode, &key)| (key, key_name(keycode))).collect();
+        entries.sort();
+        entries
+    }
+}
+
+fn parse_mapping(chip8_key: &str, host_key: &str) -> Result<(KeyCode, usize), String> {
+    let chip8_key = usize::from_str_radix(chip8_key.trim(), 16)
+        .ok()
+        .filter(|&key| key < 16)
+        .ok_or_else(|| format!("'{}' isn't a CHIP-8 key, expected 0-F", chip8_key))?;
+    let host_key = key_from_name(host_key.trim()).ok_or_else(|| format!("unknown key '{}'", host_key))?;
+
+    Ok((host_key, chip8_key))
+}
+
+pub fn key_name(keycode: KeyCode) -> String {
+    format!("{:?}", keycode)
+}
+
+/// Whether a key has a name a keymap file can use
+pub fn is_nameable(keycode: KeyCode) -> bool {
+    KEY_NAMES.contains(&keycode)
+}
+
+pub fn key_from_name(name: &str) -> Option<KeyCode> {
+    KEY_NAMES.iter().cloned().find(|&keycode| key_name(keycode).eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebinding_replaces_the_old_keys_and_moves_the_new_one() {
+        let mut keymap = Keymap::parse("5 = W\n5 = Up\n6 = E").expect("The keymap is valid");
+        assert_eq!(keymap.rebind(5, KeyCode::Space), None);
+        assert_eq!(keymap.host_keys(5), vec!["Space"]);
+        assert_eq!(keymap.get(KeyCode::W), None);
+
+        assert_eq!(keymap.rebind(5, KeyCode::E), Some(6));
+        assert_eq!(keymap.host_keys(5), vec!["E"]);
+        assert!(keymap.host_keys(6).is_empty());
+    }
+}
@@ -0,0 +1,33 @@
+pub mod analysis;
+pub mod audio;
+pub mod capture;
+pub mod cheats;
+pub mod config;
+#[cfg(feature = "frontend")]
+pub mod console;
+pub mod coverage;
+pub mod cpu;
+pub mod database;
+#[cfg(feature = "frontend")]
+pub mod display;
+pub mod environment;
+pub mod ffi;
+pub mod filter;
+pub mod framebuffer;
+pub mod instruction;
+#[cfg(feature = "frontend")]
+pub mod keymap;
+#[cfg(feature = "frontend")]
+pub mod memory_viewer;
+pub mod palette;
+pub mod profiler;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod quirks;
+pub mod script;
+#[cfg(feature = "frontend")]
+pub mod settings_menu;
+#[cfg(feature = "frontend")]
+pub mod sprite_viewer;
+pub mod sprites;
+pub mod symbols;
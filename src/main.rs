@@ -1,24 +1,791 @@
-use std::env;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use clap::{Args, Parser, Subcommand};
 use ggez::*;
+use learn_rust::{capture, config, cpu, display};
+use learn_rust::analysis::Analysis;
+use learn_rust::audio::{Pattern, WavRecorder};
+use learn_rust::cheats::{Cheat, Cheats};
+use learn_rust::coverage::Coverage;
+use learn_rust::config::{Config, Settings};
+use learn_rust::cpu::{Backend, Cpu};
+use learn_rust::database::{self, Database, RomInfo};
+use learn_rust::filter::{Persistence, PhosphorFilter};
+use learn_rust::instruction::Instruction;
+use learn_rust::keymap::{self, Keymap};
+use learn_rust::palette::Palette;
+use learn_rust::profiler::Profiler;
+use learn_rust::quirks::{self, Quirks};
+use learn_rust::script::Script;
+use learn_rust::sprites::Sprite;
+use learn_rust::symbols::Symbols;
 
-mod cpu;
-mod display;
+const DEFAULT_CLOCK_SPEED: u32 = cpu::DEFAULT_INSTRUCTIONS_PER_FRAME as u32 * cpu::FRAMES_PER_SECOND;
+const DEFAULT_HEADLESS_FRAMES: usize = 600;
+const DEFAULT_CAPTURE_SCALE: u32 = 4;
+
+/// A CHIP-8 emulator
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a ROM in a window
+    Run(RunArgs),
+    /// Print the disassembly of a ROM
+    Disasm(DisasmArgs),
+    /// Print details about a ROM
+    Info(RomArgs),
+    /// Walk a ROM's control flow without running it and report anything suspicious
+    Analyze(AnalyzeArgs),
+    /// Run a ROM without a window, then print the screen and registers
+    Headless(HeadlessArgs),
+    /// Print which instructions ran, from coverage collected by `headless --coverage`
+    Coverage(CoverageArgs),
+    /// Run a ROM without a window and print where its instructions went, by subroutine and loop
+    Profile(ProfileArgs),
+    /// Print the sprites a ROM draws, or the bytes at an address decoded as a sprite
+    Sprites(SpritesArgs)
+}
+
+#[derive(Args)]
+struct RomArgs {
+    /// Path to the ROM file
+    rom: PathBuf
+}
+
+#[derive(Args)]
+struct SymbolArgs {
+    /// Label names for addresses, as JSON or `address name` lines [default: the ROM's path with a .sym extension, if it exists]
+    #[arg(long)]
+    symbols: Option<PathBuf>
+}
+
+/// Options for how the emulated machine behaves. Those left out come from the config file and the ROM database,
+/// then the built-in defaults
+#[derive(Args)]
+struct MachineArgs {
+    /// Instructions executed per second [default: 600]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    clock: Option<u32>,
+    /// Quirk profile to emulate: default, chip8, chip48 or schip [default: default]
+    #[arg(long, value_parser = parse_quirks)]
+    quirks: Option<Quirks>,
+    /// Seed for the random number generator, random when left out
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Address the ROM is loaded at and started from
+    #[arg(long, default_value = "0x200", value_parser = parse_address)]
+    start_address: u16,
+    /// How instructions are executed: interpreter or recompiler
+    #[arg(long, default_value = "interpreter", value_parser = parse_backend)]
+    backend: Backend,
+    /// Ignore the config file
+    #[arg(long)]
+    no_config: bool,
+    /// Don't apply the settings the ROM database recommends
+    #[arg(long)]
+    no_database: bool
+}
+
+#[derive(Args)]
+struct RunArgs {
+    #[command(flatten)]
+    rom: RomArgs,
+    #[command(flatten)]
+    machine: MachineArgs,
+    #[command(flatten)]
+    symbols: SymbolArgs,
+    /// Pause before running the instruction at this label or address, which can be given more than once
+    #[arg(long = "break", value_name = "LABEL")]
+    breakpoints: Vec<String>,
+    /// Rhai script to run alongside the ROM
+    #[arg(long)]
+    script: Option<PathBuf>,
+    /// Size of a CHIP-8 pixel on screen [default: 20]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    scale: Option<u32>,
+    /// Display colors: a preset like amber, green, lcd or octo, FOREGROUND,BACKGROUND like FFB000,282828,
+    /// or 4 or 16 colors for multiple planes starting with the background
+    #[arg(long, value_parser = Palette::parse)]
+    palette: Option<Palette>,
+    /// File with one `<chip-8 key> = <host key>` mapping per line, replacing the default and recommended keys
+    #[arg(long)]
+    keymap: Option<PathBuf>,
+    /// Let pixels linger to hide flicker: off, decay[:FACTOR] or or[:FRAMES]
+    #[arg(long, default_value = "off", value_parser = Persistence::parse)]
+    persistence: Persistence,
+    /// Start in fullscreen
+    #[arg(long)]
+    fullscreen: bool
+}
+
+#[derive(Args)]
+struct DisasmArgs {
+    #[command(flatten)]
+    rom: RomArgs,
+    #[command(flatten)]
+    symbols: SymbolArgs,
+    /// Address the ROM is loaded at
+    #[arg(long, default_value = "0x200", value_parser = parse_address)]
+    start_address: u16
+}
+
+#[derive(Args)]
+struct AnalyzeArgs {
+    #[command(flatten)]
+    rom: RomArgs,
+    /// Address the ROM is loaded at and started from
+    #[arg(long, default_value = "0x200", value_parser = parse_address)]
+    start_address: u16,
+    /// Quirk profile the ROM runs under, which decides how stores move I [default: the ROM database's recommendation, then default]
+    #[arg(long, value_parser = parse_quirks)]
+    quirks: Option<Quirks>,
+    /// Write the control-flow graph of basic blocks to this file as Graphviz DOT
+    #[arg(long)]
+    dot: Option<PathBuf>,
+    /// Write the call graph to this file as Graphviz DOT
+    #[arg(long)]
+    call_graph: Option<PathBuf>
+}
+
+#[derive(Args)]
+struct HeadlessArgs {
+    #[command(flatten)]
+    rom: RomArgs,
+    #[command(flatten)]
+    machine: MachineArgs,
+    #[command(flatten)]
+    symbols: SymbolArgs,
+    /// Number of 60Hz frames to run
+    #[arg(long, default_value_t = DEFAULT_HEADLESS_FRAMES)]
+    frames: usize,
+    /// Stop before running the instruction at this label or address, which can be given more than once
+    #[arg(long = "break", value_name = "LABEL")]
+    breakpoints: Vec<String>,
+    /// Print every instruction as it runs
+    #[arg(long, conflicts_with = "script")]
+    trace: bool,
+    /// Rhai script to run alongside the ROM. The run ends early if it calls stop()
+    #[arg(long)]
+    script: Option<PathBuf>,
+    /// Hold an address, label or register at a value every frame, like 0x2F0=5 or V3=9, on top of any saved
+    /// for the ROM. Can be given more than once
+    #[arg(long = "cheat", value_name = "TARGET=VALUE")]
+    cheats: Vec<String>,
+    /// Let pixels linger to hide flicker: off, decay[:FACTOR] or or[:FRAMES]. Faded pixels print as + and -
+    #[arg(long, default_value = "off", value_parser = Persistence::parse)]
+    persistence: Persistence,
+    /// Save the screen as a PNG once the last frame has run
+    #[arg(long)]
+    screenshot: Option<PathBuf>,
+    /// Record every frame to a GIF, or to raw RGB frames for ffmpeg with any other extension
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// Record the sound to a WAV file, in step with the emulated frames
+    #[arg(long)]
+    audio: Option<PathBuf>,
+    /// Collect which instructions run and which bytes are read and written, adding to this file if it exists
+    #[arg(long)]
+    coverage: Option<PathBuf>,
+    /// Size of a CHIP-8 pixel in screenshots and recordings
+    #[arg(long, default_value_t = DEFAULT_CAPTURE_SCALE, value_parser = clap::value_parser!(u32).range(1..))]
+    scale: u32,
+    /// Colors for screenshots and recordings, as for `run`
+    #[arg(long, value_parser = Palette::parse)]
+    palette: Option<Palette>
+}
+
+#[derive(Args)]
+struct CoverageArgs {
+    #[command(flatten)]
+    rom: RomArgs,
+    /// Coverage files from `headless --coverage`, added together
+    #[arg(required = true)]
+    data: Vec<PathBuf>,
+    /// Address the ROM was loaded at
+    #[arg(long, default_value = "0x200", value_parser = parse_address)]
+    start_address: u16,
+    /// Also write an lcov tracefile whose line numbers match the output of `disasm`
+    #[arg(long)]
+    lcov: Option<PathBuf>
+}
+
+#[derive(Args)]
+struct ProfileArgs {
+    #[command(flatten)]
+    rom: RomArgs,
+    #[command(flatten)]
+    machine: MachineArgs,
+    #[command(flatten)]
+    symbols: SymbolArgs,
+    /// Number of 60Hz frames to run
+    #[arg(long, default_value_t = DEFAULT_HEADLESS_FRAMES)]
+    frames: usize,
+    /// Write the instruction counts per call stack to this file in the folded format flamegraph tools read
+    #[arg(long)]
+    folded: Option<PathBuf>,
+    /// Number of loops to list
+    #[arg(long, default_value_t = 10)]
+    top: usize
+}
+
+#[derive(Args)]
+struct SpritesArgs {
+    #[command(flatten)]
+    rom: RomArgs,
+    #[command(flatten)]
+    machine: MachineArgs,
+    /// Number of 60Hz frames to run while collecting the sprites that get drawn
+    #[arg(long, default_value_t = DEFAULT_HEADLESS_FRAMES)]
+    frames: usize,
+    /// Decode the bytes at this address instead of running the ROM
+    #[arg(long, value_parser = parse_address)]
+    address: Option<u16>,
+    /// Rows to decode at --address
+    #[arg(long, default_value_t = 15, value_parser = clap::value_parser!(u32).range(1..=256))]
+    rows: u32,
+    /// Decode --address as a 16x16 SCHIP sprite
+    #[arg(long)]
+    large: bool,
+    /// Directory to save each sprite to as a PNG
+    #[arg(long)]
+    export: Option<PathBuf>,
+    /// Size of a sprite pixel in exported PNGs
+    #[arg(long, default_value_t = DEFAULT_CAPTURE_SCALE, value_parser = clap::value_parser!(u32).range(1..))]
+    scale: u32,
+    /// Colors for exported PNGs, as for `run`
+    #[arg(long, value_parser = Palette::parse)]
+    palette: Option<Palette>
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let bytes = fs::read(&args[1]).expect("Cannot find file");
-    let mut c = cpu::Cpu::new();
-    c.load_rom(bytes);
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Run(args) => run(args),
+        Command::Disasm(args) => disassemble(args),
+        Command::Info(args) => info(args),
+        Command::Analyze(args) => analyze(args),
+        Command::Headless(args) => headless(args),
+        Command::Coverage(args) => coverage(args),
+        Command::Profile(args) => profile(args),
+        Command::Sprites(args) => sprites(args)
+    };
+
+    if let Err(message) = result {
+        eprintln!("error: {}", message);
+        process::exit(1);
+    }
+}
+
+fn run(args: RunArgs) -> Result<(), String> {
+    let session = create_session(&args.rom, &args.machine)?;
+    let settings = &session.settings;
+    let mut emulator = display::Emulator::new(session.cpu);
+    emulator.instructions_per_frame = instructions_per_frame(session.clock);
+    emulator.filter.set_persistence(args.persistence);
+    emulator.pixel_size = args.scale.or(settings.scale).unwrap_or(display::DEFAULT_PIXEL_SIZE).max(1);
+
+    if let Some(palette) = args.palette.or(settings.parse_palette().map_err(config_error)?) {
+        emulator.palette = palette;
+    }
+
+    if let Some(path) = &args.keymap {
+        let text = fs::read_to_string(path).map_err(|error| format!("can't read {}: {}", path.display(), error))?;
+        emulator.keymap = Keymap::parse(&text).map_err(|error| format!("{}: {}", path.display(), error))?;
+    } else {
+        if let Some(keymap) = settings.parse_keymap().map_err(config_error)? {
+            emulator.keymap = keymap;
+        }
+        for &(host_key, key) in session.info.iter().flat_map(|info| info.keys.iter()) {
+            if let Some(keycode) = keymap::key_from_name(host_key) {
+                emulator.keymap.insert_missing(keycode, key);
+            }
+        }
+    }
 
+    emulator.symbols = load_symbols(&args.rom, &args.symbols)?;
+    set_breakpoints(&mut emulator.cpu, &emulator.symbols, &args.breakpoints)?;
+    if let Some(path) = &args.script {
+        emulator.script = Some(Script::load(path, &mut emulator.cpu, &emulator.symbols)?);
+    }
+    emulator.cheats = saved_cheats(settings, &emulator.symbols)?;
+    emulator.config = session.config;
+    emulator.rom_hash = Some(session.rom_hash);
+
+    let (width, height) = emulator.window_size();
     let mut conf = conf::Conf::new();
-    conf.window_setup = conf::WindowSetup::default().title("Chip8");
-    conf.window_mode = conf::WindowMode::default().dimensions(display::WINDOW_WIDTH as f32, display::WINDOW_HEIGHT as f32);
+    let title = match &session.info {
+        Some(info) => format!("Chip8 - {}", info.title),
+        None => String::from("Chip8")
+    };
+    conf.window_setup = conf::WindowSetup::default().title(&title);
+    conf.window_mode = conf::WindowMode::default().dimensions(width, height);
+    if args.fullscreen {
+        conf.window_mode = conf.window_mode.fullscreen_type(conf::FullscreenType::Desktop);
+    }
 
     let (ref mut ctx, ref mut event_loop) = ContextBuilder::new("Chip8", "caklimas@gmail.com")
         .conf(conf)
         .build()
-        .expect("Error building context");
+        .map_err(|error| format!("can't create the window: {}", error))?;
+
+    event::run(ctx, event_loop, &mut emulator).map_err(|error| format!("emulator stopped: {}", error))
+}
+
+fn disassemble(args: DisasmArgs) -> Result<(), String> {
+    let bytes = read_rom(&args.rom.rom, args.start_address)?;
+    let symbols = load_symbols(&args.rom, &args.symbols)?;
+
+    // Labels go at the end of the line, so line numbers still match coverage's lcov output
+    for (index, word) in bytes.chunks(2).enumerate() {
+        let address = args.start_address as usize + index * 2;
+        let label = match symbols.label(address as u16) {
+            Some(label) => format!("  ; {}:", label),
+            None => String::new()
+        };
+        if word.len() == 1 {
+            println!("{:#05X}  {:02X}    DB {:#04X}{}", address, word[0], word[0], label);
+            continue;
+        }
+
+        let opcode = (word[0] as u16) << 8 | word[1] as u16;
+        let code = symbols.format_instruction(Instruction::decode(opcode));
+        match label.is_empty() {
+            true => println!("{:#05X}  {:04X}  {}", address, opcode, code),
+            false => println!("{:#05X}  {:04X}  {:<20}{}", address, opcode, code, label)
+        }
+    }
+
+    Ok(())
+}
+
+fn info(args: RomArgs) -> Result<(), String> {
+    let bytes = read_rom(&args.rom, cpu::START_ADDRESS)?;
+    let instructions: Vec<Instruction> = bytes
+        .chunks(2)
+        .filter(|word| word.len() == 2)
+        .map(|word| Instruction::decode((word[0] as u16) << 8 | word[1] as u16))
+        .collect();
+    let unknown = instructions.iter().filter(|instruction| matches!(instruction, Instruction::Unknown(_))).count();
+
+    println!("File:         {}", args.rom.display());
+    println!("Size:         {} bytes ({} bytes of memory left)", bytes.len(), cpu::MAX_ROM_SIZE - bytes.len());
+    println!("Loads at:     {:#05X}-{:#05X}", cpu::START_ADDRESS, cpu::START_ADDRESS as usize + bytes.len() - 1);
+    println!("Words:        {} ({} don't decode to an instruction, likely data)", instructions.len(), unknown);
+    println!("SHA-1:        {}", config::rom_hash(&bytes));
+
+    let info = match Database::bundled().lookup(&config::rom_hash(&bytes)) {
+        Some(info) => info,
+        None => {
+            println!("Not in the ROM database");
+            return Ok(());
+        }
+    };
+
+    println!("Title:        {}", info.title);
+    if !info.authors.is_empty() {
+        println!("Authors:      {}", info.authors.join(", "));
+    }
+    if let Some(release) = &info.release {
+        println!("Released:     {}", release);
+    }
+    if let Some(platform) = &info.platform {
+        println!("Platform:     {}", platform);
+    }
+    if let Some(quirks) = info.quirks {
+        println!("Quirks:       {}", quirks.profile_name().map(String::from).unwrap_or_else(|| format!("{:?}", quirks)));
+    }
+    if let Some(tickrate) = info.tickrate {
+        println!("Clock:        {} Hz", tickrate * cpu::FRAMES_PER_SECOND);
+    }
+    if !info.keys.is_empty() {
+        println!("Keys:         {}", database::describe_keys(&info.keys));
+    }
+    if let Some(description) = &info.description {
+        println!();
+        println!("{}", description);
+    }
+
+    Ok(())
+}
+
+fn analyze(args: AnalyzeArgs) -> Result<(), String> {
+    let bytes = read_rom(&args.rom.rom, args.start_address)?;
+    let quirks = args
+        .quirks
+        .or_else(|| Database::bundled().lookup(&config::rom_hash(&bytes)).and_then(|info| info.quirks))
+        .unwrap_or_default();
+    let analysis = Analysis::analyze(&bytes, args.start_address, args.start_address, quirks);
+
+    let instructions: usize = analysis.blocks.values().map(|block| block.instructions.len()).sum();
+    println!("Quirks:       {}", quirks.profile_name().map(String::from).unwrap_or_else(|| format!("{:?}", quirks)));
+    println!("Reachable:    {} instructions in {} basic blocks", instructions, analysis.blocks.len());
+    for (function, calls) in &analysis.call_graph {
+        let name = if *function == analysis.entry { "Entry point:" } else { "Subroutine: " };
+        let calls = calls.iter().map(|target| format!("{:#05X}", target)).collect::<Vec<_>>();
+        if calls.is_empty() {
+            println!("{} {:#05X}", name, function);
+        } else {
+            println!("{} {:#05X}, calls {}", name, function, calls.join(", "));
+        }
+    }
+
+    println!();
+    if analysis.findings.is_empty() {
+        println!("Nothing to report");
+    }
+    for finding in &analysis.findings {
+        println!("{}", finding);
+    }
+
+    let outputs = [(&args.dot, analysis.to_dot()), (&args.call_graph, analysis.call_graph_dot())];
+    for (path, dot) in outputs.iter().filter_map(|(path, dot)| path.as_ref().map(|path| (path, dot))) {
+        fs::write(path, dot).map_err(|error| format!("can't write {}: {}", path.display(), error))?;
+    }
+
+    Ok(())
+}
+
+fn headless(args: HeadlessArgs) -> Result<(), String> {
+    let session = create_session(&args.rom, &args.machine)?;
+    let mut cpu = session.cpu;
+    let instructions_per_frame = instructions_per_frame(session.clock);
+    let symbols = load_symbols(&args.rom, &args.symbols)?;
+    set_breakpoints(&mut cpu, &symbols, &args.breakpoints)?;
+    let mut script = match &args.script {
+        Some(path) => Some(Script::load(path, &mut cpu, &symbols)?),
+        None => None
+    };
+    let mut cheats = saved_cheats(&session.settings, &symbols)?;
+    for text in &args.cheats {
+        cheats.add(Cheat::parse(text, &symbols).map_err(|error| format!("--cheat {}: {}", text, error))?);
+    }
+
+    let mut filter = PhosphorFilter::new(args.persistence);
+    let palette = match args.palette {
+        Some(palette) => palette,
+        None => session.settings.parse_palette().map_err(config_error)?.unwrap_or_default()
+    };
+    let scale = args.scale as usize;
+
+    let mut recorder = match &args.record {
+        Some(path) => Some(capture::Recorder::create(path, cpu.graphics.width(), cpu.graphics.height(), &palette, scale)?),
+        None => None
+    };
+
+    if args.coverage.is_some() {
+        cpu.coverage = Some(Coverage::new(&session.rom_hash));
+    }
+
+    let mut audio_recorder = match &args.audio {
+        Some(path) => Some(WavRecorder::create(path)?),
+        None => None
+    };
+
+    for frame in 0..args.frames {
+        cheats.apply(&mut cpu);
+        let sounding = match &mut script {
+            Some(script) => script.run_frame(&mut cpu, instructions_per_frame)?,
+            None if args.trace => run_traced_frame(&mut cpu, instructions_per_frame, &symbols),
+            None => cpu.run_frame(instructions_per_frame)
+        };
+        filter.update(&cpu.graphics);
+
+        if let Some(recorder) = &mut recorder {
+            recorder.record_frame(&cpu.graphics)?;
+        }
+        if let Some(audio_recorder) = &mut audio_recorder {
+            audio_recorder.record_frame(sounding, &Pattern::beeper())?;
+        }
+        if let Some(address) = cpu.breakpoint_hit() {
+            eprintln!("Stopped at breakpoint {} in frame {}", symbols.describe(address), frame + 1);
+            break;
+        }
+        if script.as_mut().is_some_and(Script::take_stop_request) {
+            eprintln!("Stopped by the script in frame {}", frame + 1);
+            break;
+        }
+    }
+
+    if let (Some(recorder), Some(path)) = (recorder, &args.record) {
+        let raw_hint = if recorder.is_gif() { None } else { Some(recorder.ffmpeg_command(path)) };
+        let frames = recorder.finish()?;
+        eprintln!("Recorded {} frames to {}", frames, path.display());
+        if let Some(command) = raw_hint {
+            eprintln!("Turn it into a video with: {}", command);
+        }
+    }
+
+    if let (Some(audio_recorder), Some(path)) = (audio_recorder, &args.audio) {
+        let frames = audio_recorder.finish()?;
+        eprintln!("Recorded {} frames of sound to {}", frames, path.display());
+    }
+
+    if let (Some(mut coverage), Some(path)) = (cpu.coverage.take(), &args.coverage) {
+        if let Some(earlier) = Coverage::load(path)? {
+            coverage.merge(&earlier).map_err(|error| format!("{}: {}", path.display(), error))?;
+        }
+        coverage.save(path)?;
+        eprintln!("Saved coverage of {} runs to {}", coverage.runs, path.display());
+    }
+
+    if let Some(path) = &args.screenshot {
+        capture::save_screenshot(path, &cpu.graphics, &palette, scale)?;
+    }
+
+    let mut screen = vec![vec!['.'; cpu.graphics.width()]; cpu.graphics.height()];
+    for (x, y, _, brightness) in filter.pixels() {
+        screen[y][x] = match brightness {
+            b if b >= 1.0 => '#',
+            b if b >= 0.5 => '+',
+            _ => '-'
+        };
+    }
+    for row in screen {
+        println!("{}", row.into_iter().collect::<String>());
+    }
+
+    println!();
+    println!(
+        "PC={:#05X} I={:#05X} SP={} DT={} ST={}",
+        cpu.program_counter, cpu.index_register, cpu.stack_pointer, cpu.delay_timer, cpu.sound_timer
+    );
+    let registers: Vec<String> = cpu.cpu_registers.iter().enumerate().map(|(i, v)| format!("V{:X}={:02X}", i, v)).collect();
+    println!("{}", registers.join(" "));
+
+    Ok(())
+}
+
+/// Runs a frame like `Cpu::run_frame`, one instruction at a time, printing each before it runs
+fn run_traced_frame(cpu: &mut Cpu, instructions_per_frame: usize, symbols: &Symbols) -> bool {
+    for _ in 0..instructions_per_frame {
+        if cpu.waiting_for_vblank() || cpu.waiting_for_key() {
+            break;
+        }
+
+        let address = cpu.program_counter & (cpu::MEMORY_SIZE as u16 - 1);
+        let opcode = (cpu.memory[address as usize] as u16) << 8 | cpu.memory[(address as usize + 1) % cpu::MEMORY_SIZE] as u16;
+        cpu.run_instructions(1);
+        if cpu.breakpoint_hit().is_some() {
+            break;
+        }
+        println!("{:#05X}  {:<16} {}", address, symbols.describe(address), symbols.format_instruction(Instruction::decode(opcode)));
+    }
+
+    let sounding = cpu.sound_timer > 0;
+    cpu.update_timers();
+    sounding
+}
+
+fn coverage(args: CoverageArgs) -> Result<(), String> {
+    let bytes = read_rom(&args.rom.rom, args.start_address)?;
+    let mut coverage = Coverage::new(&config::rom_hash(&bytes));
+    coverage.runs = 0;
+
+    for path in &args.data {
+        let data = Coverage::load(path)?.ok_or_else(|| format!("{} doesn't exist", path.display()))?;
+        coverage.merge(&data).map_err(|error| format!("{}: {}", path.display(), error))?;
+    }
+
+    print!("{}", coverage.annotate(&bytes, args.start_address));
+    println!();
+    println!("{}", coverage.summary(&bytes, args.start_address));
+
+    if let Some(path) = &args.lcov {
+        // Only the call graph is used, which doesn't depend on the quirks
+        let analysis = Analysis::analyze(&bytes, args.start_address, args.start_address, Quirks::default());
+        let subroutines: Vec<u16> = analysis.call_graph.keys().cloned().filter(|&entry| entry != analysis.entry).collect();
+        let source = format!("{}.asm", args.rom.rom.display());
+        let lcov = coverage.lcov(&bytes, args.start_address, &source, &subroutines);
+        fs::write(path, lcov).map_err(|error| format!("can't write {}: {}", path.display(), error))?;
+    }
+
+    Ok(())
+}
+
+fn profile(args: ProfileArgs) -> Result<(), String> {
+    let session = create_session(&args.rom, &args.machine)?;
+    let mut cpu = session.cpu;
+    let instructions_per_frame = instructions_per_frame(session.clock);
+
+    cpu.profiler = Some(Profiler::new());
+    for _ in 0..args.frames {
+        cpu.run_frame(instructions_per_frame);
+    }
+    let profiler = cpu.profiler.take().expect("set above");
+
+    let symbols = load_symbols(&args.rom, &args.symbols)?;
+    print!("{}", profiler.summary(args.top, &symbols));
+
+    if let Some(path) = &args.folded {
+        fs::write(path, profiler.folded_stacks(&symbols)).map_err(|error| format!("can't write {}: {}", path.display(), error))?;
+    }
+
+    Ok(())
+}
+
+fn sprites(args: SpritesArgs) -> Result<(), String> {
+    let session = create_session(&args.rom, &args.machine)?;
+    let mut cpu = session.cpu;
+
+    let sprites = match args.address {
+        Some(address) if args.large => vec![Sprite::large(address)],
+        Some(address) => vec![Sprite::new(address, args.rows as usize)],
+        None => {
+            let instructions_per_frame = instructions_per_frame(session.clock);
+            for _ in 0..args.frames {
+                cpu.run_frame(instructions_per_frame);
+            }
+            cpu.sprites_drawn.iter().cloned().collect()
+        }
+    };
+
+    if sprites.is_empty() {
+        println!("Nothing was drawn in {} frames", args.frames);
+    }
+
+    for sprite in &sprites {
+        println!("{}", sprite);
+        println!("{}", sprite.to_text(&cpu.memory));
+        println!();
+    }
+
+    if let Some(directory) = &args.export {
+        let palette = match args.palette {
+            Some(palette) => palette,
+            None => session.settings.parse_palette().map_err(config_error)?.unwrap_or_default()
+        };
+        fs::create_dir_all(directory).map_err(|error| format!("can't create {}: {}", directory.display(), error))?;
+
+        for sprite in &sprites {
+            sprite.save_png(&directory.join(sprite.file_name()), &cpu.memory, &palette, args.scale as usize)?;
+        }
+        eprintln!("Saved {} sprites to {}", sprites.len(), directory.display());
+    }
+
+    Ok(())
+}
+
+struct Session {
+    cpu: Cpu,
+    clock: u32,
+    /// The config file's settings for the ROM, before command line options are applied
+    settings: Settings,
+    config: Option<Config>,
+    rom_hash: String,
+    /// What the ROM database knows about the ROM
+    info: Option<RomInfo>
+}
+
+/// Loads a ROM into a CPU set up from the command line, falling back to the config file
+fn create_session(rom: &RomArgs, machine: &MachineArgs) -> Result<Session, String> {
+    let bytes = read_rom(&rom.rom, machine.start_address)?;
+    let rom_hash = config::rom_hash(&bytes);
+    let config = if machine.no_config { None } else { Some(Config::load()?) };
+    let info = if machine.no_database { None } else { Database::bundled().lookup(&rom_hash) };
+    let recommended = info.as_ref().map(RomInfo::settings).unwrap_or_default();
+    let settings = match &config {
+        Some(config) => config.settings_for(&rom_hash, &recommended),
+        None => recommended
+    };
+
+    let quirks = match machine.quirks {
+        Some(quirks) => quirks,
+        None => settings.parse_quirks().map_err(config_error)?.unwrap_or_default()
+    };
+    let clock = machine.clock.or(settings.clock).unwrap_or(DEFAULT_CLOCK_SPEED);
+
+    let mut cpu = Cpu::new();
+    cpu.quirks = quirks;
+    cpu.set_backend(machine.backend);
+    if let Some(seed) = machine.seed {
+        cpu.seed_rng(seed);
+    }
+    cpu.load_rom_at(bytes, machine.start_address)?;
+
+    Ok(Session { cpu, clock, settings, config, rom_hash, info })
+}
+
+/// Loads the symbols given on the command line, or those beside the ROM if there are any
+fn load_symbols(rom: &RomArgs, args: &SymbolArgs) -> Result<Symbols, String> {
+    let beside_rom = rom.rom.with_extension("sym");
+    match &args.symbols {
+        Some(path) => Symbols::load(path),
+        None if beside_rom.is_file() => Symbols::load(&beside_rom),
+        None => Ok(Symbols::new())
+    }
+}
+
+fn saved_cheats(settings: &Settings, symbols: &Symbols) -> Result<Cheats, String> {
+    Cheats::parse(settings.cheats.as_deref().unwrap_or_default(), symbols).map_err(config_error)
+}
+
+fn set_breakpoints(cpu: &mut Cpu, symbols: &Symbols, breakpoints: &[String]) -> Result<(), String> {
+    for text in breakpoints {
+        let address = symbols.resolve(text).map_err(|error| format!("--break {}: {}", text, error))?;
+        cpu.breakpoints.insert(address & (cpu::MEMORY_SIZE as u16 - 1));
+    }
+    Ok(())
+}
+
+/// Reads a ROM, making sure it fits in memory when loaded at `start_address`
+fn read_rom(path: &Path, start_address: u16) -> Result<Vec<u8>, String> {
+    let bytes = fs::read(path).map_err(|error| format!("can't read {}: {}", path.display(), error))?;
+    let capacity = cpu::MEMORY_SIZE - start_address as usize;
+
+    if bytes.is_empty() {
+        return Err(format!("{} is empty", path.display()));
+    }
+
+    if bytes.len() > capacity {
+        return Err(format!(
+            "{} is {} bytes but only {} bytes fit in memory from {:#05X}",
+            path.display(), bytes.len(), capacity, start_address
+        ));
+    }
+
+    Ok(bytes)
+}
+
+fn config_error(error: String) -> String {
+    match Config::path() {
+        Some(path) => format!("{}: {}", path.display(), error),
+        None => error
+    }
+}
+
+fn instructions_per_frame(clock: u32) -> usize {
+    (clock / cpu::FRAMES_PER_SECOND).max(1) as usize
+}
+
+fn parse_quirks(name: &str) -> Result<Quirks, String> {
+    Quirks::from_profile(name).ok_or_else(|| format!("unknown quirk profile, expected one of {}", quirks::PROFILES.join(", ")))
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    let address = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse()
+    }
+    .map_err(|_| String::from("expected an address like 0x200 or 512"))?;
+
+    if address as usize >= cpu::MEMORY_SIZE {
+        return Err(format!("address must be below {:#05X}", cpu::MEMORY_SIZE));
+    }
+
+    Ok(address)
+}
 
-    event::run(ctx, event_loop, &mut c).expect("Error running loop");
-} 
\ No newline at end of file
+fn parse_backend(name: &str) -> Result<Backend, String> {
+    match name {
+        "interpreter" => Ok(Backend::Interpreter),
+        "recompiler" => Ok(Backend::Recompiler),
+        _ => Err(String::from("expected interpreter or recompiler"))
+    }
+}
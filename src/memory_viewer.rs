@@ -0,0 +1,203 @@
+use ggez::event::KeyCode;
+use crate::cpu::{Cpu, FONT_START_ADDRESS, MEMORY_SIZE};
+use crate::symbols::Symbols;
+
+const BYTES_PER_ROW: u16 = 16;
+const ROWS: u16 = 8;
+const PAGE: u16 = BYTES_PER_ROW * ROWS;
+const ADDRESS_MASK: u16 = MEMORY_SIZE as u16 - 1;
+/// The built-in font is 16 characters of 5 bytes
+const FONT_SIZE: usize = 16 * 5;
+
+/// Why a byte stands out in the hex view
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Highlight {
+    Cursor,
+    /// Part of the instruction at PC
+    ProgramCounter,
+    /// The byte I points at
+    Index,
+    /// An address on the call stack, where a return will go back to
+    Stack,
+    Font
+}
+
+/// A hex view of memory that follows the CPU as it runs. Arrows move the cursor, hex digits overwrite the
+/// byte under it while paused and G jumps to a typed address
+pub struct MemoryViewer {
+    cursor: u16,
+    /// Address of the first row shown
+    top: u16,
+    /// The first digit of a byte being typed over the cursor
+    pending_digit: Option<u8>,
+    /// Digits typed so far after G
+    jump: Option<String>,
+    message: Option<String>
+}
+
+impl MemoryViewer {
+    /// A viewer starting at the instruction about to run
+    pub fn new(cpu: &Cpu) -> Self {
+        let mut viewer = MemoryViewer {
+            cursor: 0,
+            top: 0,
+            pending_digit: None,
+            jump: None,
+            message: None
+        };
+        viewer.go_to(cpu.program_counter);
+        viewer
+    }
+
+    fn go_to(&mut self, address: u16) {
+        self.cursor = address & ADDRESS_MASK;
+        self.pending_digit = None;
+
+        if self.cursor < self.top || self.cursor >= self.top + PAGE {
+            self.top = (self.cursor - self.cursor % BYTES_PER_ROW).min(MEMORY_SIZE as u16 - PAGE);
+        }
+    }
+
+    fn highlight(&self, cpu: &Cpu, address: u16) -> Option<Highlight> {
+        let address = address as usize;
+        let program_counter = cpu.program_counter as usize;
+        if address == self.cursor as usize {
+            Some(Highlight::Cursor)
+        } else if address == program_counter || address == program_counter + 1 {
+            Some(Highlight::ProgramCounter)
+        } else if address == cpu.index_register as usize {
+            Some(Highlight::Index)
+        } else if cpu.execution_stack[..cpu.stack_pointer].iter().any(|&target| target as usize == address) {
+            Some(Highlight::Stack)
+        } else if (FONT_START_ADDRESS..FONT_START_ADDRESS + FONT_SIZE).contains(&address) {
+            Some(Highlight::Font)
+        } else {
+            None
+        }
+    }
+
+    /// Lines of text to show, each split into pieces that are drawn highlighted or plain
+    pub fn lines(&self, cpu: &Cpu, symbols: &Symbols) -> Vec<Vec<(String, Option<Highlight>)>> {
+        let plain = |text: String| vec![(text, None)];
+        let program_counter = if symbols.is_empty() {
+            format!("{:03X}", cpu.program_counter)
+        } else {
+            format!("{:03X} ({})", cpu.program_counter, symbols.describe(cpu.program_counter))
+        };
+        let mut lines = vec![
+            plain(format!("Memory - PC {}  I {:03X}  SP {}", program_counter, cpu.index_register, cpu.stack_pointer)),
+            plain(String::new())
+        ];
+
+        for row in 0..ROWS {
+            let start = self.top + row * BYTES_PER_ROW;
+            let mut line = vec![(format!("{:03X} ", start), None)];
+            for address in start..start + BYTES_PER_ROW {
+                let text = match self.pending_digit {
+                    Some(digit) if address == self.cursor => format!("{:X}_", digit),
+                    _ => format!("{:02X}", cpu.memory[address as usize])
+                };
+                line.push((String::from(" "), None));
+                line.push((text, self.highlight(cpu, address)));
+            }
+            lines.push(line);
+        }
+
+        lines.push(plain(String::new()));
+        lines.push(plain(match (&self.jump, &self.message) {
+            (Some(digits), _) => format!("Go to: {}_", digits),
+            (None, Some(message)) => message.clone(),
+            (None, None) => match symbols.is_empty() {
+                true => format!("{:03X}: {:02X}", self.cursor, cpu.memory[self.cursor as usize]),
+                false => format!("{:03X} {}: {:02X}", self.cursor, symbols.describe(self.cursor), cpu.memory[self.cursor as usize])
+            }
+        }));
+        lines.push(plain(String::from("Arrows/PgUp/PgDn move, Home PC, End I, G go to")));
+        lines.push(plain(String::from("0-F edit while paused, F4 close")));
+        lines
+    }
+
+    /// Handles a key press, returning whether the viewer used it. Other keys are left to the emulator,
+    /// so it can still be paused and stepped with the viewer open
+    pub fn handle_key(&mut self, cpu: &mut Cpu, keycode: KeyCode, paused: bool) -> bool {
+        if let Some(digits) = &mut self.jump {
+            match keycode {
+                KeyCode::Return => {
+                    let address = u16::from_str_radix(digits, 16).ok();
+                    self.jump = None;
+                    if let Some(address) = address {
+                        self.go_to(address);
+                    }
+                },
+                KeyCode::Escape => self.jump = None,
+                KeyCode::Back => {
+                    digits.pop();
+                },
+                _ => {
+                    if let Some(digit) = hex_digit(keycode).filter(|_| digits.len() < 3) {
+                        digits.push_str(&format!("{:X}", digit));
+                    }
+                }
+            }
+            return true;
+        }
+
+        self.message = None;
+        match keycode {
+            KeyCode::Left => self.go_to(self.cursor.wrapping_sub(1)),
+            KeyCode::Right => self.go_to(self.cursor + 1),
+            KeyCode::Up => self.go_to(self.cursor.wrapping_sub(BYTES_PER_ROW)),
+            KeyCode::Down => self.go_to(self.cursor + BYTES_PER_ROW),
+            KeyCode::PageUp => self.go_to(self.cursor.wrapping_sub(PAGE)),
+            KeyCode::PageDown => self.go_to(self.cursor + PAGE),
+            KeyCode::Home => self.go_to(cpu.program_counter),
+            KeyCode::End => self.go_to(cpu.index_register),
+            KeyCode::G => {
+                self.pending_digit = None;
+                self.jump = Some(String::new());
+            },
+            KeyCode::Escape if self.pending_digit.is_some() => self.pending_digit = None,
+            _ => match hex_digit(keycode) {
+                Some(digit) if paused => self.type_digit(cpu, digit),
+                // Digits go to the running program instead
+                _ => return false
+            }
+        }
+
+        true
+    }
+
+    fn type_digit(&mut self, cpu: &mut Cpu, digit: u8) {
+        match self.pending_digit.take() {
+            Some(high) => {
+                cpu.write_byte(self.cursor, high << 4 | digit);
+                self.message = Some(format!("Wrote {:02X} to {:03X}", high << 4 | digit, self.cursor));
+                self.go_to(self.cursor + 1);
+            },
+            None => self.pending_digit = Some(digit)
+        }
+    }
+}
+
+fn hex_digit(keycode: KeyCode) -> Option<u8> {
+    let digit = match keycode {
+        KeyCode::Key0 | KeyCode::Numpad0 => 0x0,
+        KeyCode::Key1 | KeyCode::Numpad1 => 0x1,
+        KeyCode::Key2 | KeyCode::Numpad2 => 0x2,
+        KeyCode::Key3 | KeyCode::Numpad3 => 0x3,
+        KeyCode::Key4 | KeyCode::Numpad4 => 0x4,
+        KeyCode::Key5 | KeyCode::Numpad5 => 0x5,
+        KeyCode::Key6 | KeyCode::Numpad6 => 0x6,
+        KeyCode::Key7 | KeyCode::Numpad7 => 0x7,
+        KeyCode::Key8 | KeyCode::Numpad8 => 0x8,
+        KeyCode::Key9 | KeyCode::Numpad9 => 0x9,
+        KeyCode::A => 0xA,
+        KeyCode::B => 0xB,
+        KeyCode::C => 0xC,
+        KeyCode::D => 0xD,
+        KeyCode::E => 0xE,
+        KeyCode::F => 0xF,
+        _ => return None
+    };
+    Some(digit)
+}
@@ -0,0 +1,205 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Rgb { r, g, b }
+    }
+
+    /// Mixes this color with `other`, giving this color when `amount` is 0 and `other` when it's 1
+    pub fn blend(self, other: Rgb, amount: f32) -> Self {
+        let amount = amount.clamp(0.0, 1.0);
+        let mix = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * amount).round() as u8;
+        Rgb::new(mix(self.r, other.r), mix(self.g, other.g), mix(self.b, other.b))
+    }
+
+    /// Parses `RRGGBB`, optionally prefixed with `#`
+    pub fn parse(hex: &str) -> Result<Self, String> {
+        let digits = hex.trim().trim_start_matches('#');
+        if digits.len() != 6 {
+            return Err(format!("'{}' isn't a color, expected RRGGBB", hex));
+        }
+
+        let value = u32::from_str_radix(digits, 16).map_err(|_| format!("'{}' isn't a color, expected RRGGBB", hex))?;
+        Ok(Rgb::new((value >> 16) as u8, (value >> 8) as u8, value as u8))
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02X}{:02X}{:02X}", self.r, self.g, self.b)
+    }
+}
+
+/// Named palettes, listed in the order the palette hotkey cycles through them
+pub const PRESETS: [(&str, &[Rgb]); 10] = [
+    ("classic", &[Rgb::new(0x00, 0x00, 0x00), Rgb::new(0xFF, 0xFF, 0xFF)]),
+    ("amber", &[Rgb::new(0x1A, 0x10, 0x00), Rgb::new(0xFF, 0xB0, 0x00)]),
+    ("green", &[Rgb::new(0x06, 0x1A, 0x06), Rgb::new(0x33, 0xFF, 0x33)]),
+    ("lcd", &[Rgb::new(0x9B, 0xBC, 0x0F), Rgb::new(0x0F, 0x38, 0x0F), Rgb::new(0x8B, 0xAC, 0x0F), Rgb::new(0x30, 0x62, 0x30)]),
+    // Octo's themes
+    ("octo", &[Rgb::new(0x99, 0x66, 0x00), Rgb::new(0xFF, 0xCC, 0x00), Rgb::new(0xFF, 0x66, 0x00), Rgb::new(0x66, 0x22, 0x00)]),
+    ("octo-lcd", &[Rgb::new(0xF9, 0xFF, 0xB3), Rgb::new(0x3D, 0x80, 0x26), Rgb::new(0xAB, 0xCC, 0x47), Rgb::new(0x00, 0x13, 0x1A)]),
+    ("hotdog", &[Rgb::new(0x00, 0x00, 0x00), Rgb::new(0xFF, 0x00, 0x00), Rgb::new(0xFF, 0xFF, 0x00), Rgb::new(0xFF, 0xFF, 0xFF)]),
+    ("gray", &[Rgb::new(0xAA, 0xAA, 0xAA), Rgb::new(0x00, 0x00, 0x00), Rgb::new(0xFF, 0xFF, 0xFF), Rgb::new(0x66, 0x66, 0x66)]),
+    ("cga0", &[Rgb::new(0x00, 0x00, 0x00), Rgb::new(0x00, 0xFF, 0x00), Rgb::new(0xFF, 0x00, 0x00), Rgb::new(0xFF, 0xFF, 0x00)]),
+    ("cga1", &[Rgb::new(0x00, 0x00, 0x00), Rgb::new(0xFF, 0x00, 0xFF), Rgb::new(0x00, 0xFF, 0xFF), Rgb::new(0xFF, 0xFF, 0xFF)])
+];
+
+/// Colors the display is drawn with, indexed by a pixel's color index: the background first, then the color of
+/// pixels lit in the first plane. Palettes have two, four or sixteen colors to cover one, two or four planes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette {
+    colors: Vec<Rgb>
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::preset(PRESETS[0].0).expect("the first preset exists")
+    }
+}
+
+impl Palette {
+    pub fn new(colors: Vec<Rgb>) -> Result<Self, String> {
+        match colors.len() {
+            2 | 4 | 16 => Ok(Palette { colors }),
+            count => Err(format!("a palette needs 2, 4 or 16 colors, not {}", count))
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        PRESETS
+            .iter()
+            .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+            .map(|(_, colors)| Palette { colors: colors.to_vec() })
+    }
+
+    /// Name of the preset this palette matches, if any
+    pub fn preset_name(&self) -> Option<&'static str> {
+        PRESETS.iter().find(|(_, colors)| *colors == self.colors.as_slice()).map(|&(name, _)| name)
+    }
+
+    /// The preset after this one, wrapping around. Palettes that aren't presets go to the first one
+    pub fn next_preset(&self) -> Self {
+        self.step_preset(1)
+    }
+
+    pub fn previous_preset(&self) -> Self {
+        self.step_preset(PRESETS.len() - 1)
+    }
+
+    fn step_preset(&self, step: usize) -> Self {
+        let next = match self.preset_name().and_then(|name| PRESETS.iter().position(|&(preset, _)| preset == name)) {
+            Some(index) => (index + step) % PRESETS.len(),
+            None => 0
+        };
+        Palette { colors: PRESETS[next].1.to_vec() }
+    }
+
+    pub fn background(&self) -> Rgb {
+        self.colors[0]
+    }
+
+    pub fn foreground(&self) -> Rgb {
+        self.colors[1]
+    }
+
+    /// Color for a pixel's color index. Indexes past the end of a small palette use the foreground
+    pub fn color(&self, index: usize) -> Rgb {
+        self.colors.get(index).cloned().unwrap_or_else(|| self.foreground())
+    }
+
+    pub fn colors(&self) -> &[Rgb] {
+        &self.colors
+    }
+
+    /// Parses a preset name, `FOREGROUND,BACKGROUND`, or four or sixteen comma separated colors in color index
+    /// order starting with the background. Each color is `RRGGBB`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if let Some(palette) = Palette::preset(text.trim()) {
+            return Ok(palette);
+        }
+
+        let colors = text.split(',').map(Rgb::parse).collect::<Result<Vec<Rgb>, String>>()?;
+        match colors.as_slice() {
+            [foreground, background] => Palette::new(vec![*background, *foreground]),
+            [_, _, _, _] | [_, _, _, _, _, _, _, _, _, _, _, _, _, _, _, _] => Palette::new(colors),
+            _ => Err(format!(
+                "'{}' isn't a palette, expected a preset ({}), FOREGROUND,BACKGROUND or 4 or 16 colors",
+                text,
+                PRESETS.iter().map(|&(name, _)| name).collect::<Vec<_>>().join(", ")
+            ))
+        }
+    }
+}
+
+/// Formats as the preset name when there is one, otherwise in the form `Palette::parse` reads
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = self.preset_name() {
+            return write!(f, "{}", name);
+        }
+
+        let colors: Vec<String> = match self.colors.as_slice() {
+            [background, foreground] => vec![foreground.to_string(), background.to_string()],
+            colors => colors.iter().map(Rgb::to_string).collect()
+        };
+        write!(f, "{}", colors.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_colors_are_given_foreground_first_and_kept_background_first() {
+        let palette = Palette::parse("#FFB000,1a1000").unwrap();
+        assert_eq!(palette.background(), Rgb::new(0x1A, 0x10, 0x00));
+        assert_eq!(palette.foreground(), Rgb::new(0xFF, 0xB0, 0x00));
+        assert_eq!(palette, Palette::preset("amber").unwrap());
+
+        let custom = Palette::parse("FF0000,000080").unwrap();
+        assert_eq!(custom.colors(), &[Rgb::new(0x00, 0x00, 0x80), Rgb::new(0xFF, 0x00, 0x00)]);
+        assert_eq!(custom.color(3), custom.foreground());
+        assert_eq!(custom.to_string(), "#FF0000,#000080");
+        assert_eq!(Palette::parse(&custom.to_string()), Ok(custom));
+    }
+
+    #[test]
+    fn palettes_parse_presets_and_color_lists() {
+        assert_eq!(Palette::parse(" Octo-LCD "), Ok(Palette::preset("octo-lcd").unwrap()));
+        assert_eq!(Palette::parse("000000,111111,222222,333333").unwrap().color(3), Rgb::new(0x33, 0x33, 0x33));
+        assert_eq!(Palette::parse(&vec!["ABCDEF"; 16].join(",")).unwrap().colors().len(), 16);
+
+        assert!(Palette::parse("000000,111111,222222").is_err());
+        assert!(Palette::parse("000000,11111").is_err());
+        assert!(Palette::parse("sepia").is_err());
+        assert_eq!(Rgb::parse("12345G"), Err(String::from("'12345G' isn't a color, expected RRGGBB")));
+    }
+
+    #[test]
+    fn presets_cycle_round() {
+        let first = Palette::default();
+        let last = Palette::preset(PRESETS[PRESETS.len() - 1].0).unwrap();
+        assert_eq!(first.previous_preset(), last);
+        assert_eq!(last.next_preset(), first);
+        assert_eq!(first.next_preset().preset_name(), Some(PRESETS[1].0));
+
+        let mut palette = first.clone();
+        for _ in 0..PRESETS.len() {
+            palette = palette.next_preset();
+        }
+        assert_eq!(palette, first);
+
+        let custom = Palette::parse("FF0000,000080").unwrap();
+        assert_eq!(custom.next_preset(), first);
+        assert_eq!(custom.previous_preset(), first);
+    }
+}
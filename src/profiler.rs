@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use crate::cpu::MEMORY_SIZE;
+use crate::symbols::Symbols;
+
+/// A subroutine at one particular call stack
+#[derive(Clone)]
+struct Node {
+    /// Entry address of the subroutine, `None` for the code that was running when profiling started
+    subroutine: Option<u16>,
+    parent: Option<usize>,
+    children: BTreeMap<u16, usize>,
+    /// Instructions run in this subroutine itself at this stack
+    instructions: u64
+}
+
+/// Totals for one subroutine over every stack it was called from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SubroutineProfile {
+    pub subroutine: Option<u16>,
+    pub calls: u64,
+    /// Instructions run in the subroutine itself
+    pub exclusive: u64,
+    /// Instructions run in the subroutine and everything it called
+    pub inclusive: u64
+}
+
+/// A backward jump and how much ran inside it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Loop {
+    /// Address of the jump back
+    pub jump: u16,
+    /// Address it jumps back to, the top of the loop
+    pub target: u16,
+    pub iterations: u64,
+    /// Instructions run between the top of the loop and the jump, inclusive
+    pub instructions: u64
+}
+
+/// Counts instructions by call stack, following `CALL` and `RET`, and counts how often each backward jump is taken
+#[derive(Clone)]
+pub struct Profiler {
+    nodes: Vec<Node>,
+    current: usize,
+    calls: BTreeMap<u16, u64>,
+    executions: Vec<u64>,
+    /// Times each backward jump was taken, by (jump, target)
+    loops: BTreeMap<(u16, u16), u64>
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            nodes: vec![Node { subroutine: None, parent: None, children: BTreeMap::new(), instructions: 0 }],
+            current: 0,
+            calls: BTreeMap::new(),
+            executions: vec![0; MEMORY_SIZE],
+            loops: BTreeMap::new()
+        }
+    }
+
+    pub fn record_instruction(&mut self, address: usize) {
+        self.nodes[self.current].instructions += 1;
+        self.executions[address] += 1;
+    }
+
+    pub fn record_call(&mut self, subroutine: u16) {
+        *self.calls.entry(subroutine).or_insert(0) += 1;
+
+        let next = self.nodes.len();
+        let current = self.current;
+        let child = *self.nodes[current].children.entry(subroutine).or_insert(next);
+        if child == next {
+            self.nodes.push(Node { subroutine: Some(subroutine), parent: Some(current), children: BTreeMap::new(), instructions: 0 });
+        }
+        self.current = child;
+    }
+
+    /// A `RET` with nothing to return to stays at the top of the stack, like the CPU does
+    pub fn record_return(&mut self) {
+        if let Some(parent) = self.nodes[self.current].parent {
+            self.current = parent;
+        }
+    }
+
+    pub fn record_jump(&mut self, from: u16, to: u16) {
+        if to <= from {
+            *self.loops.entry((from, to)).or_insert(0) += 1;
+        }
+    }
+
+    /// The subroutine's label, or `sub_` and its address
+    fn name(subroutine: Option<u16>, symbols: &Symbols) -> String {
+        match subroutine {
+            Some(address) => symbols.label(address).map(str::to_string).unwrap_or_else(|| format!("sub_{:03X}", address)),
+            None => String::from("main")
+        }
+    }
+
+    fn stack(&self, mut node: usize) -> Vec<Option<u16>> {
+        let mut stack = vec![self.nodes[node].subroutine];
+        while let Some(parent) = self.nodes[node].parent {
+            stack.push(self.nodes[parent].subroutine);
+            node = parent;
+        }
+        stack.reverse();
+        stack
+    }
+
+    /// One line per call stack in the folded format flamegraph tools read, e.g. `main;sub_2D4 120`
+    pub fn folded_stacks(&self, symbols: &Symbols) -> String {
+        let mut text = String::new();
+        for (index, node) in self.nodes.iter().enumerate().filter(|(_, node)| node.instructions > 0) {
+            let stack: Vec<String> = self.stack(index).into_iter().map(|subroutine| Profiler::name(subroutine, symbols)).collect();
+            text.push_str(&format!("{} {}\n", stack.join(";"), node.instructions));
+        }
+        text
+    }
+
+    pub fn total_instructions(&self) -> u64 {
+        self.nodes.iter().map(|node| node.instructions).sum()
+    }
+
+    /// Every subroutine that ran, most inclusive instructions first. A recursive call counts towards the
+    /// inclusive total of the outermost call only
+    pub fn subroutines(&self) -> Vec<SubroutineProfile> {
+        let mut inclusive = vec![0; self.nodes.len()];
+        for index in (0..self.nodes.len()).rev() {
+            inclusive[index] += self.nodes[index].instructions;
+            if let Some(parent) = self.nodes[index].parent {
+                inclusive[parent] += inclusive[index];
+            }
+        }
+
+        let mut profiles: BTreeMap<Option<u16>, SubroutineProfile> = BTreeMap::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            let profile = profiles.entry(node.subroutine).or_insert(SubroutineProfile {
+                subroutine: node.subroutine,
+                calls: node.subroutine.map(|address| self.calls[&address]).unwrap_or(0),
+                exclusive: 0,
+                inclusive: 0
+            });
+            profile.exclusive += node.instructions;
+
+            let stack = self.stack(index);
+            if !stack[..stack.len() - 1].contains(&node.subroutine) {
+                profile.inclusive += inclusive[index];
+            }
+        }
+
+        let mut profiles: Vec<SubroutineProfile> = profiles.into_values().collect();
+        profiles.sort_by_key(|profile| std::cmp::Reverse(profile.inclusive));
+        profiles
+    }
+
+    /// Backward jumps by how many instructions ran inside them, most first
+    pub fn loops(&self) -> Vec<Loop> {
+        let mut loops: Vec<Loop> = self
+            .loops
+            .iter()
+            .map(|(&(jump, target), &iterations)| Loop {
+                jump,
+                target,
+                iterations,
+                instructions: self.executions[target as usize..=jump as usize].iter().sum()
+            })
+            .collect();
+        loops.sort_by_key(|hot_loop| std::cmp::Reverse(hot_loop.instructions));
+        loops
+    }
+
+    /// A table of subroutines and the `top` hottest loops
+    pub fn summary(&self, top: usize, symbols: &Symbols) -> String {
+        let total = self.total_instructions().max(1) as f64;
+        let percent = |count: u64| count as f64 * 100.0 / total;
+
+        let subroutines = self.subroutines();
+        let names: Vec<String> = subroutines.iter().map(|profile| Profiler::name(profile.subroutine, symbols)).collect();
+        let width = names.iter().map(String::len).max().unwrap_or(0).max(10);
+
+        let mut text = format!("{} instructions\n\n", self.total_instructions());
+        text.push_str(&format!("{:<width$} {:>8} {:>12} {:>7} {:>12} {:>7}\n", "Subroutine", "Calls", "Inclusive", "%", "Exclusive", "%"));
+        for (profile, name) in subroutines.iter().zip(names) {
+            text.push_str(&format!(
+                "{:<width$} {:>8} {:>12} {:>6.1}% {:>12} {:>6.1}%\n",
+                name,
+                profile.calls,
+                profile.inclusive,
+                percent(profile.inclusive),
+                profile.exclusive,
+                percent(profile.exclusive)
+            ));
+        }
+
+        let loops: Vec<Loop> = self.loops().into_iter().take(top).collect();
+        if !loops.is_empty() {
+            let jumps: Vec<String> = loops
+                .iter()
+                .map(|hot_loop| format!("{} -> {}", describe(hot_loop.jump, symbols), describe(hot_loop.target, symbols)))
+                .collect();
+            let width = jumps.iter().map(String::len).max().unwrap_or(0).max(13);
+
+            text.push_str(&format!("\n{:<width$} {:>10} {:>12} {:>7}\n", "Loop", "Iterations", "Instructions", "%"));
+            for (hot_loop, jump) in loops.iter().zip(jumps) {
+                text.push_str(&format!(
+                    "{:<width$} {:>10} {:>12} {:>6.1}%\n",
+                    jump,
+                    hot_loop.iterations,
+                    hot_loop.instructions,
+                    percent(hot_loop.instructions)
+                ));
+            }
+        }
+
+        text
+    }
+}
+
+/// An address by label when there are symbols, otherwise in hex
+fn describe(address: u16, symbols: &Symbols) -> String {
+    match symbols.is_empty() {
+        true => format!("{:03X}", address),
+        false => symbols.describe(address)
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// main calls 0x210, which calls `draw` at 0x220, then calls `draw` itself and a subroutine that returns at once
+    fn profile() -> Profiler {
+        let mut profiler = Profiler::new();
+        profiler.record_instruction(0x200);
+        profiler.record_call(0x210);
+        profiler.record_instruction(0x210);
+        profiler.record_instruction(0x212);
+        profiler.record_call(0x220);
+        for _ in 0..3 {
+            profiler.record_instruction(0x220);
+        }
+        profiler.record_return();
+        profiler.record_instruction(0x214);
+        profiler.record_return();
+        profiler.record_instruction(0x202);
+        profiler.record_call(0x220);
+        profiler.record_instruction(0x220);
+        profiler.record_return();
+        profiler.record_call(0x230);
+        profiler.record_return();
+        profiler.record_return();
+        profiler.record_instruction(0x204);
+        profiler
+    }
+
+    #[test]
+    fn folded_stacks_name_subroutines_by_label() {
+        let mut symbols = Symbols::new();
+        symbols.insert("draw", 0x220);
+
+        assert_eq!(profile().folded_stacks(&symbols), "main 3\nmain;sub_210 3\nmain;sub_210;draw 3\nmain;draw 1\n");
+        assert_eq!(profile().total_instructions(), 10);
+    }
+
+    #[test]
+    fn subroutines_total_their_calls() {
+        let profiles = profile().subroutines();
+        let draw = profiles.iter().find(|profile| profile.subroutine == Some(0x220)).unwrap();
+        assert_eq!(*draw, SubroutineProfile { subroutine: Some(0x220), calls: 2, exclusive: 4, inclusive: 4 });
+        assert_eq!(profiles[0], SubroutineProfile { subroutine: None, calls: 0, exclusive: 3, inclusive: 10 });
+    }
+}
@@ -0,0 +1,196 @@
+use numpy::{PyArray1, PyArray2, PyArrayMethods};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use crate::cpu::{Backend, Cpu, DEFAULT_INSTRUCTIONS_PER_FRAME, MEMORY_SIZE, START_ADDRESS};
+use crate::quirks::{self, Quirks};
+
+/// The emulator core for Python, built with the `python` feature:
+///
+/// ```python
+/// import chip8
+/// cpu = chip8.Cpu(seed=1)
+/// cpu.load_rom(open("pong.ch8", "rb").read())
+/// cpu.run_frames(60)
+/// screen = cpu.screen()  # uint8 array of color indices, shape (height, width)
+/// ```
+#[pyclass(name = "Cpu", module = "chip8")]
+struct PyCpu {
+    cpu: Cpu
+}
+
+#[pymethods]
+impl PyCpu {
+    /// A CPU with the built-in font loaded. `quirks` is a profile name like "chip8" and `backend` is
+    /// "interpreter" or "recompiler"
+    #[new]
+    #[pyo3(signature = (seed = None, quirks = "default", backend = "interpreter"))]
+    fn new(seed: Option<u64>, quirks: &str, backend: &str) -> PyResult<Self> {
+        let mut cpu = Cpu::new();
+        cpu.quirks = Quirks::from_profile(quirks)
+            .ok_or_else(|| value_error(format!("unknown quirk profile, expected one of {}", quirks::PROFILES.join(", "))))?;
+        cpu.set_backend(match backend {
+            "interpreter" => Backend::Interpreter,
+            "recompiler" => Backend::Recompiler,
+            _ => return Err(value_error(String::from("expected interpreter or recompiler")))
+        });
+        if let Some(seed) = seed {
+            cpu.seed_rng(seed);
+        }
+        Ok(PyCpu { cpu })
+    }
+
+    /// Copies a ROM into memory and starts running from where it was loaded
+    #[pyo3(signature = (rom, start_address = START_ADDRESS))]
+    fn load_rom(&mut self, rom: &[u8], start_address: u16) -> PyResult<()> {
+        self.cpu.load_rom_at(rom.to_vec(), start_address).map_err(value_error)
+    }
+
+    /// Runs a number of instructions without touching the timers
+    #[pyo3(signature = (instructions = 1))]
+    fn step(&mut self, instructions: usize) {
+        self.cpu.run_instructions(instructions);
+    }
+
+    /// Runs one instruction and then updates the timers
+    fn cycle(&mut self) {
+        self.cpu.cycle();
+    }
+
+    /// Runs a 60Hz frame, returning whether the buzzer sounded
+    #[pyo3(signature = (instructions_per_frame = DEFAULT_INSTRUCTIONS_PER_FRAME))]
+    fn run_frame(&mut self, instructions_per_frame: usize) -> bool {
+        self.cpu.run_frame(instructions_per_frame)
+    }
+
+    /// Runs several frames, returning how many of them the buzzer sounded in
+    #[pyo3(signature = (frames, instructions_per_frame = DEFAULT_INSTRUCTIONS_PER_FRAME))]
+    fn run_frames(&mut self, frames: usize, instructions_per_frame: usize) -> usize {
+        (0..frames).filter(|_| self.cpu.run_frame(instructions_per_frame)).count()
+    }
+
+    /// The display as a uint8 array of shape (height, width), holding each pixel's color index
+    fn screen<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyArray2<u8>>> {
+        let graphics = &self.cpu.graphics;
+        let pixels: Vec<u8> = graphics.pixels().map(|(_, _, color)| color as u8).collect();
+        PyArray1::from_vec(py, pixels).reshape([graphics.height(), graphics.width()])
+    }
+
+    fn register(&self, register: usize) -> PyResult<u8> {
+        self.cpu.cpu_registers.get(register).copied().ok_or_else(|| value_error(format!("V{} doesn't exist", register)))
+    }
+
+    fn set_register(&mut self, register: usize, value: u8) -> PyResult<()> {
+        let slot = self.cpu.cpu_registers.get_mut(register).ok_or_else(|| value_error(format!("V{} doesn't exist", register)))?;
+        *slot = value;
+        Ok(())
+    }
+
+    /// V0 to VF
+    #[getter]
+    fn registers(&self) -> Vec<u8> {
+        self.cpu.cpu_registers.to_vec()
+    }
+
+    #[getter]
+    fn pc(&self) -> u16 {
+        self.cpu.program_counter
+    }
+
+    #[setter]
+    fn set_pc(&mut self, address: u16) {
+        self.cpu.program_counter = address;
+    }
+
+    #[getter]
+    fn index(&self) -> u16 {
+        self.cpu.index_register
+    }
+
+    #[setter]
+    fn set_index(&mut self, address: u16) {
+        self.cpu.index_register = address;
+    }
+
+    #[getter]
+    fn delay_timer(&self) -> u8 {
+        self.cpu.delay_timer
+    }
+
+    #[setter]
+    fn set_delay_timer(&mut self, value: u8) {
+        self.cpu.delay_timer = value;
+    }
+
+    #[getter]
+    fn sound_timer(&self) -> u8 {
+        self.cpu.sound_timer
+    }
+
+    #[setter]
+    fn set_sound_timer(&mut self, value: u8) {
+        self.cpu.sound_timer = value;
+    }
+
+    /// Whether the CPU is halted on an `Fx0A` until a key is pressed and released
+    #[getter]
+    fn waiting_for_key(&self) -> bool {
+        self.cpu.waiting_for_key()
+    }
+
+    /// Reads `length` bytes of memory from `address`
+    #[pyo3(signature = (address, length = 1))]
+    fn read_memory<'py>(&self, py: Python<'py>, address: usize, length: usize) -> PyResult<Bound<'py, PyBytes>> {
+        let bytes = self.cpu.memory.get(address..address.saturating_add(length)).ok_or_else(|| outside_memory(address, length))?;
+        Ok(PyBytes::new(py, bytes))
+    }
+
+    /// Writes bytes to memory from `address`
+    fn write_memory(&mut self, address: usize, data: &[u8]) -> PyResult<()> {
+        if address.saturating_add(data.len()) > MEMORY_SIZE {
+            return Err(outside_memory(address, data.len()));
+        }
+        for (offset, &byte) in data.iter().enumerate() {
+            self.cpu.write_byte((address + offset) as u16, byte);
+        }
+        Ok(())
+    }
+
+    fn press(&mut self, key: usize) -> PyResult<()> {
+        self.set_key(key, true)
+    }
+
+    fn release(&mut self, key: usize) -> PyResult<()> {
+        self.set_key(key, false)
+    }
+
+    fn set_key(&mut self, key: usize, pressed: bool) -> PyResult<()> {
+        if key >= 16 {
+            return Err(value_error(format!("key {} doesn't exist, keys go from 0 to 15", key)));
+        }
+        self.cpu.set_key(key, pressed);
+        Ok(())
+    }
+
+    /// The machine's state, to be restored with `load_state`
+    fn save_state<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.cpu.save_state())
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> PyResult<()> {
+        self.cpu.load_state(state).map_err(value_error)
+    }
+}
+
+fn value_error(message: String) -> PyErr {
+    PyValueError::new_err(message)
+}
+
+fn outside_memory(address: usize, length: usize) -> PyErr {
+    value_error(format!("{} bytes from {:#05X} go past the end of memory", length, address))
+}
+
+#[pymodule]
+fn chip8(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyCpu>()
+}
@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+
+/// Behaviors that differ between CHIP-8 interpreters. Games written for one platform often depend on its quirks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quirks {
+    /// 8xy6/8xyE shift Vy and store the result in Vx instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    /// Fx55/Fx65 leave I pointing just past the last register they touched
+    pub load_store_increments_i: bool,
+    /// With `load_store_increments_i`, I only moves along by x instead of x + 1, like on CHIP-48
+    pub load_store_increments_by_x: bool,
+    /// Bnnn jumps to nnn + Vx, where x is the highest nibble of nnn, instead of nnn + V0
+    pub jump_uses_vx: bool,
+    /// 8xy1/8xy2/8xy3 reset VF to zero
+    pub logic_resets_vf: bool,
+    /// Sprites wrap around to the opposite edge of the screen instead of being clipped
+    pub wrap_sprites: bool,
+    /// Dxyn waits for the next 60Hz frame like it waits for the vertical blank on the COSMAC VIP,
+    /// so at most one sprite is drawn per frame
+    pub display_wait: bool
+}
+
+/// Names accepted by `Quirks::from_profile`
+pub const PROFILES: [&str; 4] = ["default", "chip8", "chip48", "schip"];
+
+/// The quirks this emulator has always used
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            load_store_increments_by_x: false,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            wrap_sprites: false,
+            display_wait: false
+        }
+    }
+}
+
+impl Quirks {
+    /// The original COSMAC VIP interpreter
+    pub fn chip8() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            load_store_increments_by_x: false,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            wrap_sprites: false,
+            display_wait: true
+        }
+    }
+
+    /// CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: true,
+            load_store_increments_by_x: true,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            wrap_sprites: false,
+            display_wait: false
+        }
+    }
+
+    /// SUPER-CHIP 1.1
+    pub fn schip() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            load_store_increments_by_x: false,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            wrap_sprites: false,
+            display_wait: false
+        }
+    }
+
+    pub fn from_profile(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "default" => Some(Quirks::default()),
+            "chip8" | "chip-8" | "vip" => Some(Quirks::chip8()),
+            "chip48" | "chip-48" => Some(Quirks::chip48()),
+            "schip" | "superchip" => Some(Quirks::schip()),
+            _ => None
+        }
+    }
+
+    /// Name of the profile these quirks match, if any
+    pub fn profile_name(&self) -> Option<&'static str> {
+        PROFILES.iter().cloned().find(|&name| Quirks::from_profile(name) == Some(*self))
+    }
+}
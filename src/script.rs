@@ -0,0 +1,365 @@
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+use std::ptr;
+use std::rc::Rc;
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST, INT};
+use crate::cpu::{Cpu, MEMORY_SIZE};
+use crate::symbols::Symbols;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// A Rhai script driving the emulator. Its top level runs once when it's loaded, then the emulator calls
+/// whichever of these it defines:
+///
+/// - `on_frame(frame)` after every 60Hz frame, counting from 1
+/// - `on_instruction(pc)` before every instruction
+/// - `on_breakpoint(pc)` when a breakpoint is hit, after which running carries on unless the script calls `stop()`
+///
+/// Rhai functions can't see variables outside them, so the hooks get `this`, a map that's kept between calls for
+/// whatever the script wants to remember.
+///
+/// Scripts read and change the machine with functions like `register(n)`, `peek(address)`, `press(key)` and
+/// `add_breakpoint(address)`. The CPU is lent to the script only while it runs, so the functions always see
+/// the machine as it is at that moment
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    /// `this` in the hooks
+    state: Dynamic,
+    cpu: CpuHandle,
+    stop_requested: Rc<Cell<bool>>,
+    frame: INT,
+    /// Where `on_instruction` was last called, until that instruction runs, so a breakpoint or `stop()` in
+    /// between doesn't call it twice
+    announced: Option<u16>,
+    on_frame: bool,
+    on_instruction: bool,
+    on_breakpoint: bool
+}
+
+impl Script {
+    /// Compiles the script and runs its top level against the CPU
+    pub fn load(path: &Path, cpu: &mut Cpu, symbols: &Symbols) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|error| format!("can't read {}: {}", path.display(), error))?;
+        Script::compile(&text, cpu, symbols).map_err(|error| format!("{}: {}", path.display(), error))
+    }
+
+    fn compile(text: &str, cpu: &mut Cpu, symbols: &Symbols) -> Result<Self, String> {
+        let mut engine = Engine::new();
+        let handle = CpuHandle(Rc::new(Cell::new(ptr::null_mut())));
+        let stop_requested = Rc::new(Cell::new(false));
+        register_functions(&mut engine, &handle, &stop_requested, symbols);
+
+        let ast = engine.compile(text).map_err(|error| error.to_string())?;
+        let defines = |name: &str| ast.iter_functions().any(|function| function.name == name && function.params.len() == 1);
+        let (on_frame, on_instruction, on_breakpoint) = (defines("on_frame"), defines("on_instruction"), defines("on_breakpoint"));
+
+        let mut script = Script {
+            engine,
+            ast,
+            scope: Scope::new(),
+            state: Dynamic::from_map(Map::new()),
+            cpu: handle,
+            stop_requested,
+            frame: 0,
+            announced: None,
+            on_frame,
+            on_instruction,
+            on_breakpoint
+        };
+
+        let (engine, scope, ast) = (&script.engine, &mut script.scope, &script.ast);
+        script.cpu.lend(cpu, || engine.run_ast_with_scope(scope, ast)).map_err(|error| error.to_string())?;
+
+        Ok(script)
+    }
+
+    /// Runs a 60Hz frame like `Cpu::run_frame`, calling the script's hooks along the way. Returns whether the
+    /// buzzer sounded, and stops early if the script asked to or a breakpoint it doesn't handle was hit
+    pub fn run_frame(&mut self, cpu: &mut Cpu, instructions_per_frame: usize) -> Result<bool, String> {
+        if !self.on_instruction && !self.on_breakpoint {
+            cpu.run_instructions(instructions_per_frame);
+        } else {
+            let mut remaining = instructions_per_frame;
+            while remaining > 0 && !self.stop_requested.get() && !cpu.waiting_for_vblank() && !cpu.waiting_for_key() {
+                if self.on_instruction && self.announced != Some(cpu.program_counter) {
+                    self.announced = Some(cpu.program_counter);
+                    self.call(cpu, "on_instruction", cpu.program_counter)?;
+                    if self.stop_requested.get() {
+                        break;
+                    }
+                }
+
+                cpu.run_instructions(1);
+                match cpu.breakpoint_hit() {
+                    Some(address) if self.on_breakpoint => self.call(cpu, "on_breakpoint", address)?,
+                    Some(_) => break,
+                    // The next run steps past the breakpoint, so this doesn't count towards the frame
+                    None => {
+                        self.announced = None;
+                        remaining -= 1;
+                    }
+                }
+            }
+        }
+
+        let sounding = cpu.sound_timer > 0;
+        cpu.update_timers();
+
+        self.frame += 1;
+        if self.on_frame {
+            self.call(cpu, "on_frame", self.frame)?;
+        }
+        Ok(sounding)
+    }
+
+    /// Whether the script called `stop()` since this was last asked
+    pub fn take_stop_request(&mut self) -> bool {
+        self.stop_requested.replace(false)
+    }
+
+    fn call(&mut self, cpu: &mut Cpu, name: &str, argument: impl Into<INT>) -> Result<(), String> {
+        // The top level already ran when the script was loaded
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut self.state);
+        let (engine, scope, ast) = (&self.engine, &mut self.scope, &self.ast);
+        let argument = argument.into();
+        let result = self.cpu.lend(cpu, || engine.call_fn_with_options::<Dynamic>(options, scope, ast, name, (argument,)));
+        result.map(|_| ()).map_err(|error| format!("{}: {}", name, error))
+    }
+}
+
+/// The emulator's CPU as the script's functions see it: lent for as long as the script runs and null otherwise,
+/// so nothing has to be copied in and out around every call
+#[derive(Clone)]
+struct CpuHandle(Rc<Cell<*mut Cpu>>);
+
+impl CpuHandle {
+    fn lend<T>(&self, cpu: &mut Cpu, run: impl FnOnce() -> T) -> T {
+        /// Takes the CPU back even if the script panics
+        struct Return<'a>(&'a Cell<*mut Cpu>);
+        impl Drop for Return<'_> {
+            fn drop(&mut self) {
+                self.0.set(ptr::null_mut());
+            }
+        }
+
+        self.0.set(cpu);
+        let _returned = Return(&self.0);
+        run()
+    }
+
+    fn with<T>(&self, use_cpu: impl FnOnce(&mut Cpu) -> T) -> ScriptResult<T> {
+        // The pointer is only set while `lend` holds the emulator's `&mut Cpu`, and the script's functions
+        // run one at a time, so this is the only reference to it
+        match unsafe { self.0.get().as_mut() } {
+            Some(cpu) => Ok(use_cpu(cpu)),
+            None => Err("the CPU is only there while the script runs".into())
+        }
+    }
+}
+
+fn register_functions(engine: &mut Engine, cpu: &CpuHandle, stop_requested: &Rc<Cell<bool>>, symbols: &Symbols) {
+    let shared = cpu.clone();
+    engine.register_fn("register", move |register: INT| -> ScriptResult<INT> {
+        let register = check(register, 16, "a register")?;
+        shared.with(|cpu| cpu.cpu_registers[register] as INT)
+    });
+    let shared = cpu.clone();
+    engine.register_fn("set_register", move |register: INT, value: INT| -> ScriptResult<()> {
+        let (register, value) = (check(register, 16, "a register")?, check(value, 0x100, "a byte")?);
+        shared.with(|cpu| cpu.cpu_registers[register] = value as u8)
+    });
+
+    let shared = cpu.clone();
+    engine.register_fn("pc", move || shared.with(|cpu| cpu.program_counter as INT));
+    let shared = cpu.clone();
+    engine.register_fn("set_pc", move |address: INT| -> ScriptResult<()> {
+        let address = check(address, MEMORY_SIZE, "an address")?;
+        shared.with(|cpu| cpu.program_counter = address as u16)
+    });
+    let shared = cpu.clone();
+    engine.register_fn("index", move || shared.with(|cpu| cpu.index_register as INT));
+    let shared = cpu.clone();
+    engine.register_fn("set_index", move |address: INT| -> ScriptResult<()> {
+        let address = check(address, 0x10000, "an address")?;
+        shared.with(|cpu| cpu.index_register = address as u16)
+    });
+
+    let shared = cpu.clone();
+    engine.register_fn("delay_timer", move || shared.with(|cpu| cpu.delay_timer as INT));
+    let shared = cpu.clone();
+    engine.register_fn("set_delay_timer", move |value: INT| -> ScriptResult<()> {
+        let value = check(value, 0x100, "a byte")?;
+        shared.with(|cpu| cpu.delay_timer = value as u8)
+    });
+    let shared = cpu.clone();
+    engine.register_fn("sound_timer", move || shared.with(|cpu| cpu.sound_timer as INT));
+    let shared = cpu.clone();
+    engine.register_fn("set_sound_timer", move |value: INT| -> ScriptResult<()> {
+        let value = check(value, 0x100, "a byte")?;
+        shared.with(|cpu| cpu.sound_timer = value as u8)
+    });
+
+    let shared = cpu.clone();
+    engine.register_fn("peek", move |address: INT| -> ScriptResult<INT> {
+        let address = check(address, MEMORY_SIZE, "an address")?;
+        shared.with(|cpu| cpu.memory[address] as INT)
+    });
+    let shared = cpu.clone();
+    engine.register_fn("poke", move |address: INT, value: INT| -> ScriptResult<()> {
+        let (address, value) = (check(address, MEMORY_SIZE, "an address")?, check(value, 0x100, "a byte")?);
+        shared.with(|cpu| cpu.write_byte(address as u16, value as u8))
+    });
+
+    let shared = cpu.clone();
+    engine.register_fn("key", move |key: INT| -> ScriptResult<bool> {
+        let key = check(key, 16, "a key")?;
+        shared.with(|cpu| cpu.keypad[key])
+    });
+    let shared = cpu.clone();
+    engine.register_fn("press", move |key: INT| -> ScriptResult<()> {
+        let key = check(key, 16, "a key")?;
+        shared.with(|cpu| cpu.set_key(key, true))
+    });
+    let shared = cpu.clone();
+    engine.register_fn("release", move |key: INT| -> ScriptResult<()> {
+        let key = check(key, 16, "a key")?;
+        shared.with(|cpu| cpu.set_key(key, false))
+    });
+
+    let shared = cpu.clone();
+    engine.register_fn("screen_width", move || shared.with(|cpu| cpu.graphics.width() as INT));
+    let shared = cpu.clone();
+    engine.register_fn("screen_height", move || shared.with(|cpu| cpu.graphics.height() as INT));
+    let shared = cpu.clone();
+    engine.register_fn("pixel", move |x: INT, y: INT| -> ScriptResult<bool> {
+        shared.with(|cpu| {
+            let x = check(x, cpu.graphics.width(), "an x coordinate")?;
+            let y = check(y, cpu.graphics.height(), "a y coordinate")?;
+            Ok(cpu.graphics.pixel(x, y))
+        })?
+    });
+
+    let shared = cpu.clone();
+    engine.register_fn("add_breakpoint", move |address: INT| -> ScriptResult<()> {
+        let address = check(address, MEMORY_SIZE, "an address")?;
+        shared.with(|cpu| {
+            cpu.breakpoints.insert(address as u16);
+        })
+    });
+    let shared = cpu.clone();
+    engine.register_fn("remove_breakpoint", move |address: INT| -> ScriptResult<()> {
+        let address = check(address, MEMORY_SIZE, "an address")?;
+        shared.with(|cpu| {
+            cpu.breakpoints.remove(&(address as u16));
+        })
+    });
+
+    let symbols = symbols.clone();
+    engine.register_fn("address", move |label: &str| -> ScriptResult<INT> { Ok(symbols.resolve(label)? as INT) });
+
+    let stop_requested = stop_requested.clone();
+    engine.register_fn("stop", move || stop_requested.set(true));
+}
+
+/// Checks a number from the script is below `limit`, naming what it should be if it isn't
+fn check(value: INT, limit: usize, what: &str) -> ScriptResult<usize> {
+    match usize::try_from(value) {
+        Ok(value) if value < limit => Ok(value),
+        _ => Err(format!("{} isn't {}", value, what).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A CPU running the program, and the script loaded against it
+    fn load(program: &[u8], script: &str) -> (Cpu, Script) {
+        let mut cpu = Cpu::new();
+        cpu.seed_rng(0);
+        cpu.load_rom(program.to_vec()).unwrap();
+        let script = Script::compile(script, &mut cpu, &Symbols::new()).unwrap();
+        (cpu, script)
+    }
+
+    /// The addresses a script's hooks pushed onto `this.<name>`
+    fn logged(script: &Script, name: &str) -> Vec<INT> {
+        let state = script.state.read_lock::<Map>().unwrap();
+        state.get(name).map_or_else(Vec::new, |log| log.clone().into_array().unwrap().into_iter().map(|pc| pc.as_int().unwrap()).collect())
+    }
+
+    const LOGGING: &str = r#"
+        fn log(name, pc) {
+            if type_of(this[name]) == "()" {
+                this[name] = [];
+            }
+            this[name].push(pc);
+        }
+        fn on_instruction(pc) { this.log("instructions", pc); }
+        fn on_breakpoint(pc) { this.log("breakpoints", pc); }
+    "#;
+
+    #[test]
+    fn the_top_level_runs_against_the_cpu() {
+        let (cpu, _) = load(&[0x12, 0x00], "set_register(3, 7); poke(0x300, pc() >> 4);");
+        assert_eq!(cpu.cpu_registers[3], 7);
+        assert_eq!(cpu.memory[0x300], 0x20);
+    }
+
+    #[test]
+    fn instruction_hooks_fire_once_per_instruction_even_at_breakpoints() {
+        // LD V0, 1, then ADD V0, 1 twice and a jump to itself
+        let (mut cpu, mut script) = load(&[0x60, 0x01, 0x70, 0x01, 0x70, 0x01, 0x12, 0x06], LOGGING);
+        cpu.breakpoints.insert(0x202);
+        script.run_frame(&mut cpu, 4).unwrap();
+
+        assert_eq!(logged(&script, "instructions"), vec![0x200, 0x202, 0x204, 0x206]);
+        assert_eq!(logged(&script, "breakpoints"), vec![0x202]);
+        assert_eq!(cpu.cpu_registers[0], 3);
+    }
+
+    #[test]
+    fn unhandled_breakpoints_end_the_frame_without_repeating_the_hook() {
+        let script = "fn on_instruction(pc) { set_register(0xE, register(0xE) + 1); }";
+        let (mut cpu, mut script) = load(&[0x60, 0x01, 0x70, 0x01, 0x12, 0x04], script);
+        cpu.breakpoints.insert(0x202);
+
+        script.run_frame(&mut cpu, 10).unwrap();
+        assert_eq!((cpu.breakpoint_hit(), cpu.cpu_registers[0xE]), (Some(0x202), 2));
+
+        // Picking up from the breakpoint runs ADD V0, 1 without announcing it again
+        script.run_frame(&mut cpu, 2).unwrap();
+        assert_eq!((cpu.cpu_registers[0], cpu.cpu_registers[0xE]), (2, 3));
+    }
+
+    #[test]
+    fn scripts_press_keys_and_stop_the_emulator() {
+        let script = r#"
+            fn on_frame(frame) {
+                if frame == 1 { press(5); }
+                if frame == 2 { release(5); }
+                if frame == 3 { stop(); }
+            }
+        "#;
+        // LD V0, K and a jump to itself
+        let (mut cpu, mut script) = load(&[0xF0, 0x0A, 0x12, 0x02], script);
+        for _ in 0..3 {
+            script.run_frame(&mut cpu, 10).unwrap();
+        }
+
+        assert_eq!(cpu.cpu_registers[0], 5);
+        assert!(script.take_stop_request());
+        assert!(!script.take_stop_request());
+    }
+
+    #[test]
+    fn bad_arguments_are_script_errors() {
+        let (mut cpu, mut script) = load(&[0x12, 0x00], "fn on_frame(frame) { poke(0x1000, 1); }");
+        let error = script.run_frame(&mut cpu, 1).unwrap_err();
+        assert!(error.contains("4096 isn't an address"), "{}", error);
+    }
+}
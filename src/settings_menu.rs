@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use ggez::event::KeyCode;
+use crate::config::{QuirkSetting, Settings};
+use crate::display::{Emulator, FRAMES_PER_SECOND};
+use crate::keymap::{self, Keymap};
+use crate::quirks::{self, Quirks};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Item {
+    Clock,
+    Quirks,
+    Palette,
+    Keymap,
+    SaveForRom,
+    SaveAsDefaults,
+    Close
+}
+
+const ITEMS: [Item; 7] = [Item::Clock, Item::Quirks, Item::Palette, Item::Keymap, Item::SaveForRom, Item::SaveAsDefaults, Item::Close];
+
+/// The in-app settings menu. Up and Down pick an item, Left and Right change it and Enter saves or closes.
+/// On the keymap item, Left and Right pick a CHIP-8 key and Enter waits for the host key to press it with.
+/// Changes apply right away but only last past this run once saved to the config file
+pub struct SettingsMenu {
+    selected: usize,
+    message: Option<String>,
+    chip8_key: usize, // The CHIP-8 key shown on the keymap item
+    rebinding: bool // Whether the next key pressed gets bound to `chip8_key`
+}
+
+impl SettingsMenu {
+    pub fn new() -> Self {
+        SettingsMenu {
+            selected: 0,
+            message: None,
+            chip8_key: 0,
+            rebinding: false
+        }
+    }
+
+    /// Lines of text to show, with the selected item marked
+    pub fn lines(&self, emulator: &Emulator) -> Vec<String> {
+        let mut lines = vec![String::from("Settings"), String::new()];
+
+        for (index, &item) in ITEMS.iter().enumerate() {
+            let label = match item {
+                Item::Clock => format!("Clock:   < {} Hz >", emulator.instructions_per_frame as u32 * FRAMES_PER_SECOND),
+                Item::Quirks => format!("Quirks:  < {} >", emulator.cpu.quirks.profile_name().unwrap_or("custom")),
+                Item::Palette => format!("Palette: < {} >", emulator.palette.preset_name().unwrap_or("custom")),
+                Item::Keymap if self.rebinding => format!("Key {:X}:   press a key, Escape cancels", self.chip8_key),
+                Item::Keymap => {
+                    let host_keys = emulator.keymap.host_keys(self.chip8_key);
+                    let host_keys = if host_keys.is_empty() { String::from("none") } else { host_keys.join(", ") };
+                    format!("Key {:X}:   < {} >", self.chip8_key, host_keys)
+                },
+                Item::SaveForRom => String::from("Save for this ROM"),
+                Item::SaveAsDefaults => String::from("Save as defaults"),
+                Item::Close => String::from("Close")
+            };
+            let marker = if index == self.selected { ">" } else { " " };
+            lines.push(format!("{} {}", marker, label));
+        }
+
+        lines.push(String::new());
+        lines.push(self.message.clone().unwrap_or_else(|| String::from("Up/Down select, Left/Right change, Enter confirm, F1 close")));
+        lines
+    }
+
+    /// Handles a key press, returning whether the menu stays open
+    pub fn handle_key(&mut self, emulator: &mut Emulator, keycode: KeyCode) -> bool {
+        if self.rebinding {
+            self.rebind(emulator, keycode);
+            return true;
+        }
+
+        match keycode {
+            KeyCode::Up => self.selected = (self.selected + ITEMS.len() - 1) % ITEMS.len(),
+            KeyCode::Down => self.selected = (self.selected + 1) % ITEMS.len(),
+            KeyCode::Left => self.change(emulator, false),
+            KeyCode::Right => self.change(emulator, true),
+            KeyCode::Return => match ITEMS[self.selected] {
+                Item::Keymap => {
+                    self.rebinding = true;
+                    self.message = None;
+                },
+                Item::SaveForRom => self.save(emulator, true),
+                Item::SaveAsDefaults => self.save(emulator, false),
+                Item::Close => return false,
+                _ => ()
+            },
+            KeyCode::Escape | KeyCode::F1 => return false,
+            _ => ()
+        }
+
+        true
+    }
+
+    fn change(&mut self, emulator: &mut Emulator, increase: bool) {
+        match ITEMS[self.selected] {
+            Item::Clock => {
+                emulator.instructions_per_frame = if increase {
+                    emulator.instructions_per_frame + 1
+                } else {
+                    emulator.instructions_per_frame.saturating_sub(1).max(1)
+                };
+            },
+            Item::Quirks => {
+                let current = emulator.cpu.quirks.profile_name().and_then(|name| quirks::PROFILES.iter().position(|&profile| profile == name));
+                let count = quirks::PROFILES.len();
+                let next = match current {
+                    Some(index) if increase => (index + 1) % count,
+                    Some(index) => (index + count - 1) % count,
+                    None => 0
+                };
+                emulator.cpu.quirks = Quirks::from_profile(quirks::PROFILES[next]).expect("PROFILES only holds known profiles");
+            },
+            Item::Palette => {
+                emulator.palette = if increase { emulator.palette.next_preset() } else { emulator.palette.previous_preset() };
+            },
+            Item::Keymap => {
+                self.chip8_key = if increase { (self.chip8_key + 1) % 16 } else { (self.chip8_key + 15) % 16 };
+            },
+            _ => ()
+        }
+    }
+
+    /// Binds the pressed key to the CHIP-8 key on the keymap item in place of the keys bound to it before
+    fn rebind(&mut self, emulator: &mut Emulator, keycode: KeyCode) {
+        self.rebinding = false;
+        let name = keymap::key_name(keycode);
+        self.message = Some(if keycode == KeyCode::Escape {
+            String::from("Kept the old key")
+        } else if !keymap::is_nameable(keycode) {
+            format!("{} can't be used in a keymap", name)
+        } else {
+            match emulator.keymap.rebind(self.chip8_key, keycode) {
+                Some(previous) => format!("{} now presses {:X} instead of {:X}", name, self.chip8_key, previous),
+                None => format!("{} now presses {:X}", name, self.chip8_key)
+            }
+        });
+    }
+
+    /// Writes the current settings to the ROM's section or the global defaults, leaving out the palette and
+    /// keymap when they're what would be used anyway
+    fn save(&mut self, emulator: &mut Emulator, for_rom: bool) {
+        let config = match &mut emulator.config {
+            Some(config) => config,
+            None => {
+                self.message = Some(String::from("The config file isn't in use"));
+                return;
+            }
+        };
+
+        let fallback = if for_rom { config.defaults.clone() } else { Settings::default() };
+        let fallback_palette = fallback.parse_palette().ok().flatten().unwrap_or_default();
+        let fallback_keymap = fallback.parse_keymap().ok().flatten().unwrap_or_default();
+
+        let clock = emulator.instructions_per_frame as u32 * FRAMES_PER_SECOND;
+        let quirks = QuirkSetting::from_quirks(emulator.cpu.quirks);
+        let palette = if emulator.palette == fallback_palette { None } else { Some(emulator.palette.to_string()) };
+        let keymap = if emulator.keymap == fallback_keymap { None } else { Some(keymap_table(&emulator.keymap)) };
+
+        let settings = if for_rom {
+            match &emulator.rom_hash {
+                Some(hash) => config.rom_settings_mut(hash),
+                None => {
+                    self.message = Some(String::from("No ROM to save settings for"));
+                    return;
+                }
+            }
+        } else {
+            &mut config.defaults
+        };
+
+        settings.clock = Some(clock);
+        settings.quirks = Some(quirks);
+        settings.palette = palette;
+        settings.keymap = keymap;
+
+        self.message = Some(match config.save() {
+            Ok(path) => format!("Saved to {}", path.display()),
+            Err(error) => error
+        });
+    }
+}
+
+impl Default for SettingsMenu {
+    fn default() -> Self {
+        SettingsMenu::new()
+    }
+}
+
+fn keymap_table(keymap: &Keymap) -> BTreeMap<String, String> {
+    keymap.entries().into_iter().map(|(chip8_key, host_key)| (host_key, format!("{:X}", chip8_key))).collect()
+}
@@ -0,0 +1,100 @@
+use ggez::event::KeyCode;
+use crate::cpu::Cpu;
+use crate::palette::Palette;
+use crate::sprites::Sprite;
+
+const MAX_ROWS: usize = 15;
+/// Entries listed at once, the rest scroll
+const VISIBLE_ENTRIES: usize = 8;
+/// Size of a sprite pixel in saved PNGs
+const EXPORT_SCALE: usize = 8;
+
+/// Lists the sprites drawn so far, plus the bytes at I, and shows the selected one as a bitmap.
+/// Up and Down pick a sprite and E saves it as a PNG
+pub struct SpriteViewer {
+    /// 0 is the sprite at I, then the drawn sprites in address order
+    selected: usize,
+    /// Rows shown at I
+    rows: usize,
+    /// Whether the bytes at I are shown as a 16x16 sprite
+    large: bool,
+    message: Option<String>
+}
+
+impl SpriteViewer {
+    pub fn new() -> Self {
+        SpriteViewer {
+            selected: 0,
+            rows: MAX_ROWS,
+            large: false,
+            message: None
+        }
+    }
+
+    fn at_index(&self, cpu: &Cpu) -> Sprite {
+        if self.large { Sprite::large(cpu.index_register) } else { Sprite::new(cpu.index_register, self.rows) }
+    }
+
+    /// The sprite to show, which stays put when more get drawn because they're kept in address order
+    pub fn selected(&self, cpu: &Cpu) -> Sprite {
+        match self.selected {
+            0 => self.at_index(cpu),
+            index => cpu.sprites_drawn.iter().nth(index - 1).cloned().unwrap_or_else(|| self.at_index(cpu))
+        }
+    }
+
+    /// Lines of text to show, with the selected sprite marked
+    pub fn lines(&self, cpu: &Cpu) -> Vec<String> {
+        let mut lines = vec![
+            format!("Sprites - {} drawn so far", cpu.sprites_drawn.len()),
+            String::new()
+        ];
+
+        let entries = std::iter::once(format!("At I: {}", self.at_index(cpu)))
+            .chain(cpu.sprites_drawn.iter().map(Sprite::to_string));
+        let first = self.selected.saturating_sub(VISIBLE_ENTRIES - 1);
+        for (index, entry) in entries.enumerate().skip(first).take(VISIBLE_ENTRIES) {
+            let marker = if index == self.selected { ">" } else { " " };
+            lines.push(format!("{} {}", marker, entry));
+        }
+
+        lines.push(String::new());
+        if let Some(message) = &self.message {
+            lines.push(message.clone());
+        }
+        lines.push(String::from("Up/Down select, Left/Right rows at I, L 16x16"));
+        lines.push(String::from("E save as PNG, F7 close"));
+        lines
+    }
+
+    /// Handles a key press, returning whether the viewer used it
+    pub fn handle_key(&mut self, cpu: &Cpu, palette: &Palette, keycode: KeyCode) -> bool {
+        let count = cpu.sprites_drawn.len() + 1;
+        self.message = None;
+
+        match keycode {
+            KeyCode::Up => self.selected = (self.selected.min(count - 1) + count - 1) % count,
+            KeyCode::Down => self.selected = (self.selected + 1) % count,
+            KeyCode::Left => self.rows = (self.rows - 1).max(1),
+            KeyCode::Right => self.rows = (self.rows + 1).min(MAX_ROWS),
+            KeyCode::L => self.large = !self.large,
+            KeyCode::E => {
+                let sprite = self.selected(cpu);
+                let path = sprite.file_name();
+                self.message = Some(match sprite.save_png(&path, &cpu.memory, palette, EXPORT_SCALE) {
+                    Ok(()) => format!("Saved {}", path.display()),
+                    Err(error) => error
+                });
+            },
+            _ => return false
+        }
+
+        true
+    }
+}
+
+impl Default for SpriteViewer {
+    fn default() -> Self {
+        SpriteViewer::new()
+    }
+}
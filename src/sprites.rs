@@ -0,0 +1,89 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use crate::capture;
+use crate::cpu::MEMORY_SIZE;
+use crate::palette::Palette;
+
+/// A bitmap in memory: `height` rows of one byte for 8 pixel wide sprites, or two bytes for 16 pixel wide ones.
+/// Set bits are lit pixels, most significant bit on the left
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Sprite {
+    pub address: u16,
+    pub width: usize,
+    pub height: usize
+}
+
+impl Sprite {
+    /// An 8 pixel wide sprite as `DRW` draws it
+    pub fn new(address: u16, height: usize) -> Self {
+        Sprite { address, width: 8, height }
+    }
+
+    /// A 16x16 sprite in the SCHIP layout
+    pub fn large(address: u16) -> Self {
+        Sprite { address, width: 16, height: 16 }
+    }
+
+    /// Number of bytes the sprite takes up
+    pub fn len(&self) -> usize {
+        self.width / 8 * self.height
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Rows of pixels, reading past the end of memory wrapping around to the start like `I` does
+    pub fn pixels(&self, memory: &[u8]) -> Vec<Vec<bool>> {
+        let bytes_per_row = self.width / 8;
+        (0..self.height)
+            .map(|row| {
+                (0..bytes_per_row)
+                    .flat_map(|column| {
+                        let byte = memory[(self.address as usize + row * bytes_per_row + column) % MEMORY_SIZE];
+                        (0..8).map(move |bit| byte & (0x80 >> bit) != 0)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The sprite drawn with `#` and `.`, one row per line
+    pub fn to_text(&self, memory: &[u8]) -> String {
+        self.pixels(memory)
+            .iter()
+            .map(|row| row.iter().map(|&lit| if lit { '#' } else { '.' }).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Saves the sprite as a PNG in the palette's foreground and background, scaled up by `scale`
+    pub fn save_png(&self, path: &Path, memory: &[u8], palette: &Palette, scale: usize) -> Result<(), String> {
+        let mut rgb = Vec::with_capacity(self.width * self.height * scale * scale * 3);
+        for row in self.pixels(memory) {
+            let line: Vec<u8> = row
+                .iter()
+                .flat_map(|&lit| {
+                    let color = if lit { palette.foreground() } else { palette.background() };
+                    [color.r, color.g, color.b].repeat(scale)
+                })
+                .collect();
+            for _ in 0..scale {
+                rgb.extend_from_slice(&line);
+            }
+        }
+
+        capture::save_png(path, self.width * scale, self.height * scale, &rgb)
+    }
+
+    /// File name for an exported sprite, e.g. `sprite-2EA-8x6.png`
+    pub fn file_name(&self) -> PathBuf {
+        PathBuf::from(format!("sprite-{:03X}-{}x{}.png", self.address, self.width, self.height))
+    }
+}
+
+impl fmt::Display for Sprite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#05X} {}x{}", self.address, self.width, self.height)
+    }
+}
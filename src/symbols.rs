@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use crate::instruction::Instruction;
+
+/// Label names for addresses, from an assembler's symbol file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Symbols {
+    by_address: BTreeMap<u16, String>,
+    by_name: HashMap<String, u16>
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols::default()
+    }
+
+    /// Loads a JSON object of names to addresses, or text with one `address name` or `name address` pair per line
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|error| format!("can't read {}: {}", path.display(), error))?;
+        Symbols::parse(&text).map_err(|error| format!("{}: {}", path.display(), error))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if text.trim_start().starts_with('{') {
+            return Symbols::parse_json(text);
+        }
+
+        let mut symbols = Symbols::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(['#', ';']).next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let pair = match parts.as_slice() {
+                [first, second] => parse_number(first)
+                    .map(|address| (*second, address))
+                    .or_else(|| parse_number(second).map(|address| (*first, address))),
+                _ => None
+            };
+
+            match pair {
+                Some((name, address)) => symbols.insert(name, address),
+                None => return Err(format!("line {}: expected an address and a name, got '{}'", number + 1, line))
+            }
+        }
+        Ok(symbols)
+    }
+
+    fn parse_json(text: &str) -> Result<Self, String> {
+        let entries: BTreeMap<String, serde_json::Value> = serde_json::from_str(text).map_err(|error| error.to_string())?;
+        let mut symbols = Symbols::new();
+        for (name, value) in entries {
+            let address = match &value {
+                serde_json::Value::Number(number) => number.as_u64().filter(|&address| address <= 0xFFFF).map(|address| address as u16),
+                serde_json::Value::String(text) => parse_number(text),
+                _ => None
+            };
+            match address {
+                Some(address) => symbols.insert(&name, address),
+                None => return Err(format!("'{}' isn't an address for {}", value, name))
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Adds a label. When several name one address, the first one is shown
+    pub fn insert(&mut self, name: &str, address: u16) {
+        self.by_address.entry(address).or_insert_with(|| name.to_string());
+        self.by_name.insert(name.to_string(), address);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// The label at exactly this address
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.by_address.get(&address).map(String::as_str)
+    }
+
+    /// The nearest label at or before the address with the offset from it, like `main_loop+4`, or the address in hex
+    pub fn describe(&self, address: u16) -> String {
+        match self.by_address.range(..=address).next_back() {
+            Some((&start, name)) if start == address => name.clone(),
+            Some((&start, name)) => format!("{}+{}", name, address - start),
+            None => format!("{:#05X}", address)
+        }
+    }
+
+    /// Reads a label, `label+offset` or a number as an address
+    pub fn resolve(&self, text: &str) -> Result<u16, String> {
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => (name, parse_number(offset).ok_or_else(|| format!("'{}' isn't an offset", offset))?),
+            None => (text, 0)
+        };
+
+        match self.by_name.get(name) {
+            Some(&address) => Ok(address.wrapping_add(offset)),
+            None if offset == 0 => parse_number(text).ok_or_else(|| format!("'{}' isn't a label or an address", text)),
+            None => Err(format!("'{}' isn't a label", name))
+        }
+    }
+
+    /// The instruction with any address it refers to shown by label, e.g. `JP main_loop`
+    pub fn format_instruction(&self, instruction: Instruction) -> String {
+        let label = |address: u16| self.label(address).map(str::to_string).unwrap_or_else(|| format!("{:#05X}", address));
+        match instruction {
+            Instruction::Jp(address) => format!("JP {}", label(address)),
+            Instruction::Call(address) => format!("CALL {}", label(address)),
+            Instruction::LdIndex(address) => format!("LD I, {}", label(address)),
+            Instruction::JpOffset(address) => format!("JP V0, {}", label(address)),
+            _ => instruction.to_string()
+        }
+    }
+}
+
+/// Reads hex like `0x21A` or `$21A`, or decimal like `538`
+fn parse_number(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).or_else(|| text.strip_prefix('$')) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYMBOLS: &str = "# from the assembler\n0x200 main\nmain_loop $20A ; either order\nstart 512\n\ndraw 0x2F0\n";
+
+    #[test]
+    fn labels_resolve_to_addresses() {
+        let symbols = Symbols::parse(SYMBOLS).unwrap();
+
+        assert_eq!(symbols.resolve("main_loop"), Ok(0x20A));
+        assert_eq!(symbols.resolve("main_loop+4"), Ok(0x20E));
+        assert_eq!(symbols.resolve("draw+0x10"), Ok(0x300));
+        assert_eq!(symbols.resolve("0x2A0"), Ok(0x2A0));
+        assert_eq!(symbols.resolve("$2A0"), Ok(0x2A0));
+        assert_eq!(symbols.resolve("672"), Ok(0x2A0));
+        assert_eq!(symbols.resolve("nowhere"), Err(String::from("'nowhere' isn't a label or an address")));
+        assert_eq!(symbols.resolve("nowhere+2"), Err(String::from("'nowhere' isn't a label")));
+        assert_eq!(symbols.resolve("main+x"), Err(String::from("'x' isn't an offset")));
+    }
+
+    #[test]
+    fn addresses_are_described_from_the_nearest_label() {
+        let symbols = Symbols::parse(SYMBOLS).unwrap();
+
+        // Two labels name 0x200, and the first one is shown
+        assert_eq!(symbols.describe(0x200), "main");
+        assert_eq!(symbols.describe(0x20A), "main_loop");
+        assert_eq!(symbols.describe(0x210), "main_loop+6");
+        assert_eq!(symbols.describe(0x1FF), "0x1FF");
+        assert_eq!(symbols.format_instruction(Instruction::Jp(0x20A)), "JP main_loop");
+        assert_eq!(symbols.format_instruction(Instruction::Call(0x20C)), "CALL 0x20C");
+    }
+
+    #[test]
+    fn json_symbols_take_numbers_or_strings() {
+        let symbols = Symbols::parse(r#"{"main": 512, "draw": "0x2F0"}"#).unwrap();
+        assert_eq!((symbols.resolve("main"), symbols.resolve("draw")), (Ok(0x200), Ok(0x2F0)));
+
+        assert!(Symbols::parse(r#"{"main": 65536}"#).is_err());
+        assert_eq!(Symbols::parse("0x200 main extra"), Err(String::from("line 1: expected an address and a name, got '0x200 main extra'")));
+    }
+}
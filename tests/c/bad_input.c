@@ -0,0 +1,66 @@
+/* Feeds the C API input it should turn away and checks it says so. Prints the first check that fails */
+#include <stdio.h>
+#include <string.h>
+#include "chip8.h"
+
+#define CHECK(condition)                              \
+    if (!(condition)) {                               \
+        fprintf(stderr, "failed: %s\n", #condition);  \
+        return 1;                                     \
+    }
+
+int main(void) {
+    Chip8 *chip8 = chip8_create();
+    CHECK(chip8 != NULL);
+
+    CHECK(!chip8_set_quirks(chip8, "no such profile"));
+    CHECK(!chip8_set_quirks(chip8, NULL));
+    CHECK(chip8_set_quirks(chip8, "schip"));
+
+    CHECK(!chip8_set_key(chip8, 16, true));
+    CHECK(chip8_set_key(chip8, 15, true));
+
+    static unsigned char too_large[4096];
+    CHECK(!chip8_load_rom(chip8, too_large, sizeof too_large));
+    CHECK(!chip8_load_rom(chip8, NULL, 2));
+    CHECK(chip8_load_rom(chip8, NULL, 0));
+
+    /* V0 = 5, then add 1 to it forever */
+    const unsigned char rom[] = {0x60, 0x05, 0x70, 0x01, 0x12, 0x02};
+    CHECK(chip8_load_rom(chip8, rom, sizeof rom));
+    chip8_step(chip8, 3);
+
+    /* Too small a buffer is left alone, but the size still comes back */
+    unsigned char small[16];
+    memset(small, 0xAA, sizeof small);
+    size_t state_length = chip8_save_state(chip8, small, sizeof small);
+    CHECK(state_length > sizeof small);
+    CHECK(small[0] == 0xAA);
+
+    static unsigned char state[1 << 16];
+    CHECK(state_length <= sizeof state);
+    CHECK(chip8_save_state(chip8, state, sizeof state) == state_length);
+
+    const char *garbage = "{\"version\": 1}";
+    CHECK(!chip8_load_state(chip8, (const unsigned char *)garbage, strlen(garbage)));
+    CHECK(!chip8_load_state(chip8, NULL, 0));
+    CHECK(!chip8_load_state(chip8, state, state_length - 1));
+
+    /* The failed loads left the machine where it was, and a good one rewinds it */
+    static unsigned char unchanged[1 << 16];
+    CHECK(chip8_save_state(chip8, unchanged, sizeof unchanged) == state_length);
+    CHECK(memcmp(state, unchanged, state_length) == 0);
+    chip8_step(chip8, 100);
+    CHECK(chip8_load_state(chip8, state, state_length));
+    CHECK(chip8_save_state(chip8, unchanged, sizeof unchanged) == state_length);
+    CHECK(memcmp(state, unchanged, state_length) == 0);
+
+    CHECK(!chip8_run_frame(NULL, 10));
+    CHECK(chip8_screen_width(NULL) == 0);
+    CHECK(chip8_framebuffer(NULL) == NULL);
+    chip8_destroy(NULL);
+
+    chip8_destroy(chip8);
+    puts("ok");
+    return 0;
+}
@@ -0,0 +1,85 @@
+//! Compiles C programs against the generated header and the shared library: the example, which runs Pong, and a
+//! program feeding the API bad input. Run with `--no-default-features` too, which checks the library built
+//! without the frontend links nothing but the C runtime
+#![cfg(unix)]
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The directory holding the shared library Cargo built alongside this test: the one above the directory holding
+/// the test itself. `cargo test` builds every crate type the library has, so nothing needs building here
+fn library_directory() -> PathBuf {
+    let executable = env::current_exe().expect("The test knows where it is");
+    let directory = executable.parent().and_then(Path::parent).expect("Tests run from target/<profile>/deps");
+    let library = directory.join(format!("{}learn_rust{}", env::consts::DLL_PREFIX, env::consts::DLL_SUFFIX));
+    assert!(library.exists(), "{} wasn't built", library.display());
+    directory.to_path_buf()
+}
+
+/// Compiles a C file against the header and the shared library, returning the executable
+fn compile(source: &str) -> PathBuf {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let libraries = library_directory();
+    let name = Path::new(source).file_stem().expect("C sources have a name");
+    let executable = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let compiler = env::var("CC").unwrap_or_else(|_| String::from("cc"));
+
+    let status = Command::new(compiler)
+        .args(["-Wall", "-Wextra", "-Werror", "-std=c99"])
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg(root.join(source))
+        .arg("-L")
+        .arg(&libraries)
+        .arg("-llearn_rust")
+        .arg(format!("-Wl,-rpath,{}", libraries.display()))
+        .arg("-o")
+        .arg(&executable)
+        .status()
+        .expect("Unable to run the C compiler");
+    assert!(status.success(), "{} didn't compile", source);
+    executable
+}
+
+#[test]
+fn c_example_runs_pong() {
+    let rom = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/test_roms/Pong.ch8");
+    let output = Command::new(compile("examples/c/run_rom.c"))
+        .arg(rom)
+        .output()
+        .expect("Unable to run the C example");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "the C example failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let screen: Vec<&str> = stdout.lines().take_while(|line| !line.starts_with("Frames")).collect();
+    assert_eq!(screen.len(), 32);
+    assert!(screen.iter().all(|row| row.len() == 64));
+    assert!(screen.iter().any(|row| row.contains('#')), "nothing was drawn");
+}
+
+#[test]
+fn c_api_turns_away_bad_input() {
+    let output = Command::new(compile("tests/c/bad_input.c")).output().expect("Unable to run the C checks");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+/// Without the frontend the library is the emulator core alone, and mustn't drag in a window or audio stack
+#[cfg(all(target_os = "linux", not(feature = "frontend")))]
+#[test]
+fn core_library_links_no_gui_or_audio() {
+    let library = library_directory().join("liblearn_rust.so");
+    let output = Command::new("readelf").arg("-d").arg(&library).output().expect("Unable to run readelf");
+    let dynamic = String::from_utf8_lossy(&output.stdout);
+    let needed: Vec<&str> = dynamic
+        .lines()
+        .filter(|line| line.contains("(NEEDED)"))
+        .filter_map(|line| line.split('[').nth(1)?.split(']').next())
+        .collect();
+    assert!(!needed.is_empty(), "readelf found no dependencies: {}", dynamic);
+
+    let runtime = ["libc.so", "libm.so", "libgcc_s.so", "libpthread.so", "libdl.so", "librt.so", "ld-linux"];
+    for library in needed {
+        assert!(runtime.iter().any(|prefix| library.starts_with(prefix)), "the core library needs {}", library);
+    }
+}
@@ -0,0 +1,30 @@
+//! Checks the committed C header against what cbindgen generates from the C API, rewriting it instead when
+//! `CHIP8_UPDATE_HEADER` is set
+use std::env;
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn header_matches_the_c_api() {
+    let root = env!("CARGO_MANIFEST_DIR");
+    let config = cbindgen::Config::from_root_or_default(root);
+    let mut generated = Vec::new();
+    cbindgen::Builder::new()
+        .with_crate(root)
+        .with_config(config)
+        .generate()
+        .expect("Unable to generate the C header")
+        .write(&mut generated);
+
+    let header = Path::new(root).join("include/chip8.h");
+    if env::var_os("CHIP8_UPDATE_HEADER").is_some() {
+        fs::write(&header, &generated).expect("Unable to write the C header");
+        return;
+    }
+
+    let committed = fs::read(&header).expect("Unable to read the C header");
+    assert!(
+        committed == generated,
+        "include/chip8.h is out of date, regenerate it with `CHIP8_UPDATE_HEADER=1 cargo test --test header`"
+    );
+}